@@ -0,0 +1,240 @@
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+
+/// Jobs waiting for a worker, at most one per key.
+struct JobQueue<K, J> {
+    /// Priority, generation and the job of each key.
+    jobs:     HashMap<K, (u64, u64, J)>,
+    /// The keys ordered by priority, then by submission.
+    order:    BTreeSet<(u64, u64, K)>,
+    shutdown: bool,
+}
+
+impl<K, J> JobQueue<K, J> where K: Hash + Ord + Clone {
+    fn remove(&mut self, key: &K) -> bool {
+        match self.jobs.remove(key) {
+            Some((prio, gen, _)) => {
+                self.order.remove(&(prio, gen, key.clone()));
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn pop(&mut self) -> Option<(K, u64, J)> {
+        let first = self.order.iter().next()?.clone();
+        self.order.remove(&first);
+        let (_, gen, job) = self.jobs.remove(&first.2)?;
+        Some((first.2, gen, job))
+    }
+}
+
+type SharedQueue<K, J> = Arc<(Mutex<JobQueue<K, J>>, Condvar)>;
+
+/// Runs jobs on worker threads, lowest priority value first.
+///
+/// Each job has a key, like the index of the chunk it renders. Submitting
+/// a job for a key replaces a job of that key that did not start yet,
+/// and results of jobs that were replaced or cancelled after they started
+/// are dropped by `get_result`. So there is only ever one result per
+/// submission that is still wanted.
+pub struct JobScheduler<K, J, R> {
+    queue:     SharedQueue<K, J>,
+    result_rx: Receiver<(K, u64, R)>,
+    /// Generation of the newest submission of each key without result.
+    latest:    HashMap<K, u64>,
+    next_gen:  u64,
+    cancelled: usize,
+}
+
+impl<K, J, R> JobScheduler<K, J, R>
+    where K: Hash + Ord + Clone + Send + 'static,
+          J: Send + 'static,
+          R: Send + 'static
+{
+    pub fn new<F>(f: F, worker_count: usize) -> Self
+        where F: Fn(J) -> R,
+              F: Send + 'static,
+              F: Clone,
+    {
+        let queue : SharedQueue<K, J> =
+            Arc::new((Mutex::new(JobQueue {
+                jobs:     HashMap::new(),
+                order:    BTreeSet::new(),
+                shutdown: false,
+            }), Condvar::new()));
+        let (result_tx, result_rx) = channel();
+
+        for i in 0..worker_count {
+            let queue     = queue.clone();
+            let result_tx = result_tx.clone();
+            let f         = f.clone();
+            std::thread::Builder::new()
+                .name(format!("T[{}]", i))
+                .spawn(move || run_worker(queue, result_tx, f))
+                .expect("Couldn't start worker thread");
+        }
+
+        Self {
+            queue,
+            result_rx,
+            latest:    HashMap::new(),
+            next_gen:  0,
+            cancelled: 0,
+        }
+    }
+
+    /// Queues `job`, replacing the waiting job of `key` if there is one.
+    pub fn submit(&mut self, key: K, priority: u64, job: J) {
+        let gen = self.next_gen;
+        self.next_gen += 1;
+        self.latest.insert(key.clone(), gen);
+
+        let (lock, cvar) = &*self.queue;
+        let mut q = lock.lock().unwrap();
+        if q.remove(&key) { self.cancelled += 1; }
+        q.order.insert((priority, gen, key.clone()));
+        q.jobs.insert(key, (priority, gen, job));
+        cvar.notify_one();
+    }
+
+    /// Drops the job of `key`, a result of it is not returned anymore.
+    pub fn cancel(&mut self, key: &K) {
+        self.latest.remove(key);
+        let (lock, _) = &*self.queue;
+        if lock.lock().unwrap().remove(key) { self.cancelled += 1; }
+    }
+
+    pub fn cancel_all(&mut self) {
+        self.latest.clear();
+        let (lock, _) = &*self.queue;
+        let mut q = lock.lock().unwrap();
+        self.cancelled += q.jobs.len();
+        q.jobs.clear();
+        q.order.clear();
+    }
+
+    /// Number of submitted jobs whose result is still to come.
+    pub fn pending_count(&self) -> usize { self.latest.len() }
+
+    /// Number of jobs that were replaced or cancelled before they ran.
+    pub fn cancelled_count(&self) -> usize { self.cancelled }
+
+    fn take_current(&mut self, (key, gen, res): (K, u64, R)) -> Option<(K, R)> {
+        if self.latest.get(&key) != Some(&gen) { return None; }
+        self.latest.remove(&key);
+        Some((key, res))
+    }
+
+    /// Returns a finished result if there is one.
+    pub fn get_result(&mut self) -> Option<(K, R)> {
+        while let Ok(r) = self.result_rx.try_recv() {
+            if let Some(r) = self.take_current(r) { return Some(r); }
+        }
+        None
+    }
+
+    /// Waits for the next result, `None` if no results are pending.
+    pub fn get_result_blocking(&mut self) -> Option<(K, R)> {
+        while self.pending_count() > 0 {
+            let r = self.result_rx.recv().ok()?;
+            if let Some(r) = self.take_current(r) { return Some(r); }
+        }
+        None
+    }
+}
+
+impl<K, J, R> Drop for JobScheduler<K, J, R> {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.queue;
+        if let Ok(mut q) = lock.lock() {
+            q.shutdown = true;
+        }
+        cvar.notify_all();
+    }
+}
+
+fn run_worker<K, J, R, F>(queue: SharedQueue<K, J>, result_tx: Sender<(K, u64, R)>, f: F)
+    where K: Hash + Ord + Clone,
+          F: Fn(J) -> R
+{
+    loop {
+        let (key, gen, job) = {
+            let (lock, cvar) = &*queue;
+            let mut q = lock.lock().unwrap();
+            loop {
+                if q.shutdown { return; }
+                if let Some(j) = q.pop() { break j; }
+                q = cvar.wait(q).unwrap();
+            }
+        };
+
+        if result_tx.send((key, gen, f(job))).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn check_priorities_and_replacing() {
+        // The worker is blocked by the first job until `go` is sent,
+        // so the others queue up:
+        let (go_tx, go_rx) = sync_channel::<()>(0);
+        let go_rx = Arc::new(Mutex::new(go_rx));
+        let mut s = JobScheduler::new(move |j: (usize, &'static str)| {
+            if j.1 == "block" { go_rx.lock().unwrap().recv().unwrap(); }
+            j.1
+        }, 1);
+
+        s.submit(0, 0, (0, "block"));
+        while s.queue.0.lock().unwrap().jobs.len() > 0 {
+            std::thread::yield_now();
+        }
+
+        s.submit(1, 50, (1, "far"));
+        s.submit(2, 10, (2, "near"));
+        s.submit(3, 30, (3, "middle"));
+        s.submit(1, 20, (1, "far, moved closer"));
+        s.submit(4, 40, (4, "cancelled"));
+        s.cancel(&4);
+        // Replacing the running job drops its result:
+        s.submit(0, 60, (0, "again"));
+        assert_eq!(s.pending_count(), 4);
+        assert_eq!(s.cancelled_count(), 2);
+
+        go_tx.send(()).unwrap();
+        let mut results = vec![];
+        while let Some(r) = s.get_result_blocking() { results.push(r); }
+        assert_eq!(results, vec![
+            (2, "near"), (1, "far, moved closer"), (3, "middle"), (0, "again")]);
+        assert_eq!(s.pending_count(), 0);
+        assert_eq!(s.get_result(), None);
+    }
+
+    #[test]
+    fn check_many_workers() {
+        let mut s = JobScheduler::new(|j: u64| j * 2, 4);
+        for round in 0..3 {
+            for k in 0..100 {
+                s.submit(k, k, k + round * 1000);
+            }
+        }
+        s.cancel_all();
+        for k in 0..100 {
+            s.submit(k, 100 - k, k);
+        }
+
+        let mut results = vec![];
+        while let Some(r) = s.get_result_blocking() { results.push(r); }
+        results.sort();
+        assert_eq!(results, (0..100).map(|k| (k, k * 2)).collect::<Vec<_>>());
+        assert_eq!(s.pending_count(), 0);
+    }
+}
@@ -3,8 +3,11 @@ mod state;
 mod system_map;
 mod wl_gd_mod_resolver;
 mod util;
+mod job_scheduler;
 mod voxel_structure;
 mod voxeltree;
+mod voxel_palette;
+mod voxel_export;
 mod voxeltree_wlambda;
 mod gd_voxel_impl;
 mod gui;
@@ -8,6 +8,7 @@ use gdnative::*;
 use wlambda::{VVal, Env, GlobalEnv, EvalContext, SymbolTable};
 use wlambda::set_vval_method;
 use crate::voxeltree_wlambda::*;
+use crate::voxeltree::VoxelEditStore;
 use crate::wl_gd_mod_resolver::*;
 
 #[derive(Debug, Clone)]
@@ -37,6 +38,8 @@ pub struct SSCGState {
     pub cmd_queue:       Rc<RefCell<std::vec::Vec<VVal>>>,
     pub wm:              Rc<RefCell<WindowManager>>,
     pub vox_painters:    Rc<RefCell<std::vec::Vec<Rc<RefCell<VoxelPainter>>>>>,
+    /// Changes the player made to voxel structures, saved with the game.
+    pub voxel_edits:     Rc<RefCell<VoxelEditStore>>,
 }
 
 // XXX: This is safe as long as it is only accessed from the
@@ -78,6 +81,7 @@ impl SSCGState {
             Ok(VVal::Nul)
         });
         let _cmd_queue = cmd_queue.clone();
+        let voxel_edits = Rc::new(RefCell::new(VoxelEditStore::new()));
         set_vval_method!(o, _cmd_queue, read_data_text, Some(1), Some(1), env, _argc, {
             let filename = env.arg(0).s_raw();
             let fileurl = format!("res://{}", filename);
@@ -93,7 +97,8 @@ impl SSCGState {
                 }
             }
         });
-        set_vval_method!(o, _cmd_queue, read_savegame, Some(1), Some(1), env, _argc, {
+        // The voxel edits are restored along with the game state:
+        set_vval_method!(o, voxel_edits, read_savegame, Some(1), Some(1), env, _argc, {
             let filename = env.arg(0).s_raw();
 
             let savegame_url = format!("user://{}.json", filename);
@@ -103,7 +108,24 @@ impl SSCGState {
                 Ok(_) => {
                     let txt = f.get_as_text().to_string();
                     match VVal::from_json(&txt) {
-                        Ok(v) => Ok(v),
+                        Ok(v) => {
+                            let edits =
+                                match v.get_key("voxel_edits") {
+                                    Some(e) => voxel_edits_from_vval(&e),
+                                    None    => Ok(VoxelEditStore::new()),
+                                };
+                            match edits {
+                                Ok(edits) => {
+                                    *voxel_edits.borrow_mut() = edits;
+                                    Ok(v)
+                                },
+                                Err(e) => {
+                                    Ok(VVal::err_msg(
+                                        &format!("Couldn't load game '{}': {}",
+                                                 savegame_url, e)))
+                                },
+                            }
+                        },
                         Err(e) => {
                             Ok(VVal::err_msg(
                                 &format!("Couldn't load game '{}': {:?}",
@@ -118,9 +140,13 @@ impl SSCGState {
                 }
             }
         });
-        set_vval_method!(o, _cmd_queue, write_savegame, Some(2), Some(2), env, _argc, {
+        // Adds the voxel edits to the state under the key "voxel_edits":
+        set_vval_method!(o, voxel_edits, write_savegame, Some(2), Some(2), env, _argc, {
             let filename = env.arg(0).s_raw();
             let state    = env.arg(1);
+            state.set_map_key(
+                "voxel_edits".to_string(),
+                voxel_edits_to_vval(&voxel_edits.borrow()));
 
             let savegame_url = format!("user://{}.json", filename);
 
@@ -164,6 +190,7 @@ impl SSCGState {
             wm,
             cmd_queue,
             vox_painters,
+            voxel_edits,
             fonts:           fh,
             temp_stations:   vec![(1, 1), (900, 500)],
             update_stations: true,
@@ -0,0 +1,344 @@
+use crate::voxeltree::*;
+
+/// The file formats `MeshData::export` writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MeshFormat {
+    /// Wavefront OBJ with an MTL file, one material per face color.
+    Obj,
+    /// Binary little endian PLY.
+    Ply,
+    /// glTF 2.0 with the buffer embedded as data URI.
+    Gltf,
+}
+
+impl MeshFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "obj"  => Some(MeshFormat::Obj),
+            "ply"  => Some(MeshFormat::Ply),
+            "gltf" => Some(MeshFormat::Gltf),
+            _      => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MeshFormat::Obj  => "obj",
+            MeshFormat::Ply  => "ply",
+            MeshFormat::Gltf => "gltf",
+        }
+    }
+}
+
+const BASE64_CHARS : &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            if chunk.len() > 1 { chunk[1] } else { 0 },
+            if chunk.len() > 2 { chunk[2] } else { 0 },
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes standard base64 with padding, `None` if `s` isn't valid.
+pub fn base64_decode(s: &str) -> Option<std::vec::Vec<u8>> {
+    let s = s.as_bytes();
+    if s.len() % 4 != 0 { return None; }
+
+    let mut out = std::vec::Vec::with_capacity(s.len() / 4 * 3);
+    for (ci, chunk) in s.chunks(4).enumerate() {
+        let last = ci == s.len() / 4 - 1;
+        let mut n   = 0_u32;
+        let mut pad = 0;
+        for (i, c) in chunk.iter().enumerate() {
+            let v =
+                if *c == b'=' && last && i >= 2 {
+                    pad += 1;
+                    0
+                } else if pad > 0 {
+                    return None;
+                } else {
+                    BASE64_CHARS.iter().position(|b| b == c)? as u32
+                };
+            n = n << 6 | v;
+        }
+
+        out.push((n >> 16) as u8);
+        if pad < 2 { out.push((n >> 8) as u8); }
+        if pad < 1 { out.push(n as u8); }
+    }
+    Some(out)
+}
+
+fn color_to_u8(c: f32) -> u8 {
+    (c.min(1.0).max(0.0) * 255.0).round() as u8
+}
+
+impl MeshData {
+    /// The triangles wound counter clockwise seen from the front, as
+    /// the exchange formats expect. Godot uses the opposite winding.
+    fn ccw_triangles<'a>(&'a self) -> impl Iterator<Item=[u32; 3]> + 'a {
+        self.indices.chunks(3).map(|t| [t[0], t[2], t[1]])
+    }
+
+    /// Writes the mesh as OBJ referencing `mtl_file` and the matching
+    /// MTL file. The vertex colors are written after the positions,
+    /// as most importers understand it, and each distinct triangle
+    /// color gets a material. Without ambient occlusion that is one
+    /// material per voxel color.
+    pub fn to_obj(&self, mtl_file: &str) -> (String, String) {
+        use std::fmt::Write;
+
+        let mut obj = String::new();
+        let mut mtl = String::new();
+        let _ = writeln!(obj, "# sscg voxel mesh");
+        let _ = writeln!(obj, "mtllib {}", mtl_file);
+        for (p, c) in self.positions.iter().zip(self.colors.iter()) {
+            let _ = writeln!(obj, "v {} {} {} {} {} {}", p[0], p[1], p[2], c[0], c[1], c[2]);
+        }
+        for n in self.normals.iter() {
+            let _ = writeln!(obj, "vn {} {} {}", n[0], n[1], n[2]);
+        }
+
+        let mut materials : std::vec::Vec<([u8; 3], std::vec::Vec<[u32; 3]>)> = vec![];
+        for tri in self.ccw_triangles() {
+            let c = self.colors[tri[0] as usize];
+            let key = [color_to_u8(c[0]), color_to_u8(c[1]), color_to_u8(c[2])];
+            match materials.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, tris)) => tris.push(tri),
+                None            => materials.push((key, vec![tri])),
+            }
+        }
+
+        for (key, tris) in materials.iter() {
+            let name = format!("color_{:02x}{:02x}{:02x}", key[0], key[1], key[2]);
+            let _ = writeln!(mtl, "newmtl {}", name);
+            let _ = writeln!(mtl, "Kd {} {} {}",
+                key[0] as f32 / 255.0, key[1] as f32 / 255.0, key[2] as f32 / 255.0);
+            let _ = writeln!(mtl, "d 1\nillum 1\n");
+
+            let _ = writeln!(obj, "usemtl {}", name);
+            for t in tris.iter() {
+                let _ = writeln!(obj, "f {}//{} {}//{} {}//{}",
+                    t[0] + 1, t[0] + 1, t[1] + 1, t[1] + 1, t[2] + 1, t[2] + 1);
+            }
+        }
+
+        (obj, mtl)
+    }
+
+    /// Writes the mesh as binary little endian PLY with vertex colors.
+    pub fn to_ply(&self) -> std::vec::Vec<u8> {
+        let header = format!(
+            "ply\n\
+             format binary_little_endian 1.0\n\
+             comment sscg voxel mesh\n\
+             element vertex {}\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property float nx\nproperty float ny\nproperty float nz\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             property uchar alpha\n\
+             element face {}\n\
+             property list uchar uint vertex_indices\n\
+             end_header\n",
+            self.vertex_count(), self.triangle_count());
+
+        let mut out = header.into_bytes();
+        for i in 0..self.vertex_count() {
+            for f in self.positions[i].iter().chain(self.normals[i].iter()) {
+                out.extend_from_slice(&f.to_le_bytes());
+            }
+            for c in self.colors[i].iter() {
+                out.push(color_to_u8(*c));
+            }
+        }
+        for tri in self.ccw_triangles() {
+            out.push(3);
+            for idx in tri.iter() {
+                out.extend_from_slice(&idx.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    /// Writes the mesh as glTF 2.0 JSON, with the positions, normals,
+    /// vertex colors and indices in one embedded buffer.
+    pub fn to_gltf(&self) -> String {
+        let asset = r#""asset":{"version":"2.0","generator":"sscg"}"#;
+        if self.is_empty() {
+            return format!(r#"{{{},"scene":0,"scenes":[{{"nodes":[]}}]}}"#, asset);
+        }
+
+        let n = self.vertex_count();
+        let mut buf : std::vec::Vec<u8> = vec![];
+        let mut min = [std::f32::MAX; 3];
+        let mut max = [std::f32::MIN; 3];
+        for p in self.positions.iter() {
+            for i in 0..3 {
+                min[i] = min[i].min(p[i]);
+                max[i] = max[i].max(p[i]);
+                buf.extend_from_slice(&p[i].to_le_bytes());
+            }
+        }
+        for f in self.normals.iter().flat_map(|n| n.iter()) {
+            buf.extend_from_slice(&f.to_le_bytes());
+        }
+        for f in self.colors.iter().flat_map(|c| c.iter()) {
+            buf.extend_from_slice(&f.to_le_bytes());
+        }
+        for idx in self.ccw_triangles().flat_map(|t| t.to_vec()) {
+            buf.extend_from_slice(&idx.to_le_bytes());
+        }
+
+        let (pos_len, nrm_len, clr_len) = (n * 12, n * 12, n * 16);
+        let idx_len = self.indices.len() * 4;
+
+        format!(concat!(
+            r#"{{{asset},"scene":0,"scenes":[{{"nodes":[0]}}],"#,
+            r#""nodes":[{{"mesh":0,"name":"voxels"}}],"#,
+            r#""meshes":[{{"primitives":[{{"attributes":{{"POSITION":0,"NORMAL":1,"COLOR_0":2}},"indices":3,"mode":4}}]}}],"#,
+            r#""accessors":["#,
+            r#"{{"bufferView":0,"componentType":5126,"count":{n},"type":"VEC3","min":[{min0},{min1},{min2}],"max":[{max0},{max1},{max2}]}},"#,
+            r#"{{"bufferView":1,"componentType":5126,"count":{n},"type":"VEC3"}},"#,
+            r#"{{"bufferView":2,"componentType":5126,"count":{n},"type":"VEC4"}},"#,
+            r#"{{"bufferView":3,"componentType":5125,"count":{icount},"type":"SCALAR"}}],"#,
+            r#""bufferViews":["#,
+            r#"{{"buffer":0,"byteOffset":0,"byteLength":{pos_len},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{nrm_offs},"byteLength":{nrm_len},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{clr_offs},"byteLength":{clr_len},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{idx_offs},"byteLength":{idx_len},"target":34963}}],"#,
+            r#""buffers":[{{"byteLength":{buf_len},"uri":"data:application/octet-stream;base64,{data}"}}]}}"#),
+            asset    = asset,
+            n        = n,
+            icount   = self.indices.len(),
+            min0 = min[0], min1 = min[1], min2 = min[2],
+            max0 = max[0], max1 = max[1], max2 = max[2],
+            pos_len  = pos_len,
+            nrm_offs = pos_len,
+            nrm_len  = nrm_len,
+            clr_offs = pos_len + nrm_len,
+            clr_len  = clr_len,
+            idx_offs = pos_len + nrm_len + clr_len,
+            idx_len  = idx_len,
+            buf_len  = buf.len(),
+            data     = base64_encode(&buf))
+    }
+
+    /// Returns the files of the mesh in `format` as (file name, contents),
+    /// the file names being `name` with the extension of the format.
+    pub fn export(&self, format: MeshFormat, name: &str) -> std::vec::Vec<(String, std::vec::Vec<u8>)> {
+        let file = format!("{}.{}", name, format.extension());
+        match format {
+            MeshFormat::Obj => {
+                let mtl_file = format!("{}.mtl", name);
+                let (obj, mtl) = self.to_obj(&mtl_file);
+                vec![(file, obj.into_bytes()), (mtl_file, mtl.into_bytes())]
+            },
+            MeshFormat::Ply  => vec![(file, self.to_ply())],
+            MeshFormat::Gltf => vec![(file, self.to_gltf().into_bytes())],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_base64() {
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(b"Ma"),  "TWE=");
+        assert_eq!(base64_encode(b"M"),   "TQ==");
+        assert_eq!(base64_encode(b""),    "");
+
+        for data in [&b""[..], b"M", b"Ma", b"Man", b"\x00\xff\x10\x80"].iter() {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data.to_vec());
+        }
+        assert_eq!(base64_decode("TQ=="), Some(b"M".to_vec()));
+        assert_eq!(base64_decode("TQ="),  None);
+        assert_eq!(base64_decode("T=Q="), None);
+        assert_eq!(base64_decode("TQ==TQ=="), None);
+        assert_eq!(base64_decode("TQ*="), None);
+    }
+
+    #[test]
+    fn check_mesh_export() {
+        let mut v : Vol<u8> = Vol::new(4);
+        v.set(0, 3, 0, 1.into());
+        v.set(1, 3, 0, 2.into());
+        let mut t : Octree<u8> = Octree::new(0, v);
+        t.recompute();
+        let md = mesh_octree(&t, MeshOptions {
+            backend: MeshBackend::Greedy, ..MeshOptions::default() },
+            |c: u8| [c as f32 / 2.0, 0.0, 0.0, 1.0]);
+        // The shared face between the two colors is hidden:
+        assert_eq!(md.triangle_count(), 20);
+
+        let files = md.export(MeshFormat::Obj, "ship");
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, "ship.obj");
+        assert_eq!(files[1].0, "ship.mtl");
+        let obj = String::from_utf8(files[0].1.clone()).unwrap();
+        let mtl = String::from_utf8(files[1].1.clone()).unwrap();
+        assert!(obj.contains("mtllib ship.mtl\n"));
+        assert_eq!(obj.lines().filter(|l| l.starts_with("v ")).count(),  md.vertex_count());
+        assert_eq!(obj.lines().filter(|l| l.starts_with("vn ")).count(), md.vertex_count());
+        assert_eq!(obj.lines().filter(|l| l.starts_with("f ")).count(),  20);
+        assert_eq!(obj.lines().filter(|l| l.starts_with("usemtl ")).count(), 2);
+        assert!(mtl.contains("newmtl color_800000\nKd 0.5019608 0 0\n"));
+        assert!(mtl.contains("newmtl color_ff0000\nKd 1 0 0\n"));
+        // The first triangle is wound the other way than for Godot:
+        let f = obj.lines().find(|l| l.starts_with("f ")).unwrap();
+        assert_eq!(f, format!("f {0}//{0} {1}//{1} {2}//{2}",
+            md.indices[0] + 1, md.indices[2] + 1, md.indices[1] + 1));
+
+        let ply = md.export(MeshFormat::Ply, "ship");
+        assert_eq!(ply[0].0, "ship.ply");
+        let ply = &ply[0].1;
+        let hdr_end = b"end_header\n";
+        let body =
+            ply.windows(hdr_end.len()).position(|w| w == hdr_end).unwrap()
+            + hdr_end.len();
+        let header = std::str::from_utf8(&ply[0..body]).unwrap();
+        assert!(header.starts_with("ply\nformat binary_little_endian 1.0\n"));
+        assert!(header.contains(&format!("element vertex {}\n", md.vertex_count())));
+        assert!(header.contains("element face 20\n"));
+        assert_eq!(ply.len() - body, md.vertex_count() * (6 * 4 + 4) + 20 * (1 + 3 * 4));
+        // The alpha of the first vertex, and the first face:
+        assert_eq!(ply[body + 27], 255);
+        let f0 = body + md.vertex_count() * 28;
+        assert_eq!(ply[f0], 3);
+        assert_eq!(ply[f0 + 5..f0 + 9], md.indices[2].to_le_bytes());
+
+        let gltf = md.export(MeshFormat::Gltf, "ship");
+        assert_eq!(gltf[0].0, "ship.gltf");
+        let gltf = String::from_utf8(gltf[0].1.clone()).unwrap();
+        assert!(gltf.starts_with("{\"asset\":{\"version\":\"2.0\""));
+        let vc = md.vertex_count();
+        let buf_len = vc * (12 + 12 + 16) + md.indices.len() * 4;
+        assert!(gltf.contains(&format!("\"byteLength\":{},\"uri\"", buf_len)));
+        assert!(gltf.contains(&format!("\"count\":{},\"type\":\"VEC4\"", vc)));
+        // The bottom layer of the volume is at y 0 of the mesh:
+        assert!(gltf.contains("\"min\":[0,0,0],\"max\":[2,1,1]"));
+        let data = gltf.split("base64,").nth(1).unwrap().split('"').next().unwrap();
+        assert_eq!(data.len(), (buf_len + 2) / 3 * 4);
+        assert_eq!(gltf.matches('{').count(), gltf.matches('}').count());
+        assert_eq!(gltf.matches('[').count(), gltf.matches(']').count());
+
+        let empty = MeshData::new().to_gltf();
+        assert!(!empty.contains("meshes"));
+    }
+}
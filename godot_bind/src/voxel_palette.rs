@@ -0,0 +1,387 @@
+/// The 256 colors of the voxel values, as used by `ColorMap`.
+/// Colors are RGB with each channel between 0.0 and 1.0.
+#[derive(Clone, Copy)]
+pub struct Palette {
+    pub colors: [[f32; 3]; 256],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteError {
+    BadHexColor { line: usize, text: String },
+    NotAGimpPalette,
+    BadGplLine { line: usize, text: String },
+    TooManyColors(usize),
+    NoColors,
+    GradientOutOfOrder { index: u8 },
+    UnknownName(String),
+}
+
+impl std::fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PaletteError::BadHexColor { line, text } =>
+                write!(f, "bad hex color '{}' in line {}", text, line),
+            PaletteError::NotAGimpPalette =>
+                write!(f, "data is not a GIMP palette, 'GIMP Palette' header missing"),
+            PaletteError::BadGplLine { line, text } =>
+                write!(f, "bad GIMP palette color '{}' in line {}", text, line),
+            PaletteError::TooManyColors(n) =>
+                write!(f, "palette has {} colors, at most 256 are supported", n),
+            PaletteError::NoColors =>
+                write!(f, "palette has no colors"),
+            PaletteError::GradientOutOfOrder { index } =>
+                write!(f, "gradient key color at index {} is not after the previous one", index),
+            PaletteError::UnknownName(name) =>
+                write!(f, "unknown palette '{}'", name),
+        }
+    }
+}
+
+/// Names of the palettes `Palette::named` knows.
+pub const PALETTE_NAMES : [&str; 6] = ["gray", "8bit", "rainbow", "heat", "rock", "ice"];
+
+/// Parses a color like "#ff8000" or "ff8000", the '#' being optional.
+pub fn parse_hex_color(s: &str) -> Option<[u8; 3]> {
+    let s = s.trim();
+    let s = if s.starts_with('#') { &s[1..] } else { s };
+    if s.len() != 6 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    Some([
+        u8::from_str_radix(&s[0..2], 16).ok()?,
+        u8::from_str_radix(&s[2..4], 16).ok()?,
+        u8::from_str_radix(&s[4..6], 16).ok()?,
+    ])
+}
+
+/// Converts hue (0.0 to 360.0), saturation and value to RGB.
+pub fn hsv_to_rgb(hsv: [f32; 3]) -> [f32; 3] {
+    let h = hsv[0].rem_euclid(360.0) / 60.0;
+    let s = hsv[1].min(1.0).max(0.0);
+    let v = hsv[2].min(1.0).max(0.0);
+
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) =
+        match h as u32 {
+            0 => (c,   x,   0.0),
+            1 => (x,   c,   0.0),
+            2 => (0.0, c,   x),
+            3 => (0.0, x,   c),
+            4 => (x,   0.0, c),
+            _ => (c,   0.0, x),
+        };
+    let m = v - c;
+    [r + m, g + m, b + m]
+}
+
+fn channel_to_u8(c: f32) -> u8 {
+    (c.min(1.0).max(0.0) * 255.0).round() as u8
+}
+
+fn lerp3(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [a[0] + (b[0] - a[0]) * t,
+     a[1] + (b[1] - a[1]) * t,
+     a[2] + (b[2] - a[2]) * t]
+}
+
+impl PartialEq for Palette {
+    fn eq(&self, other: &Self) -> bool {
+        self.colors[..] == other.colors[..]
+    }
+}
+
+impl std::fmt::Debug for Palette {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.colors.iter()).finish()
+    }
+}
+
+impl Palette {
+    pub fn new_from(colors: [[f32; 3]; 256]) -> Self {
+        Self { colors }
+    }
+
+    /// A palette of the given colors, the remaining ones being black.
+    pub fn new_from_u8(colors: &[[u8; 3]]) -> Result<Self, PaletteError> {
+        if colors.len() > 256 {
+            return Err(PaletteError::TooManyColors(colors.len()));
+        }
+
+        let mut p = Self { colors: [[0.0; 3]; 256] };
+        for (i, c) in colors.iter().enumerate() {
+            p.colors[i] = [
+                c[0] as f32 / 255.0,
+                c[1] as f32 / 255.0,
+                c[2] as f32 / 255.0,
+            ];
+        }
+        Ok(p)
+    }
+
+    pub fn gray() -> Self {
+        let mut colors = [[0.0; 3]; 256];
+        for (i, c) in colors.iter_mut().enumerate() {
+            *c = [i as f32 / 255.0; 3];
+        }
+        Self { colors }
+    }
+
+    /// The voxel value read as RRRGGGBB bits.
+    pub fn rgb332() -> Self {
+        let mut colors = [[0.0; 3]; 256];
+        for (i, c) in colors.iter_mut().enumerate() {
+            let r = (i >> 5) & 0x7;
+            let g = (i >> 2) & 0x7;
+            let b = i & 0x3;
+            *c = [
+                r as f32 / 7.0,
+                g as f32 / 7.0,
+                b as f32 / 3.0,
+            ];
+        }
+        Self { colors }
+    }
+
+    /// Interpolates linearly between key colors at the given indices,
+    /// which have to be ascending. The colors before the first and after
+    /// the last key color are the ones of those.
+    pub fn gradient(stops: &[(u8, [f32; 3])]) -> Result<Self, PaletteError> {
+        if stops.is_empty() { return Err(PaletteError::NoColors); }
+        for w in stops.windows(2) {
+            if w[1].0 <= w[0].0 {
+                return Err(PaletteError::GradientOutOfOrder { index: w[1].0 });
+            }
+        }
+
+        let mut colors = [[0.0; 3]; 256];
+        for (i, c) in colors.iter_mut().enumerate() {
+            let next = stops.iter().position(|(idx, _)| *idx as usize >= i);
+            *c =
+                match next {
+                    None    => stops[stops.len() - 1].1,
+                    Some(0) => stops[0].1,
+                    Some(n) => {
+                        let (a, b) = (stops[n - 1], stops[n]);
+                        let t = (i - a.0 as usize) as f32 / (b.0 - a.0) as f32;
+                        lerp3(a.1, b.1, t)
+                    },
+                };
+        }
+        Ok(Self { colors })
+    }
+
+    /// Interpolates hue, saturation and value from `from` at index 0
+    /// to `to` at index 255. Hues are in degrees and are not wrapped,
+    /// so 0.0 to 360.0 goes around the whole color wheel.
+    pub fn hsv_ramp(from: [f32; 3], to: [f32; 3]) -> Self {
+        let mut colors = [[0.0; 3]; 256];
+        for (i, c) in colors.iter_mut().enumerate() {
+            *c = hsv_to_rgb(lerp3(from, to, i as f32 / 255.0));
+        }
+        Self { colors }
+    }
+
+    /// One of the built-in palettes in `PALETTE_NAMES`.
+    pub fn named(name: &str) -> Result<Self, PaletteError> {
+        match name {
+            "gray"    => Ok(Self::gray()),
+            "8bit"    => Ok(Self::rgb332()),
+            "rainbow" => Ok(Self::hsv_ramp([0.0, 1.0, 1.0], [300.0, 1.0, 1.0])),
+            "heat"    => Self::gradient(&[
+                (0,   [0.0, 0.0, 0.0]),
+                (96,  [0.8, 0.0, 0.0]),
+                (192, [1.0, 0.8, 0.0]),
+                (255, [1.0, 1.0, 1.0]),
+            ]),
+            "rock"    => Self::gradient(&[
+                (0,   [0.16, 0.13, 0.11]),
+                (128, [0.45, 0.40, 0.35]),
+                (255, [0.78, 0.74, 0.68]),
+            ]),
+            "ice"     => Self::gradient(&[
+                (0,   [0.05, 0.10, 0.30]),
+                (128, [0.40, 0.70, 0.90]),
+                (255, [0.95, 0.98, 1.00]),
+            ]),
+            _ => Err(PaletteError::UnknownName(name.to_string())),
+        }
+    }
+
+    /// Reads colors like "#ff8000", separated by whitespace or commas.
+    /// Lines starting with ';' are comments.
+    pub fn from_hex_list(text: &str) -> Result<Self, PaletteError> {
+        let mut colors = vec![];
+        for (i, line) in text.lines().enumerate() {
+            if line.trim_start().starts_with(';') { continue; }
+
+            for tok in line.split(|c: char| c.is_whitespace() || c == ',') {
+                if tok.is_empty() { continue; }
+                match parse_hex_color(tok) {
+                    Some(c) => colors.push(c),
+                    None => {
+                        return Err(PaletteError::BadHexColor {
+                            line: i + 1,
+                            text: tok.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        if colors.is_empty() { return Err(PaletteError::NoColors); }
+        Self::new_from_u8(&colors)
+    }
+
+    /// Writes all 256 colors for `from_hex_list`, one per line.
+    pub fn to_hex_list(&self) -> String {
+        let mut out = String::new();
+        for c in self.to_u8().iter() {
+            out += &format!("#{:02x}{:02x}{:02x}\n", c[0], c[1], c[2]);
+        }
+        out
+    }
+
+    /// Reads a GIMP palette. The "Name" and "Columns" fields are ignored.
+    pub fn from_gpl(text: &str) -> Result<Self, PaletteError> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, l)) if l.trim() == "GIMP Palette" => (),
+            _ => return Err(PaletteError::NotAGimpPalette),
+        }
+
+        let mut colors = vec![];
+        for (i, line) in lines {
+            let l = line.trim();
+            if l.is_empty() || l.starts_with('#')
+               || l.starts_with("Name:") || l.starts_with("Columns:") {
+                continue;
+            }
+
+            let bad = || PaletteError::BadGplLine { line: i + 1, text: l.to_string() };
+            let mut rgb = [0; 3];
+            let mut fields = l.split_whitespace();
+            for c in rgb.iter_mut() {
+                *c = fields.next().and_then(|f| f.parse::<u8>().ok()).ok_or_else(bad)?;
+            }
+            colors.push(rgb);
+        }
+
+        if colors.is_empty() { return Err(PaletteError::NoColors); }
+        Self::new_from_u8(&colors)
+    }
+
+    /// Writes the palette as GIMP palette with the color indices as names.
+    pub fn to_gpl(&self, name: &str) -> String {
+        let mut out = format!("GIMP Palette\nName: {}\nColumns: 16\n#\n", name);
+        for (i, c) in self.to_u8().iter().enumerate() {
+            out += &format!("{:3} {:3} {:3}\t{}\n", c[0], c[1], c[2], i);
+        }
+        out
+    }
+
+    /// Reads a GIMP palette or a hex color list, depending on the header.
+    pub fn parse(text: &str) -> Result<Self, PaletteError> {
+        if text.trim_start().starts_with("GIMP Palette") {
+            Self::from_gpl(text)
+        } else {
+            Self::from_hex_list(text)
+        }
+    }
+
+    pub fn to_u8(&self) -> [[u8; 3]; 256] {
+        let mut out = [[0; 3]; 256];
+        for (o, c) in out.iter_mut().zip(self.colors.iter()) {
+            *o = [channel_to_u8(c[0]), channel_to_u8(c[1]), channel_to_u8(c[2])];
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_builtin_palettes() {
+        let p = Palette::rgb332();
+        assert_eq!(p.colors[0],    [0.0, 0.0, 0.0]);
+        assert_eq!(p.colors[0xFF], [1.0, 1.0, 1.0]);
+        assert_eq!(p.colors[0xE0], [1.0, 0.0, 0.0]);
+        assert_eq!(p.colors[0x1C], [0.0, 1.0, 0.0]);
+        assert_eq!(p.colors[0x03], [0.0, 0.0, 1.0]);
+        assert_eq!(p.colors[0x20], [1.0 / 7.0, 0.0, 0.0]);
+
+        let g = Palette::gray();
+        assert_eq!(g.colors[255], [1.0; 3]);
+        assert_eq!(g.to_u8()[128], [128; 3]);
+
+        for name in PALETTE_NAMES.iter() {
+            let p = Palette::named(name).unwrap();
+            assert!(p.colors.iter().all(|c| c.iter().all(|v| *v >= 0.0 && *v <= 1.0)));
+        }
+        assert_eq!(Palette::named("8bit").unwrap(), Palette::rgb332());
+        assert_eq!(Palette::named("plaid"),
+                   Err(PaletteError::UnknownName("plaid".to_string())));
+
+        assert_eq!(hsv_to_rgb([0.0,   1.0, 1.0]), [1.0, 0.0, 0.0]);
+        assert_eq!(hsv_to_rgb([120.0, 1.0, 1.0]), [0.0, 1.0, 0.0]);
+        assert_eq!(hsv_to_rgb([240.0, 1.0, 0.5]), [0.0, 0.0, 0.5]);
+        assert_eq!(hsv_to_rgb([360.0, 0.0, 1.0]), [1.0, 1.0, 1.0]);
+        let r = Palette::named("rainbow").unwrap();
+        assert_eq!(r.to_u8()[0],   [255, 0, 0]);
+        assert_eq!(r.to_u8()[255], [255, 0, 255]);
+    }
+
+    #[test]
+    fn check_gradient() {
+        let p = Palette::gradient(&[
+            (10,  [0.0, 0.0, 0.0]),
+            (20,  [1.0, 0.5, 0.0]),
+            (220, [1.0, 0.5, 1.0]),
+        ]).unwrap();
+        assert_eq!(p.colors[0],   [0.0, 0.0, 0.0]);
+        assert_eq!(p.colors[10],  [0.0, 0.0, 0.0]);
+        assert_eq!(p.colors[15],  [0.5, 0.25, 0.0]);
+        assert_eq!(p.colors[20],  [1.0, 0.5, 0.0]);
+        assert_eq!(p.colors[120], [1.0, 0.5, 0.5]);
+        assert_eq!(p.colors[255], [1.0, 0.5, 1.0]);
+
+        assert_eq!(Palette::gradient(&[]), Err(PaletteError::NoColors));
+        assert_eq!(Palette::gradient(&[(5, [0.0; 3]), (5, [1.0; 3])]),
+                   Err(PaletteError::GradientOutOfOrder { index: 5 }));
+    }
+
+    #[test]
+    fn check_palette_files() {
+        let p = Palette::from_hex_list(
+            "; a comment\n#ff0000, #00ff00\n0000ff\n\n  #FFFFFF  ").unwrap();
+        assert_eq!(p.to_u8()[0..5], [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255; 3], [0; 3]]);
+        assert_eq!(Palette::from_hex_list("#ff0000\n#ff00zz"),
+                   Err(PaletteError::BadHexColor { line: 2, text: "#ff00zz".to_string() }));
+        assert_eq!(Palette::from_hex_list("; nothing"), Err(PaletteError::NoColors));
+        assert_eq!(Palette::from_hex_list(&"#000000 ".repeat(257)),
+                   Err(PaletteError::TooManyColors(257)));
+
+        let gpl = "GIMP Palette\nName: Test\nColumns: 4\n#\n255   0   0\tRed\n  0 128   0 Green\n";
+        let p = Palette::from_gpl(gpl).unwrap();
+        assert_eq!(p.to_u8()[0..3], [[255, 0, 0], [0, 128, 0], [0, 0, 0]]);
+        assert_eq!(Palette::parse(gpl).unwrap(), p);
+        assert_eq!(Palette::from_gpl("255 0 0\n"), Err(PaletteError::NotAGimpPalette));
+        assert_eq!(Palette::from_gpl("GIMP Palette\n255 0 300 Oops\n"),
+                   Err(PaletteError::BadGplLine { line: 2, text: "255 0 300 Oops".to_string() }));
+        assert_eq!(Palette::from_gpl("GIMP Palette\n255 0\n"),
+                   Err(PaletteError::BadGplLine { line: 2, text: "255 0".to_string() }));
+
+        // Round trips, exact for palettes of 8 bit colors:
+        let heat = Palette::new_from_u8(&Palette::named("heat").unwrap().to_u8()).unwrap();
+        assert_eq!(Palette::parse(&heat.to_hex_list()).unwrap(), heat);
+        assert_eq!(Palette::parse(&heat.to_gpl("heat")).unwrap(), heat);
+        assert_eq!(heat.to_hex_list().lines().count(), 256);
+
+        assert_eq!(parse_hex_color("#a0B0c0"), Some([0xA0, 0xB0, 0xC0]));
+        assert_eq!(parse_hex_color("a0b0c"),   None);
+        assert_eq!(parse_hex_color("#+1b0c0"), None);
+    }
+}
@@ -1,10 +1,13 @@
-use crate::state::SSCG;
+use crate::state::{SSCG, SSCGState};
 #[macro_use]
 use gdnative::*;
 use crate::voxeltree::*;
+use crate::voxel_export::MeshFormat;
 use crate::gd_voxel_impl::*;
-use crate::util::WorkerPool;
+use crate::voxel_palette::*;
+use crate::job_scheduler::JobScheduler;
 use wlambda::VVal;
+use euclid::{vec2, vec3};
 
 use std::sync::RwLock;
 use std::sync::Arc;
@@ -12,84 +15,274 @@ use std::sync::Arc;
 #[derive(NativeClass)]
 #[inherit(gdnative::Spatial)]
 pub struct VoxStruct {
-    meshes:           std::vec::Vec<MeshInstance>,
-    collision_shapes: std::vec::Vec<(StaticBody, i64)>,
+    /// The nodes of each chunk, only created once it has a mesh.
+    chunk_nodes:      std::vec::Vec<Option<ChunkNodes>>,
+    /// The material of the chunk meshes.
+    material:         Option<gdnative::Material>,
+    layout:           StructureLayout,
+    /// The volume as drawn by `on_draw_voxel_structure`.
     vol:              Vol<u8>,
-    vol_generation:   usize,
-    octrees:          std::vec::Vec<Arc<RwLock<Octree<u8>>>>,
-    color_map:        ColorMap,
-
+    chunks:           ChunkedVolume<u8>,
+    materials:        MaterialTable,
+    /// Player edits in cursor coordinates.
+    journal:          EditJournal<u8>,
+
+    lod_depth:        Option<usize>,
+    ambient_occlusion: bool,
+    mesh_backend:     MeshBackend,
+    collision_mode:   CollisionMode,
+    /// The density field of a smoothly meshed structure, see
+    /// `on_wlambda_init`, with its iso level.
+    density:          Option<Arc<DensityField>>,
+    iso_level:        f32,
+    /// Atlas tiles of the voxel colors, replacing the flat colors.
+    /// Smooth surfaces keep using the colors.
+    atlas:            Option<TextureAtlas>,
     cursor:           [u16; 3],
-    workers:          WorkerPool<VoxRendJob,VoxRendResult>,
+    /// The camera position minus the structure origin, in world space.
+    /// Rotation and scale of the structure are ignored, which is close
+    /// enough for ordering the chunks. Chunks near it or the cursor are
+    /// meshed first.
+    camera_pos:       Option<[f32; 3]>,
+    /// Renders the chunks, keyed by the chunk index.
+    workers:          JobScheduler<usize, VoxRendJob, VoxRendResult>,
     last_load_vol:    std::time::Instant,
 }
 
 unsafe impl Send for VoxStruct { }
 
-const VOL_SIZE    : usize = 128;
-const SUBVOL_SIZE : usize = 16;
-const SUBVOLS     : usize = VOL_SIZE / SUBVOL_SIZE;
+/// The mesh and collision nodes of a chunk.
+struct ChunkNodes {
+    mesh:             MeshInstance,
+    body:             StaticBody,
+    /// The shape owner of the collision triangles.
+    shape_owner:      i64,
+    /// The shape owners of the collision boxes, one per box
+    /// as each box needs its own transform.
+    box_shape_owners: std::vec::Vec<i64>,
+}
+
+/// Voxel groups bigger than this are never split off from a structure.
+const ISLAND_MAX_VOXELS : usize = 4096;
+
+/// Camera distances from the structure center beyond which the
+/// sub volume octrees are only rendered up to the given depth.
+const LOD_DISTANCES : [(f32, usize); 2] = [
+    (600.0, 2),
+    (300.0, 3),
+];
+
+fn lod_depth_for_distance(dist: f32) -> Option<usize> {
+    for (min_dist, depth) in LOD_DISTANCES.iter() {
+        if dist > *min_dist { return Some(*depth); }
+    }
+    None
+}
 
-fn vval2colors(clr: VVal) -> ColorMap {
-    let mut colors = [[0.0; 3]; 256];
-    use crate::gui::wlambda_api::color_hex24tpl;
+/// Reads a color map given as vector of hex colors like "ff8000",
+/// errors report the index of the bad entry plus one as line.
+fn vval2colors(clr: VVal) -> Result<ColorMap, PaletteError> {
+    let mut colors = vec![];
     for (i, c) in clr.iter().enumerate() {
-        let tpl = color_hex24tpl(&c.s_raw());
-        colors[i] = [
-            tpl.0 as f32 / 255.0,
-            tpl.1 as f32 / 255.0,
-            tpl.2 as f32 / 255.0,
-        ];
+        let text = c.s_raw();
+        match parse_hex_color(&text) {
+            Some(rgb) => colors.push(rgb),
+            None => return Err(PaletteError::BadHexColor { line: i + 1, text }),
+        }
+    }
+    Ok(ColorMap::from_palette(&Palette::new_from_u8(&colors)?))
+}
+
+/// Reads a GIMP `.gpl` palette or a list of hex colors from a file.
+fn load_palette_file(path: &str) -> Result<Palette, String> {
+    let mut f = File::new();
+    match f.open(GodotString::from_str(path), 1) {
+        Ok(_) => {
+            let text = f.get_as_text().to_string();
+            f.close();
+            Palette::parse(&text).map_err(|e| format!("{}", e))
+        },
+        Err(e) => Err(format!("couldn't open: {:?}", e)),
+    }
+}
+
+/// Resolves a color map given by name: "vox" is handled by the caller,
+/// paths ending in ".gpl", ".hex" or ".txt" are loaded as palette files
+/// and anything else is one of the built-in palettes.
+fn named_color_map(name: &str) -> Result<ColorMap, String> {
+    if name.ends_with(".gpl") || name.ends_with(".hex") || name.ends_with(".txt") {
+        load_palette_file(name).map(|p| ColorMap::from_palette(&p))
+    } else {
+        Palette::named(name)
+            .map(|p| ColorMap::from_palette(&p))
+            .map_err(|e| format!("{}", e))
+    }
+}
+
+/// Reads the material properties returned by `on_draw_voxel_structure`.
+/// `mats` is a vector indexed by the voxel color, entries are either
+/// `$n` to keep the defaults or maps with the optional keys
+/// `time`, `density`, `yield`, `minable` and `color` (hex string).
+fn vval2materials(mats: VVal, table: &mut MaterialTable) {
+    for (i, m) in mats.iter().enumerate().take(256) {
+        if m.is_none() { continue; }
+
+        let mat = table.get_mut(i as u8);
+        if let Some(t) = m.get_key("time")    { mat.mining_time = t.f(); }
+        if let Some(d) = m.get_key("density") { mat.density     = d.f(); }
+        if let Some(y) = m.get_key("yield")   { mat.yield_units = y.f(); }
+        if let Some(b) = m.get_key("minable") { mat.minable     = b.b(); }
+        if let Some(c) = m.get_key("color") {
+            match parse_hex_color(&c.s_raw()) {
+                Some(rgb) => {
+                    mat.color = [
+                        rgb[0] as f32 / 255.0,
+                        rgb[1] as f32 / 255.0,
+                        rgb[2] as f32 / 255.0,
+                    ];
+                },
+                None => {
+                    println!("Bad color '{}' for material {}, keeping {:?}",
+                             c.s_raw(), i, mat.color);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the texture atlas returned by `on_draw_voxel_structure`, a map
+/// with the `texture` path, the number of tile `columns` and `rows` and
+/// the `tiles` vector indexed by the voxel color. Its entries are either
+/// `$n` for the first tile, one tile for all faces or `$[top, side, bottom]`.
+fn vval2atlas(v: VVal) -> Option<(String, TextureAtlas)> {
+    let texture = v.get_key("texture")?.s_raw();
+    let columns = v.get_key("columns").map(|c| c.i()).unwrap_or(1);
+    let rows    = v.get_key("rows").map(|r| r.i()).unwrap_or(1);
+
+    let fits = |n: i64| n >= 1 && n <= u16::MAX as i64;
+    if !fits(columns) || !fits(rows) || columns * rows > ATLAS_MAX_TILES as i64 {
+        println!("Bad voxel atlas size {}x{}, at most {} tiles are possible",
+                 columns, rows, ATLAS_MAX_TILES);
+        return None;
+    }
+
+    let mut atlas = TextureAtlas::new(columns as u16, rows as u16);
+    if let Some(tiles) = v.get_key("tiles") {
+        for (i, t) in tiles.iter().enumerate().take(256) {
+            if t.is_none() { continue; }
+
+            let tiles =
+                if t.is_vec() {
+                    AtlasTiles {
+                        top:    t.v_i(0) as u16,
+                        side:   t.v_i(1) as u16,
+                        bottom: t.v_i(2) as u16,
+                    }
+                } else {
+                    AtlasTiles::all(t.i() as u16)
+                };
+            atlas.set(i as u8, tiles);
+        }
+    }
+
+    Some((texture, atlas))
+}
+
+/// Reads the layout returned by `on_draw_voxel_structure`, a map with
+/// the `size` as one number for a cube or `$[w, h, d]` and the
+/// `chunk_size`. Missing values are the ones of the default layout.
+fn vval2layout(v: VVal) -> Result<StructureLayout, VolError> {
+    let def = StructureLayout::default();
+    if v.is_none() { return Ok(def); }
+
+    let (w, h, d) =
+        match v.get_key("size") {
+            Some(s) if s.is_vec() => (s.v_i(0), s.v_i(1), s.v_i(2)),
+            Some(s)               => (s.i(), s.i(), s.i()),
+            None => (def.w as i64, def.h as i64, def.d as i64),
+        };
+    let chunk_size =
+        v.get_key("chunk_size").map(|c| c.i()).unwrap_or(def.chunk_size as i64);
+
+    StructureLayout::new(
+        w.max(0) as usize, h.max(0) as usize, d.max(0) as usize,
+        chunk_size.max(0) as usize)
+}
+
+/// The data for meshing the chunks of a structure with `mesh_surface_nets`.
+/// Mined voxels are carved out of the density field.
+struct SmoothSurface {
+    density: Arc<DensityField>,
+    iso:     f32,
+    chunks:  ChunkedVolume<u8>,
+}
+
+impl SmoothSurface {
+    fn render(&self, cm: &ColorMap, idx: usize) -> RenderedMeshArrays {
+        let (w, h, d) = self.chunks.chunk_extent(idx);
+        let md = mesh_surface_nets(
+            [self.chunks.w, self.chunks.h, self.chunks.d],
+            self.chunks.chunk_origin(idx),
+            [w, h, d],
+            self.iso,
+            |p| {
+                if !self.density.contains(p) { return self.iso; }
+                let d = self.density.at(p);
+                if self.chunks.get(p).color == 0 { d.min(self.iso) } else { d }
+            },
+            |p| cm.rgba(self.chunks.get(p).color));
+        RenderedMeshArrays::from_mesh_data(&md)
     }
-    ColorMap::new_from(colors)
 }
 
 struct VoxRendJob {
-    vol_generation: usize,
     color_map: ColorMap,
+    mesh_opts: MeshOptions,
     oct_subtree_idx: usize,
     oct_subtree: Arc<RwLock<Octree<u8>>>,
+    smooth: Option<Arc<SmoothSurface>>,
+    atlas: Option<TextureAtlas>,
 }
 
 unsafe impl Send for VoxRendJob { }
 
 impl VoxRendJob {
+    /// Edits of this chunk wait for the locks taken here, see
+    /// `ChunkedVolume::set`. Edits of other chunks go on meanwhile.
     pub fn render(&mut self) -> VoxRendResult {
-//        if self.vol_generation < vg {
-//            return VoxRendResult {
-//                vol_generation:  self.vol_generation,
-//                oct_subtree_idx: self.oct_subtree_idx,
-//                empty: true,
-//                arrs: None,
-//            };
-//        }
-
-        let n = self.oct_subtree.write().unwrap().recompute();
+        let n = {
+            let mut ot = self.oct_subtree.write().unwrap();
+            ot.update_dirty();
+            ot.root()
+        };
 
         let arr =
             if !n.empty {
                 let cm = self.color_map;
-                let oct_guard = self.oct_subtree.read().unwrap();
-                let arr = render_octree_to_am(&cm, &*oct_guard);
-                Some(arr)
+                match &self.smooth {
+                    Some(smooth) => Some(smooth.render(&cm, self.oct_subtree_idx)),
+                    None => {
+                        let oct_guard = self.oct_subtree.read().unwrap();
+                        let arr =
+                            match &self.atlas {
+                                Some(atlas) =>
+                                    render_octree_atlas_to_am(&*oct_guard, self.mesh_opts, atlas),
+                                None =>
+                                    render_octree_opts_to_am(&cm, &*oct_guard, self.mesh_opts),
+                            };
+                        Some(arr)
+                    }
+                }
             } else {
                 None
             };
 
-        VoxRendResult {
-            vol_generation:  self.vol_generation,
-            oct_subtree_idx: self.oct_subtree_idx,
-            empty: n.empty,
-            arrs: arr,
-        }
+        VoxRendResult { arrs: arr }
     }
 }
 
 struct VoxRendResult {
-    vol_generation: usize,
-    oct_subtree_idx: usize,
     arrs: Option<RenderedMeshArrays>,
-    empty: bool,
 }
 
 unsafe impl Send for VoxRendResult { }
@@ -98,15 +291,24 @@ unsafe impl Send for VoxRendResult { }
 impl VoxStruct {
     fn _init(_owner: Spatial) -> Self {
         Self {
-            meshes:           vec![],
-            collision_shapes: vec![],
-            vol:              Vol::new(VOL_SIZE),
-            vol_generation:   0,
-            octrees:          vec![],
-            color_map:        ColorMap::new_gray(),
+            chunk_nodes:      vec![],
+            material:         None,
+            layout:           StructureLayout::default(),
+            vol:              StructureLayout::default().new_vol(),
+            lod_depth:        None,
+            ambient_occlusion: true,
+            mesh_backend:     MeshBackend::default(),
+            collision_mode:   CollisionMode::default(),
+            density:          None,
+            iso_level:        0.5,
+            atlas:            None,
+            chunks:           StructureLayout::default().new_chunks(),
+            materials:        MaterialTable::new_from_color_map(&ColorMap::new_gray()),
+            journal:          EditJournal::new(),
             cursor:           [0, 0, 0],
+            camera_pos:       None,
             last_load_vol:    std::time::Instant::now(),
-            workers:          WorkerPool::new(|mut j: VoxRendJob| {
+            workers:          JobScheduler::new(|mut j: VoxRendJob| {
                 j.render()
             }, 8),
         }
@@ -117,225 +319,438 @@ impl VoxStruct {
         let d = std::time::Instant::now();
         let (sysid, entid) = self.parent_info(&mut owner);
         lock_sscg!(sscg);
-        let ret = sscg.call_cb("on_draw_voxel_structure", &vec![sysid, entid]);
+        let ret = sscg.call_cb("on_draw_voxel_structure", &vec![sysid.clone(), entid.clone()]);
         if !ret.is_none() {
+            // The size of the structure and its chunks, the drawn
+            // volume is clipped to it:
+            let layout =
+                vval2layout(ret.v_(7)).unwrap_or_else(|e| {
+                    println!("Bad voxel structure layout: {}", e);
+                    StructureLayout::default()
+                });
+            self.set_layout(layout);
+
             sscg.vox_painters
                 .borrow()[ret.v_i(0) as usize]
                 .borrow()
                 .write_into_u8_vol(ret.v_i(1) as usize, &mut self.vol);
 
-            if ret.v_(2).is_str() {
-                self.color_map =
+            // The color map is either the name of a built-in palette, a
+            // palette file, "vox" or a vector of hex colors:
+            let color_map =
+                if ret.v_(2).is_str() {
                     match &ret.v_s_raw(2)[..] {
-                        "8bit" => ColorMap::new_8bit(),
-                        _      => ColorMap::new_gray(),
-                    };
-            } else if !ret.v_(2).is_none() {
-                self.color_map = vval2colors(ret.v_(2));
+                        // The palette of a volume loaded with `load_vox`:
+                        "vox"  => {
+                            sscg.vox_painters
+                                .borrow()[ret.v_i(0) as usize]
+                                .borrow()
+                                .vox_palette(ret.v_i(1) as usize)
+                                .map(|p| ColorMap::new_from_vox_palette(&p))
+                                .unwrap_or_else(ColorMap::new_gray)
+                        },
+                        name => {
+                            named_color_map(name).unwrap_or_else(|e| {
+                                println!("Couldn't load color map '{}': {}", name, e);
+                                ColorMap::new_gray()
+                            })
+                        },
+                    }
+                } else if !ret.v_(2).is_none() {
+                    vval2colors(ret.v_(2)).unwrap_or_else(|e| {
+                        println!("Bad color map: {}", e);
+                        ColorMap::new_gray()
+                    })
+                } else {
+                    ColorMap::new_gray()
+                };
+
+            self.materials = MaterialTable::new_from_color_map(&color_map);
+            if !ret.v_(3).is_none() {
+                vval2materials(ret.v_(3), &mut self.materials);
+            }
+
+            // An optional float volume and iso level turn the structure
+            // into a smooth surface, the drawn volume only supplying the
+            // materials inside of it:
+            self.density =
+                if !ret.v_(4).is_none() {
+                    sscg.vox_painters
+                        .borrow()[ret.v_i(0) as usize]
+                        .borrow()
+                        .density_field(ret.v_i(4) as usize)
+                        .map(Arc::new)
+                } else {
+                    None
+                };
+            self.iso_level = if ret.v_(5).is_none() { 0.5 } else { ret.v_f(5) as f32 };
+            if let Some(density) = self.density.clone() {
+                self.clear_outside_of_surface(&density);
+            }
+
+            self.atlas = None;
+            if let Some((texture, atlas)) = vval2atlas(ret.v_(6)) {
+                self.atlas = Some(atlas);
+                self.set_atlas_material(&texture);
             }
 
             println!("Drawing voxel volume, took {} ms", d.elapsed().as_millis());
+
+            // The player's changes from earlier sessions are kept as base
+            // of the journal, so they are saved again but can't be undone:
+            let key = (sysid.i(), entid.i());
+            let dims = self.layout.dims();
+            let saved : std::vec::Vec<(Pos, u8)> =
+                sscg.voxel_edits.borrow().restore(key, dims).unwrap_or_else(|e| {
+                    println!("Couldn't restore voxel edits of {:?}: {}", key, e);
+                    vec![]
+                });
+            let base : std::vec::Vec<(Pos, u8, u8)> =
+                saved.iter().map(|(p, c)| {
+                    let generated =
+                        self.vol.at(Pos::new(p.x, (self.vol.h - 1) as PInt - p.y, p.z)).color;
+                    (*p, generated, *c)
+                }).collect();
+            self.journal = EditJournal::with_base(&base);
+
             self.load_vol(owner);
             println!("Reloaded voxel volume, took {} ms", d.elapsed().as_millis());
         }
     }
 
     #[export]
-    fn _ready(&mut self, mut owner: Spatial) {
-        use wlambda::util::*;
-
-        let voxel_material =
+    fn _ready(&mut self, mut _owner: Spatial) {
+        self.material =
             ResourceLoader::godot_singleton().load(
                 GodotString::from_str("res://scenes/entities/materials/voxel_material.tres"),
                 GodotString::from_str("ShaderMaterial"),
-                false).unwrap().cast::<Material>().unwrap();
-
-        let mut i = 0;
-        for z in 0..SUBVOLS {
-            for y in 0..SUBVOLS {
-                for x in 0..SUBVOLS {
-                    self.meshes.push(MeshInstance::new());
+                false).and_then(|m| m.cast::<gdnative::Material>());
+    }
 
-                    let mut sb = StaticBody::new();
+    /// Replaces the volume and chunks by empty ones of `layout`,
+    /// dropping the nodes of all chunks.
+    fn set_layout(&mut self, layout: StructureLayout) {
+        for nodes in self.chunk_nodes.drain(..) {
+            if let Some(mut nodes) = nodes {
+                unsafe {
+                    nodes.mesh.queue_free();
+                    nodes.body.queue_free();
+                }
+            }
+        }
 
-                    unsafe {
-                        self.meshes[i].set_material_override(Some(voxel_material.clone()));
-                        let mut t = self.meshes[i].get_transform();
-                        t.origin.x = (x * SUBVOL_SIZE) as f32;
-                        t.origin.y = (y * SUBVOL_SIZE) as f32;
-                        t.origin.z = (z * SUBVOL_SIZE) as f32;
-                        self.meshes[i].set_transform(t);
-                        self.meshes[i].set_layer_mask_bit(0, false);
-                        self.meshes[i].set_layer_mask_bit(1, true);
-                        sb.set_transform(t);
-
-                        owner.add_child(self.meshes[i].cast::<Node>(), false);
-
-                        let sb_obj = Object::from_sys(sb.cast::<Object>().unwrap().to_sys());
-                        let id = sb.create_shape_owner(Some(sb_obj));
-                        self.collision_shapes.push((sb, id));
-
-                        owner.add_child(sb.cast::<Node>(), false);
-                    }
+        self.layout = layout;
+        self.vol    = layout.new_vol();
+        self.chunks = layout.new_chunks();
+        self.chunk_nodes.resize_with(self.chunks.chunk_count(), || None);
+        // Results of jobs for the old chunks are dropped:
+        self.workers.cancel_all();
+    }
 
-                    self.octrees.push(
-                        std::sync::Arc::new(
-                            std::sync::RwLock::new(
-                                Octree::new_from_size(SUBVOL_SIZE))));
+    /// The nodes of chunk `idx`, which are created on first use.
+    fn chunk_nodes(&mut self, owner: &mut Spatial, idx: usize) -> &mut ChunkNodes {
+        if self.chunk_nodes[idx].is_none() {
+            let mut mesh = MeshInstance::new();
+            let mut body = StaticBody::new();
 
-                    i += 1;
-                }
+            unsafe {
+                mesh.set_material_override(self.material.clone());
+                let origin = self.chunks.chunk_origin_inv_y(idx);
+                let mut t = mesh.get_transform();
+                t.origin.x = origin.x as f32;
+                t.origin.y = origin.y as f32;
+                t.origin.z = origin.z as f32;
+                mesh.set_transform(t);
+                mesh.set_layer_mask_bit(0, false);
+                mesh.set_layer_mask_bit(1, true);
+                body.set_transform(t);
+
+                owner.add_child(mesh.cast::<Node>(), false);
+
+                let sb_obj = Object::from_sys(body.cast::<Object>().unwrap().to_sys());
+                let shape_owner = body.create_shape_owner(Some(sb_obj));
+
+                owner.add_child(body.cast::<Node>(), false);
+
+                self.chunk_nodes[idx] = Some(ChunkNodes {
+                    mesh, body, shape_owner, box_shape_owners: vec![],
+                });
             }
         }
-        println!("initialized godot objects");
 
-//        self.load_vol(owner);
-//        println!("loaded godot objects");
+        self.chunk_nodes[idx].as_mut().unwrap()
     }
 
-    fn serialize_vol(&mut self) -> Vec<u8> {
-        for z in 0..VOL_SIZE {
-            let iz  = z / SUBVOL_SIZE;
-            let izi = z % SUBVOL_SIZE;
+    /// Switches the meshes to the atlas shader material with the
+    /// atlas `texture`.
+    fn set_atlas_material(&mut self, texture: &str) {
+        let tile_size =
+            match &self.atlas {
+                Some(atlas) => atlas.tile_size(),
+                None        => return,
+            };
 
-            for y in 0..VOL_SIZE {
-                let iy  = y / SUBVOL_SIZE;
-                let iyi = y % SUBVOL_SIZE;
+        let tex =
+            match ResourceLoader::godot_singleton().load(
+                    GodotString::from_str(texture),
+                    GodotString::from_str("Texture"),
+                    false).and_then(|r| r.cast::<Texture>())
+            {
+                Some(tex) => tex,
+                None => {
+                    println!("Couldn't load voxel atlas texture '{}'", texture);
+                    return;
+                }
+            };
 
-                for x in 0..VOL_SIZE {
-                    let ix  = x / SUBVOL_SIZE;
-                    let ixi = x % SUBVOL_SIZE;
+        let mat =
+            ResourceLoader::godot_singleton().load(
+                GodotString::from_str("res://scenes/entities/materials/voxel_atlas_material.tres"),
+                GodotString::from_str("ShaderMaterial"),
+                false);
 
-                    self.vol.set(x as u16, y as u16, z as u16,
-                        self.octrees[
-                              iz * (SUBVOLS * SUBVOLS)
-                            + iy * SUBVOLS
-                            + ix].read().unwrap().get(
-                                ixi as u16,
-                                iyi as u16,
-                                izi as u16));
-                }
+        unsafe {
+            // Every structure can have its own atlas:
+            let mut mat =
+                mat.and_then(|m| m.duplicate(false))
+                   .and_then(|m| m.cast::<ShaderMaterial>())
+                   .unwrap();
+            mat.set_shader_param(
+                GodotString::from_str("atlas"), Variant::from_object(&tex));
+            mat.set_shader_param(
+                GodotString::from_str("tile_size"),
+                Variant::from_vector2(&vec2(tile_size[0], tile_size[1])));
+
+            self.material = mat.cast::<gdnative::Material>();
+            for nodes in self.chunk_nodes.iter_mut().flatten() {
+                nodes.mesh.set_material_override(self.material.clone());
             }
         }
+    }
 
-        self.vol.serialize()
+    fn serialize_vol(&self) -> Vec<u8> {
+        self.chunks.to_vol().serialize()
     }
 
     #[export]
     fn load_vol(&mut self, mut _owner: Spatial) {
         self.last_load_vol = std::time::Instant::now();
 
-        for z in 0..SUBVOLS {
-            for y in 0..SUBVOLS {
-                for x in 0..SUBVOLS {
-                    let mut ot = self.octrees[
-                          z * (SUBVOLS * SUBVOLS)
-                        + (SUBVOLS - (y + 1)) * SUBVOLS
-                        + x].write().unwrap();
-
-                    for sz in 0..SUBVOL_SIZE {
-                        for sy in 0..SUBVOL_SIZE {
-                            for sx in 0..SUBVOL_SIZE {
-                                ot.set(sx as u16, sy as u16, sz as u16,
-                                       *self.vol.at(Pos {
-                                           x: x as u16 * SUBVOL_SIZE as u16 + sx as u16,
-                                           y: y as u16 * SUBVOL_SIZE as u16 + sy as u16,
-                                           z: z as u16 * SUBVOL_SIZE as u16 + sz as u16,
-                                       }));
-                            }
-                        }
-                    }
-                }
-            }
-        }
-//        for z in 0..VOL_SIZE {
-//            let iz  = z / SUBVOL_SIZE;
-//            let izi = z % SUBVOL_SIZE;
-//
-//            for y in 0..VOL_SIZE {
-//                let iy  = y / SUBVOL_SIZE;
-//                let iyi = (SUBVOL_SIZE - 1) - (y % SUBVOL_SIZE);
-//
-//                for x in 0..VOL_SIZE {
-//                    let ix  = x / SUBVOL_SIZE;
-//                    let ixi = x % SUBVOL_SIZE;
-//
-//                    self.octrees[
-//                          iz * (SUBVOLS * SUBVOLS)
-//                        + iy * SUBVOLS
-//                        + ix].write().unwrap().set(
-//                            ixi as u16,
-//                            iyi as u16,
-//                            izi as u16,
-//                            *self.vol.at(Pos {
-//                              x: x as u16,
-//                              y: y as u16,
-//                              z: z as u16
-//                            }));
-//                }
-//            }
-//        }
+        self.chunks.write_vol(&self.vol);
+        let edits = self.journal.delta();
+        self.write_voxels_unrendered(&edits);
         println!("Copy To sub octrees took {}ms",
                  self.last_load_vol.elapsed().as_millis());
 
-        self.inc_vol_generation();
-        for z in 0..SUBVOLS {
-            for y in 0..SUBVOLS {
-                for x in 0..SUBVOLS {
-                    self.reload_at(
-                        x * SUBVOL_SIZE,
-                        y * SUBVOL_SIZE,
-                        z * SUBVOL_SIZE);
+        self.reload_dirty();
+
+        println!("Issue reload jobs took {}ms",
+                 self.last_load_vol.elapsed().as_millis());
+    }
+
+    /// Clears the voxels that are not inside of the smooth surface,
+    /// so only visible voxels can be mined.
+    fn clear_outside_of_surface(&mut self, density: &DensityField) {
+        for z in 0..self.vol.d {
+            for y in 0..self.vol.h {
+                for x in 0..self.vol.w {
+                    let p = Pos::new(x as u16, y as u16, z as u16);
+                    if !density.contains(p) || density.at(p) <= self.iso_level {
+                        self.vol.set(p.x, p.y, p.z, Voxel::default());
+                    }
                 }
             }
         }
+    }
 
-        println!("Issue reload jobs took {}ms",
-                 self.last_load_vol.elapsed().as_millis());
+    /// Returns the voxel at `p` in cursor coordinates.
+    fn voxel_at(&self, p: Pos) -> Voxel<u8> {
+        self.chunks.get_inv_y(p.x, p.y, p.z)
+    }
+
+    fn cursor_pos(&self) -> Pos {
+        Pos::new(self.cursor[0], self.cursor[1], self.cursor[2])
     }
 
-    fn inc_vol_generation(&mut self) {
-        self.vol_generation = self.vol_generation.wrapping_add(1);
+    /// Removes all voxel groups that got disconnected from the structure
+    /// by removing the voxels at `removed`. The affected chunks are
+    /// marked dirty.
+    fn detach_islands_at(&mut self, removed: &[Pos]) -> std::vec::Vec<VoxelIsland<u8>> {
+        let dims = self.layout.dims();
+        let seeds = removed.iter().flat_map(|p| neighbours6(*p, dims));
+        let island_voxels =
+            find_islands(
+                dims, seeds, Some(ISLAND_MAX_VOXELS),
+                |p| self.voxel_at(p).color != 0);
+
+        let mut islands = vec![];
+        for voxels in island_voxels {
+            islands.push(VoxelIsland::from_positions(&voxels, |p| self.voxel_at(p)));
+
+            for p in voxels {
+                let old = self.voxel_at(p);
+                self.journal.record(p, old.color, 0);
+                self.chunks.set_inv_y(p.x, p.y, p.z, 0.into());
+            }
+        }
 
-//        let new_vol_gen = (*self.vol_generation.get_mut()).wrapping_add(1);
-//        *self.vol_generation.get_mut() = new_vol_gen;
+        islands
     }
 
-    fn get_octree_at(&mut self, x: usize, y: usize, z: usize) -> (&std::sync::Arc<std::sync::RwLock<Octree<u8>>>, [u16; 3]) {
-        let iz  = z / SUBVOL_SIZE;
-        let izi = z % SUBVOL_SIZE;
+    fn write_voxels_unrendered(&mut self, edits: &[(Pos, u8)]) {
+        for (p, c) in edits.iter() {
+            self.chunks.set_inv_y(p.x, p.y, p.z, (*c).into());
+        }
+    }
 
-        let iy  = y / SUBVOL_SIZE;
-        let iyi = y % SUBVOL_SIZE;
+    /// Writes voxels given in cursor coordinates and rerenders
+    /// the affected chunks.
+    fn write_voxels(&mut self, edits: &[(Pos, u8)]) {
+        self.write_voxels_unrendered(edits);
+        self.reload_dirty();
+    }
 
-        let ix  = x / SUBVOL_SIZE;
-        let ixi = x % SUBVOL_SIZE;
+    /// Puts the changes against the generated structure into the
+    /// voxel edits that are written with the savegame.
+    fn save_edits(&self, owner: &mut Spatial) {
+        let (sysid, entid) = self.parent_info(owner);
+        lock_sscg!(sscg);
+        sscg.voxel_edits.borrow_mut().store(
+            (sysid.i(), entid.i()), &self.journal, self.layout.dims());
+    }
 
-        let sub_idx =
-              iz * (SUBVOLS * SUBVOLS)
-            + iy * SUBVOLS
-            + ix;
+    /// Reverts the last mining action, including the voxels of
+    /// islands that broke off.
+    #[export]
+    fn undo_edit(&mut self, mut owner: Spatial) -> bool {
+        match self.journal.undo() {
+            Some(edits) => {
+                self.write_voxels(&edits);
+                self.save_edits(&mut owner);
+                true
+            },
+            None => false,
+        }
+    }
 
-        (&self.octrees[sub_idx], [ixi as u16, iyi as u16, izi as u16])
+    #[export]
+    fn redo_edit(&mut self, mut owner: Spatial) -> bool {
+        match self.journal.redo() {
+            Some(edits) => {
+                self.write_voxels(&edits);
+                self.save_edits(&mut owner);
+                true
+            },
+            None => false,
+        }
     }
 
     #[export]
     fn mine_info_at_cursor(&mut self, mut _owner: Spatial) -> Variant {
-        let (ot, pos) =
-            self.get_octree_at(
-                self.cursor[0] as usize,
-                self.cursor[1] as usize,
-                self.cursor[2] as usize);
-        let v = ot.read().unwrap().get_inv_y(pos[0], pos[1], pos[2]);
+        let v = self.voxel_at(self.cursor_pos());
+        let mat = *self.materials.get(v.color);
         let mut dict = gdnative::Dictionary::new();
         dict.set(&Variant::from_str("material"), &Variant::from_i64(v.color as i64));
-        dict.set(&Variant::from_str("time"),     &Variant::from_f64(1.2));
+        dict.set(&Variant::from_str("time"),     &Variant::from_f64(mat.mining_time));
+        dict.set(&Variant::from_str("density"),  &Variant::from_f64(mat.density));
+        dict.set(&Variant::from_str("yield"),    &Variant::from_f64(mat.yield_units));
+        dict.set(&Variant::from_str("minable"),  &Variant::from_bool(mat.minable));
         dict.set(&Variant::from_str("x"),        &Variant::from_i64(self.cursor[0] as i64));
         dict.set(&Variant::from_str("y"),        &Variant::from_i64(self.cursor[1] as i64));
         dict.set(&Variant::from_str("z"),        &Variant::from_i64(self.cursor[2] as i64));
         Variant::from_dictionary(&dict)
     }
 
+    /// Returns a dictionary that maps each material present in the
+    /// structure to its voxel count.
+    #[export]
+    fn material_counts(&mut self, mut _owner: Spatial) -> Variant {
+        let hist = self.chunks.color_histogram();
+
+        let mut dict = gdnative::Dictionary::new();
+        for (i, cnt) in hist.iter().enumerate().skip(1) {
+            if *cnt == 0 { continue; }
+            dict.set(&Variant::from_i64(i as i64), &Variant::from_i64(*cnt as i64));
+        }
+        Variant::from_dictionary(&dict)
+    }
+
+    /// Writes the current, possibly mined, structure as MagicaVoxel
+    /// file with the material colors as palette.
+    #[export]
+    fn export_magica_vox(&mut self, mut _owner: Spatial, path: GodotString) -> bool {
+        let vol = self.chunks.to_vol();
+        let palette = self.materials.color_map().to_vox_palette();
+        let data =
+            match vol.to_magica_vox(Some(&palette)) {
+                Ok(data) => data,
+                Err(e) => {
+                    println!("Couldn't export voxel structure: {}", e);
+                    return false;
+                }
+            };
+
+        let mut buf = ByteArray::new();
+        for b in data.iter() { buf.push(*b); }
+
+        let mut f = File::new();
+        match f.open(path.clone(), 2) {
+            Ok(_) => {
+                f.store_buffer(buf);
+                f.close();
+                true
+            },
+            Err(e) => {
+                println!("Couldn't open '{}': {:?}", path.to_string(), e);
+                false
+            }
+        }
+    }
+
+    /// Writes the current structure as mesh. `path` is the file
+    /// name without extension, `format` one of "obj", "ply" or "gltf".
+    /// OBJ writes an MTL file next to it.
+    #[export]
+    fn export_mesh(&mut self, mut _owner: Spatial, path: GodotString, format: GodotString) -> bool {
+        let format =
+            match MeshFormat::from_name(&format.to_string()) {
+                Some(f) => f,
+                None => {
+                    println!("Unknown mesh format: {}", format.to_string());
+                    return false;
+                }
+            };
+
+        let path = path.to_string();
+        let (dir, name) =
+            match path.rfind('/') {
+                Some(i) => (&path[0..=i], &path[i + 1..]),
+                None    => ("", &path[..]),
+            };
+
+        let files =
+            export_chunked_mesh(&self.chunks, &self.materials.color_map(), format, name);
+
+        for (file, data) in files.iter() {
+            let mut buf = ByteArray::new();
+            for b in data.iter() { buf.push(*b); }
+
+            let file_path = format!("{}{}", dir, file);
+            let mut f = File::new();
+            match f.open(GodotString::from_str(&file_path), 2) {
+                Ok(_) => {
+                    f.store_buffer(buf);
+                    f.close();
+                },
+                Err(e) => {
+                    println!("Couldn't open '{}': {:?}", file_path, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
     #[export]
     fn looking_at(&mut self, owner: Spatial, x: f64, y: f64, z: f64) -> bool {
         unsafe {
@@ -361,13 +776,7 @@ impl VoxStruct {
                 t.origin.z as u16,
             ];
 
-            let (ot, pos) =
-                self.get_octree_at(
-                    self.cursor[0] as usize,
-                    self.cursor[1] as usize,
-                    self.cursor[2] as usize);
-            let v = ot.read().unwrap().get_inv_y(pos[0], pos[1], pos[2]);
-            v.color != 0
+            self.voxel_at(self.cursor_pos()).color != 0
 
 //            {
 //                let (ot, pos) =
@@ -436,8 +845,7 @@ impl VoxStruct {
 
             let mut m = part.get_material_override().unwrap()
                             .cast::<SpatialMaterial>().unwrap();
-            let cm = self.color_map;
-            let mut clr = cm.map(color);
+            let mut clr = self.materials.map(color);
             let mut fx_color : Rgb = Rgb::new(clr.r, clr.g, clr.b);
             let mut fx_color_hsv : Hsv = fx_color.into();
             fx_color_hsv.saturation = 1.0;
@@ -448,7 +856,7 @@ impl VoxStruct {
             clr.b = fx_color.blue;
             m.set_albedo(clr);
             m.set_emission(clr);
-            part.set_material_override(m.cast::<Material>());
+            part.set_material_override(m.cast::<gdnative::Material>());
 
             let mut t = part.get_transform();
             t.origin.x = self.cursor[0] as f32 + 0.5;
@@ -465,12 +873,10 @@ impl VoxStruct {
 
     #[export]
     fn mine_status(&mut self, mut owner: Spatial, started: bool) -> bool {
-        let (ot, pos) =
-            self.get_octree_at(
-                self.cursor[0] as usize,
-                self.cursor[1] as usize,
-                self.cursor[2] as usize);
-        let m = ot.read().unwrap().get_inv_y(pos[0], pos[1], pos[2]);
+        let m = self.voxel_at(self.cursor_pos());
+        if started && !self.materials.get(m.color).minable {
+            return false;
+        }
 
         let (sysid, entid) = self.parent_info(&mut owner);
         lock_sscg!(sscg);
@@ -487,39 +893,65 @@ impl VoxStruct {
         ret.b()
     }
 
-    #[export]
-    fn mine_at_cursor(&mut self, mut owner: Spatial) -> bool {
-        // Prevent any change of the volume while it's being rerendered.
-        if self.workers.queued_job_count() > 0 {
-            return false;
+    /// Tells WLambda about the voxel groups that broke off.
+    fn report_islands(sscg: &mut SSCGState, sysid: &VVal, entid: &VVal,
+                      islands: std::vec::Vec<VoxelIsland<u8>>)
+    {
+        for island in islands {
+            let counts = VVal::map();
+            let mut hist = [0_i64; 256];
+            for v in island.vol.data.iter() { hist[v.color as usize] += 1; }
+            for (c, cnt) in hist.iter().enumerate().skip(1) {
+                if *cnt > 0 {
+                    counts.set_map_key(format!("{}", c), VVal::Int(*cnt));
+                }
+            }
+
+            let offs = VVal::vec();
+            offs.push(VVal::Int(island.offset.x as i64));
+            offs.push(VVal::Int(island.offset.y as i64));
+            offs.push(VVal::Int(island.offset.z as i64));
+            let dims = VVal::vec();
+            dims.push(VVal::Int(island.vol.w as i64));
+            dims.push(VVal::Int(island.vol.h as i64));
+            dims.push(VVal::Int(island.vol.d as i64));
+
+            sscg.call_cb(
+                "on_voxel_island_detached",
+                &vec![sysid.clone(), entid.clone(),
+                      offs, dims, counts,
+                      VVal::Int(island.voxel_count as i64)]);
         }
+    }
 
-        let (ot, pos) =
-            self.get_octree_at(
-                self.cursor[0] as usize,
-                self.cursor[1] as usize,
-                self.cursor[2] as usize);
-        let m = ot.read().unwrap().get_inv_y(pos[0], pos[1], pos[2]);
+    #[export]
+    fn mine_at_cursor(&mut self, mut owner: Spatial) -> bool {
+        let p = self.cursor_pos();
+        let m = self.voxel_at(p);
 
         if m.color != 0 {
-            ot.write().unwrap().set_inv_y(pos[0], pos[1], pos[2], 0.into());
-            self.inc_vol_generation();
-            self.reload_at(
-                self.cursor[0] as usize,
-                self.cursor[1] as usize,
-                self.cursor[2] as usize);
+            self.chunks.set_inv_y(p.x, p.y, p.z, 0.into());
+            self.journal.record(p, m.color, 0);
+
+            let islands = self.detach_islands_at(&[p]);
+            self.journal.commit();
+
+            self.reload_dirty();
+            self.save_edits(&mut owner);
 
             lock_sscg!(sscg);
             let (sysid, entid) = self.parent_info(&mut owner);
             sscg.call_cb(
                 "on_mined_voxel",
-                &vec![sysid, entid,
+                &vec![sysid.clone(), entid.clone(),
                       VVal::Int(m.color as i64),
                       VVal::Int(self.cursor[0] as i64),
                       VVal::Int(self.cursor[1] as i64),
                       VVal::Int(self.cursor[2] as i64),
                       ]);
 
+            Self::report_islands(sscg, &sysid, &entid, islands);
+
             self.spawn_mine_pop_at_cursor(owner, m.color);
 
             true
@@ -528,53 +960,207 @@ impl VoxStruct {
         }
     }
 
+    /// Mines with the tool "single", "sphere", "drill" or "plane" of
+    /// the given `size` at the cursor, `normal` being the normal of the
+    /// hit voxel face. Sizes beyond the chunk size of the structure are
+    /// refused. All minable voxels the tool covers are removed in
+    /// one edit and reported per material with `on_mined_area`.
+    #[export]
+    fn mine_area_at_cursor(&mut self, mut owner: Spatial, tool: GodotString,
+                           size: i64, normal: Vector3) -> bool
+    {
+        let tool_name = tool.to_string();
+        let size =
+            match self.layout.tool_size(size) {
+                Some(s) => s,
+                None => {
+                    println!("Mining tool size {} is too big, at most {}",
+                             size, self.layout.max_tool_size());
+                    return false;
+                }
+            };
+        let hit  = Face::from_normal([normal.x, normal.y, normal.z]);
+        let tool =
+            match MiningTool::from_name(&tool_name, size, hit) {
+                Some(t) => t,
+                None => {
+                    println!("Unknown mining tool: {}", tool_name);
+                    return false;
+                }
+            };
+
+        let target = self.voxel_at(self.cursor_pos());
+        let dims = self.layout.dims();
+        let mut mined   = MiningYield::new();
+        let mut removed = vec![];
+        for p in tool.positions(self.cursor_pos(), dims) {
+            let c = self.voxel_at(p).color;
+            if c == 0 || !self.materials.get(c).minable { continue; }
+
+            self.chunks.set_inv_y(p.x, p.y, p.z, 0.into());
+            self.journal.record(p, c, 0);
+            mined.add(c);
+            removed.push(p);
+        }
+        if removed.is_empty() {
+            return false;
+        }
+
+        let islands = self.detach_islands_at(&removed);
+        self.journal.commit();
+
+        self.reload_dirty();
+        self.save_edits(&mut owner);
+
+        // One entry per material with the voxel count, the total
+        // yield and the total mining time:
+        let report = VVal::map();
+        for (c, cnt) in mined.materials() {
+            let mat = self.materials.get(c);
+            let m = VVal::map();
+            m.set_map_key("count".to_string(), VVal::Int(cnt as i64));
+            m.set_map_key("yield".to_string(), VVal::Flt(mat.yield_units * cnt as f64));
+            m.set_map_key("time".to_string(),  VVal::Flt(mat.mining_time * cnt as f64));
+            report.set_map_key(format!("{}", c), m);
+        }
+
+        lock_sscg!(sscg);
+        let (sysid, entid) = self.parent_info(&mut owner);
+        sscg.call_cb(
+            "on_mined_area",
+            &vec![sysid.clone(), entid.clone(),
+                  VVal::new_str_mv(tool_name),
+                  VVal::Int(self.cursor[0] as i64),
+                  VVal::Int(self.cursor[1] as i64),
+                  VVal::Int(self.cursor[2] as i64),
+                  report,
+                  VVal::Int(mined.total() as i64)]);
+
+        Self::report_islands(sscg, &sysid, &entid, islands);
+
+        self.spawn_mine_pop_at_cursor(owner, target.color);
+
+        true
+    }
+
     #[export]
     fn looking_at_nothing(&mut self, owner: Spatial) {
         self.set_marker_status(owner, false, false);
     }
 
     #[export]
-    fn _process(&mut self, mut _owner: Spatial, _delta: f64) {
-        self.wait_for_mesh_rendering();
+    fn _process(&mut self, mut owner: Spatial, _delta: f64) {
+        self.update_lod(&mut owner);
+        self.wait_for_mesh_rendering(&mut owner);
     }
 
-    fn wait_for_mesh_rendering(&mut self) {
-        let cur_vol_gen = self.vol_generation;
-        let mut max = 5;
-        while let Some(VoxRendResult { arrs, oct_subtree_idx, empty, vol_generation }) = self.workers.get_result() {
+    /// Turns the ambient occlusion of the voxel meshes on or off.
+    #[export]
+    fn set_ambient_occlusion(&mut self, mut _owner: Spatial, enabled: bool) {
+        if enabled == self.ambient_occlusion { return; }
+        self.ambient_occlusion = enabled;
 
-            if vol_generation < cur_vol_gen {
-                continue;
-            }
+        self.chunks.mark_all_dirty();
+        self.reload_dirty();
+    }
 
-            let d = std::time::Instant::now();
-            let (mut static_body, shape_owner_idx) = self.collision_shapes[oct_subtree_idx];
-            unsafe {
-                let mut am = ArrayMesh::new();
-                let mut cvshape = ConcavePolygonShape::new();
+    /// Selects the mesher by name: "octree" for one cube per octree
+    /// node or "greedy" for merged rectangles.
+    #[export]
+    fn set_mesh_backend(&mut self, mut _owner: Spatial, name: GodotString) {
+        let backend =
+            match MeshBackend::from_name(&name.to_string()) {
+                Some(b) => b,
+                None => {
+                    println!("Unknown mesh backend: {}", name.to_string());
+                    return;
+                }
+            };
+        if backend == self.mesh_backend { return; }
+        self.mesh_backend = backend;
 
-                let mut ssb = static_body.cast::<StaticBody>().unwrap();
-                ssb.shape_owner_clear_shapes(shape_owner_idx);
+        self.chunks.mark_all_dirty();
+        self.reload_dirty();
+    }
 
-                if let Some(rend_arrs) = arrs {
-                    rend_arrs.write_to(&mut am, &mut cvshape);
+    /// Selects the collision shapes of the chunks by name: "trimesh"
+    /// for the mesh triangles, "nodes" for one box per octree node or
+    /// "boxes" for merged boxes. Smooth surfaces always use triangles.
+    #[export]
+    fn set_collision_mode(&mut self, mut _owner: Spatial, name: GodotString) {
+        let mode =
+            match CollisionMode::from_name(&name.to_string()) {
+                Some(m) => m,
+                None => {
+                    println!("Unknown collision mode: {}", name.to_string());
+                    return;
+                }
+            };
+        if mode == self.collision_mode { return; }
+        self.collision_mode = mode;
+
+        self.chunks.mark_all_dirty();
+        self.reload_dirty();
+    }
 
-                    if cvshape.get_faces().len() > 0 {
-                        ssb.shape_owner_add_shape(
-                            shape_owner_idx,
-                            cvshape.cast::<Shape>());
+    /// Switches the level of detail by the camera distance and
+    /// rerenders all sub volumes if it changed.
+    fn update_lod(&mut self, owner: &mut Spatial) {
+        let dist = unsafe {
+            let cam_pos =
+                match owner.get_viewport().and_then(|vp| vp.get_camera()) {
+                    Some(cam) => cam.get_global_transform().origin,
+                    None      => return,
+                };
+            // Rotation is ignored, an approximate center is good enough:
+            let origin = owner.get_global_transform().origin;
+            let rel = cam_pos - origin;
+            self.camera_pos = Some([rel.x, rel.y, rel.z]);
+
+            let l = self.layout;
+            let center =
+                origin + vec3(l.w as f32 / 2.0, l.h as f32 / 2.0, l.d as f32 / 2.0);
+            (cam_pos - center).length()
+        };
+
+        let lod_depth = lod_depth_for_distance(dist);
+        if lod_depth == self.lod_depth { return; }
+        self.lod_depth = lod_depth;
+
+        self.chunks.mark_all_dirty();
+        self.reload_dirty();
+    }
+
+    fn wait_for_mesh_rendering(&mut self, owner: &mut Spatial) {
+        let mut max = 5;
+        while let Some((oct_subtree_idx, VoxRendResult { arrs })) = self.workers.get_result() {
+            match arrs {
+                Some(rend_arrs) => {
+                    let boxes = rend_arrs.collision_boxes().to_vec();
+                    self.clear_collision_boxes(oct_subtree_idx);
+                    let nodes = self.chunk_nodes(owner, oct_subtree_idx);
+                    unsafe {
+                        let mut am = ArrayMesh::new();
+                        let mut cvshape = ConcavePolygonShape::new();
+
+                        nodes.body.shape_owner_clear_shapes(nodes.shape_owner);
+                        rend_arrs.write_to(&mut am, &mut cvshape);
+
+                        if cvshape.get_faces().len() > 0 {
+                            nodes.body.shape_owner_add_shape(
+                                nodes.shape_owner,
+                                cvshape.cast::<gdnative::Shape>());
+                        }
+                        nodes.mesh.set_mesh(am.cast::<Mesh>());
+                        nodes.mesh.show();
+                        nodes.body.show();
                     }
-                    self.meshes[oct_subtree_idx].set_mesh(am.cast::<Mesh>());
-                    self.meshes[oct_subtree_idx].show();
-                    static_body.show();
-                } else {
-                    self.meshes[oct_subtree_idx].set_mesh(am.cast::<Mesh>());
-                    self.meshes[oct_subtree_idx].hide();
-                    static_body.hide();
-                }
+                    self.add_collision_boxes(oct_subtree_idx, &boxes);
+                },
+                None => self.clear_chunk_mesh(oct_subtree_idx),
             }
 
-            if self.workers.queued_job_count() == 0 {
+            if self.workers.pending_count() == 0 {
                 println!("Workers done after {}ms", self.last_load_vol.elapsed().as_millis());
                 return;
             }
@@ -584,21 +1170,175 @@ impl VoxStruct {
         }
     }
 
-    fn reload_at(&mut self, x: usize, y: usize, z: usize) {
-        let iz  = z / SUBVOL_SIZE;
-        let iy  = y / SUBVOL_SIZE;
-        let ix  = x / SUBVOL_SIZE;
+    /// The smooth surface of a chunk also depends on the voxels next
+    /// to it, so the neighbouring chunks of changed ones are marked dirty.
+    fn mark_smooth_neighbours_dirty(&mut self) {
+        if self.density.is_none() { return; }
+
+        let (cw, ch, cd) = self.chunks.chunk_dims();
+        let dirty : Vec<usize> = self.chunks.dirty_chunks().collect();
+        for idx in dirty {
+            let (x, y, z) = self.chunks.chunk_coords(idx);
+            for nz in z.saturating_sub(1)..(z + 2).min(cd) {
+                for ny in y.saturating_sub(1)..(y + 2).min(ch) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(cw) {
+                        let n = self.chunks.chunk_index(nx, ny, nz);
+                        self.chunks.mark_dirty(n);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The squared distance of the center of chunk `idx` to the cursor
+    /// or the camera, whichever is closer.
+    fn chunk_priority(&self, idx: usize) -> u64 {
+        let o = self.chunks.chunk_origin_inv_y(idx);
+        let (w, h, d) = self.chunks.chunk_extent(idx);
+        let center = [
+            o.x as f32 + w as f32 / 2.0,
+            o.y as f32 + h as f32 / 2.0,
+            o.z as f32 + d as f32 / 2.0,
+        ];
+        let dist2 = |p: [f32; 3]| {
+              (p[0] - center[0]).powi(2)
+            + (p[1] - center[1]).powi(2)
+            + (p[2] - center[2]).powi(2)
+        };
+
+        let c = self.cursor;
+        let mut dist = dist2([c[0] as f32 + 0.5, c[1] as f32 + 0.5, c[2] as f32 + 0.5]);
+        if let Some(cam) = self.camera_pos {
+            dist = dist.min(dist2(cam));
+        }
+        dist as u64
+    }
+
+    /// Submits a render job for every dirty chunk, replacing its waiting
+    /// one. Chunks that were never written have no octree and just get
+    /// their mesh removed.
+    fn reload_dirty(&mut self) {
+        self.mark_smooth_neighbours_dirty();
+
+        let smooth =
+            self.density.as_ref().map(|density| Arc::new(SmoothSurface {
+                density: density.clone(),
+                iso:     self.iso_level,
+                chunks:  self.chunks.clone(),
+            }));
+
+        for idx in self.chunks.take_dirty() {
+            let ot =
+                match self.chunks.chunk(idx) {
+                    Some(ot) => ot.clone(),
+                    None => {
+                        self.workers.cancel(&idx);
+                        self.clear_chunk_mesh(idx);
+                        continue;
+                    }
+                };
+
+            let priority = self.chunk_priority(idx);
+            self.workers.submit(idx, priority, VoxRendJob {
+                color_map:       self.materials.color_map(),
+                mesh_opts:       MeshOptions {
+                    lod_depth:         self.lod_depth,
+                    ambient_occlusion: self.ambient_occlusion,
+                    backend:           self.mesh_backend,
+                    collision:         self.collision_mode,
+                },
+                oct_subtree_idx: idx,
+                oct_subtree:     ot,
+                smooth:          smooth.clone(),
+                atlas:           self.atlas,
+            });
+        }
+    }
 
-        let sub_idx =
-              iz * (SUBVOLS * SUBVOLS)
-            + iy * SUBVOLS
-            + ix;
+    fn add_collision_boxes(&mut self, idx: usize, boxes: &[CollisionBox]) {
+        let nodes =
+            match &mut self.chunk_nodes[idx] {
+                Some(nodes) => nodes,
+                None        => return,
+            };
+        unsafe {
+            for b in boxes.iter() {
+                let sb_obj = Object::from_sys(nodes.body.cast::<Object>().unwrap().to_sys());
+                let id = nodes.body.create_shape_owner(Some(sb_obj));
+
+                let mut shape = BoxShape::new();
+                shape.set_extents(
+                    vec3(b.half_extents[0], b.half_extents[1], b.half_extents[2]));
+                nodes.body.shape_owner_set_transform(id, Transform {
+                    basis:  Basis::identity(),
+                    origin: vec3(b.center[0], b.center[1], b.center[2]),
+                });
+                nodes.body.shape_owner_add_shape(id, shape.cast::<gdnative::Shape>());
+                nodes.box_shape_owners.push(id);
+            }
+        }
+    }
+
+    fn clear_collision_boxes(&mut self, idx: usize) {
+        if let Some(nodes) = &mut self.chunk_nodes[idx] {
+            for id in nodes.box_shape_owners.drain(..) {
+                unsafe { nodes.body.remove_shape_owner(id); }
+            }
+        }
+    }
+
+    /// Empties the nodes of a chunk, if it has any.
+    fn clear_chunk_mesh(&mut self, idx: usize) {
+        self.clear_collision_boxes(idx);
+        if let Some(nodes) = &mut self.chunk_nodes[idx] {
+            unsafe {
+                nodes.body.shape_owner_clear_shapes(nodes.shape_owner);
+                let am = ArrayMesh::new();
+                nodes.mesh.set_mesh(am.cast::<Mesh>());
+                nodes.mesh.hide();
+                nodes.body.hide();
+            }
+        }
+    }
+}
 
-          self.workers.send(VoxRendJob {
-              vol_generation:  self.vol_generation,
-              color_map:       self.color_map,
-              oct_subtree_idx: sub_idx,
-              oct_subtree:     self.octrees[sub_idx].clone()
-          });
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_material_overrides() {
+        let cm = ColorMap::new_gray();
+        let mut table = MaterialTable::new_from_color_map(&cm);
+
+        let mats = VVal::vec();
+        mats.push(VVal::Nul);
+        let m = VVal::map();
+        m.set_map_key("time".to_string(),    VVal::Flt(2.5));
+        m.set_map_key("density".to_string(), VVal::Flt(3.0));
+        m.set_map_key("yield".to_string(),   VVal::Int(4));
+        m.set_map_key("minable".to_string(), VVal::Bol(false));
+        m.set_map_key("color".to_string(),   VVal::new_str("#ff0000"));
+        mats.push(m);
+        // A bad color is skipped, the rest still applies:
+        let m = VVal::map();
+        m.set_map_key("time".to_string(),    VVal::Flt(0.5));
+        m.set_map_key("color".to_string(),   VVal::new_str("red-ish"));
+        mats.push(m);
+        vval2materials(mats, &mut table);
+
+        assert_eq!(*table.get(0), Material { color: cm.colors[0], minable: false,
+                                             ..Material::default() });
+        assert_eq!(*table.get(1), Material {
+            color:       [1.0, 0.0, 0.0],
+            mining_time: 2.5,
+            density:     3.0,
+            yield_units: 4.0,
+            minable:     false,
+        });
+        assert_eq!(table.get(2).mining_time, 0.5);
+        assert_eq!(table.get(2).color, cm.colors[2]);
+        assert!(table.get(2).minable);
+        assert_eq!(*table.get(3), Material { color: cm.colors[3], ..Material::default() });
     }
 }
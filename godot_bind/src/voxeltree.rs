@@ -1,3 +1,5 @@
+use crate::voxel_export::{base64_encode, base64_decode};
+
 pub trait VoxelColor: PartialEq + Sized + Copy + Into<u8> + From<u8> + std::fmt::Debug + Default {}
 impl<T: PartialEq + Sized + Copy + Into<u8> + From<u8> + std::fmt::Debug + Default> VoxelColor for T {}
 
@@ -59,118 +61,385 @@ impl Into<Voxel<u8>> for u8 {
     }
 }
 
+const VOL_V2_HEADER_LEN : usize = 12;
+const VOL_V3_HEADER_LEN : usize = 20;
+/// Upper bound for the voxel count of deserialized volumes,
+/// so that corrupt size fields don't lead to huge allocations.
+const VOL_MAX_VOXELS    : usize = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VolError {
+    Truncated { expected: usize, got: usize },
+    BadMagic,
+    UnsupportedVersion(u8),
+    SizeTooBig(usize),
+    ChecksumMismatch { expected: u32, got: u32 },
+    BadRunLength { offset: usize },
+    VoxelCountMismatch { expected: usize, got: usize },
+    NotMagicaVox,
+    MissingVoxChunk(&'static str),
+    ModelClipped { model: (usize, usize, usize), vol: (usize, usize, usize) },
+    NotADelta,
+    DeltaSizeMismatch { expected: (usize, usize, usize), got: (usize, usize, usize) },
+    BadDeltaEntry { offset: usize },
+    BadChunkSize(usize),
+}
+
+impl std::fmt::Display for VolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VolError::Truncated { expected, got } =>
+                write!(f, "volume data truncated: expected {} bytes, got {}",
+                       expected, got),
+            VolError::BadMagic =>
+                write!(f, "volume data does not start with 'vox'"),
+            VolError::UnsupportedVersion(v) =>
+                write!(f, "unsupported volume format version {}", v),
+            VolError::SizeTooBig(s) =>
+                write!(f, "volume size {} is too big", s),
+            VolError::ChecksumMismatch { expected, got } =>
+                write!(f, "volume checksum mismatch: expected {:08x}, got {:08x}",
+                       expected, got),
+            VolError::BadRunLength { offset } =>
+                write!(f, "bad run length at payload offset {}", offset),
+            VolError::VoxelCountMismatch { expected, got } =>
+                write!(f, "volume payload holds {} voxels, expected {}",
+                       got, expected),
+            VolError::NotMagicaVox =>
+                write!(f, "data is not a MagicaVoxel file, 'VOX ' header missing"),
+            VolError::MissingVoxChunk(id) =>
+                write!(f, "MagicaVoxel file has no '{}' chunk", id),
+            VolError::ModelClipped { model, vol } =>
+                write!(f, "model of size {}x{}x{} was clipped to the volume of size {}x{}x{}",
+                       model.0, model.1, model.2, vol.0, vol.1, vol.2),
+            VolError::NotADelta =>
+                write!(f, "edit delta does not start with 'vdl'"),
+            VolError::DeltaSizeMismatch { expected, got } =>
+                write!(f, "edit delta is for a volume of size {}x{}x{}, expected {}x{}x{}",
+                       got.0, got.1, got.2, expected.0, expected.1, expected.2),
+            VolError::BadDeltaEntry { offset } =>
+                write!(f, "bad edit delta entry at payload offset {}", offset),
+            VolError::BadChunkSize(s) =>
+                write!(f, "chunk size {} is not between {} and {}",
+                       s, STRUCTURE_MIN_CHUNK_SIZE, STRUCTURE_MAX_CHUNK_SIZE),
+        }
+    }
+}
+
+impl std::error::Error for VolError { }
+
+fn read_u32_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// CRC-32 (IEEE 802.3 polynomial), as used by zlib and PNG.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc : u32 = 0xFFFF_FFFF;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Reads a LEB128 encoded integer at `*i` and advances `*i` past it.
+fn read_varint(data: &[u8], i: &mut usize) -> Option<usize> {
+    let mut v     = 0_usize;
+    let mut shift = 0;
+    loop {
+        if *i >= data.len() || shift > 35 { return None; }
+        let b = data[*i];
+        *i += 1;
+        v |= ((b & 0x7F) as usize) << shift;
+        shift += 7;
+        if b & 0x80 == 0 { return Some(v); }
+    }
+}
+
+fn rle_push_run(out: &mut Vec<u8>, run: usize, c: u8) {
+    let mut run = run;
+    loop {
+        let b = (run & 0x7F) as u8;
+        run >>= 7;
+        if run == 0 { out.push(b); break; }
+        out.push(b | 0x80);
+    }
+    out.push(c);
+}
+
+fn rle_encode<I: Iterator<Item=u8>>(colors: I) -> Vec<u8> {
+    let mut out = vec![];
+
+    let mut cur : Option<(u8, usize)> = None;
+    for c in colors {
+        cur =
+            match cur {
+                Some((rc, run)) if rc == c => Some((rc, run + 1)),
+                Some((rc, run)) => { rle_push_run(&mut out, run, rc); Some((c, 1)) },
+                None            => Some((c, 1)),
+            };
+    }
+    if let Some((rc, run)) = cur {
+        rle_push_run(&mut out, run, rc);
+    }
+
+    out
+}
+
+fn rle_decode(payload: &[u8], voxel_count: usize) -> Result<Vec<u8>, VolError> {
+    let mut out = Vec::with_capacity(voxel_count);
+
+    let mut i = 0;
+    while i < payload.len() {
+        let run_start = i;
+        let run =
+            read_varint(payload, &mut i)
+            .ok_or(VolError::BadRunLength { offset: run_start })?;
+
+        if i >= payload.len() || run == 0 || out.len() + run > voxel_count {
+            return Err(VolError::BadRunLength { offset: run_start });
+        }
+
+        let c = payload[i];
+        i += 1;
+        out.resize(out.len() + run, c);
+    }
+
+    if out.len() != voxel_count {
+        return Err(VolError::VoxelCountMismatch {
+            expected: voxel_count,
+            got:      out.len(),
+        });
+    }
+
+    Ok(out)
+}
+
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct Vol<C> where C: VoxelColor {
-    pub size: usize,
+    pub w: usize,
+    pub h: usize,
+    pub d: usize,
     pub data: std::vec::Vec<Voxel<C>>,
 }
 
 impl<C> Vol<C> where C: VoxelColor {
     pub fn new(size: usize) -> Self {
-        let mut data = std::vec::Vec::new();
-        data.resize(size * size * size, Voxel::default());
-        Self {
-            size,
-            data,
-        }
+        Self::new_dims(size, size, size)
     }
 
     pub fn new_default(size: usize, default: Voxel<C>) -> Self {
+        Self::new_dims_default(size, size, size, default)
+    }
+
+    pub fn new_dims(w: usize, h: usize, d: usize) -> Self {
+        Self::new_dims_default(w, h, d, Voxel::default())
+    }
+
+    pub fn new_dims_default(w: usize, h: usize, d: usize, default: Voxel<C>) -> Self {
         let mut data = std::vec::Vec::new();
-        data.resize(size * size * size, default);
+        data.resize(w * h * d, default);
         Self {
-            size,
+            w,
+            h,
+            d,
             data,
         }
     }
 
-    pub fn deserialize(&mut self, data: &[u8]) {
-        let size = data[4] as usize;
-        let mut dv : std::vec::Vec<Voxel<C>> = std::vec::Vec::new();
-        dv.resize(size.pow(3) as usize, Voxel::default());
+    /// Returns true if `pos` lies inside the volume.
+    pub fn contains(&self, pos: Pos) -> bool {
+           (pos.x as usize) < self.w
+        && (pos.y as usize) < self.h
+        && (pos.z as usize) < self.d
+    }
+
+    fn idx(&self, x: PInt, y: PInt, z: PInt) -> usize {
+        z as usize * self.w * self.h + y as usize * self.w + x as usize
+    }
+
+    /// Reads a volume written by `serialize`. Version 1 (raw voxels with
+    /// an 8 bit size), version 2 (run length encoded cube with checksum)
+    /// and version 3 (like version 2, with independent width, height
+    /// and depth) are supported. On error the volume is left untouched.
+    pub fn deserialize(&mut self, data: &[u8]) -> Result<(), VolError> {
+        if data.len() < 4 {
+            return Err(VolError::Truncated { expected: 4, got: data.len() });
+        }
+        if &data[0..3] != b"vox" {
+            return Err(VolError::BadMagic);
+        }
+
+        let ((w, h, d), colors) =
+            match data[3] {
+                1 => Self::deserialize_v1(data)?,
+                2 => Self::deserialize_rle(data, VOL_V2_HEADER_LEN)?,
+                3 => Self::deserialize_rle(data, VOL_V3_HEADER_LEN)?,
+                v => { return Err(VolError::UnsupportedVersion(v)); },
+            };
 
-        for i in 5..(size.pow(3) + 5) {
-            dv[i - 5].color = data[i].into();
+        let mut dv : std::vec::Vec<Voxel<C>> = std::vec::Vec::new();
+        dv.resize(w * h * d, Voxel::default());
+        for (v, c) in dv.iter_mut().zip(colors.iter()) {
+            v.color = (*c).into();
         }
 
-        self.size = size;
+        self.w    = w;
+        self.h    = h;
+        self.d    = d;
         self.data = dv;
+        Ok(())
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut out : Vec<u8> = vec![];
-        out.resize(5 + self.size.pow(3) as usize, 0);
+    fn deserialize_v1(data: &[u8])
+        -> Result<((usize, usize, usize), std::vec::Vec<u8>), VolError>
+    {
+        if data.len() < 5 {
+            return Err(VolError::Truncated { expected: 5, got: data.len() });
+        }
+
+        let size = data[4] as usize;
+        let len  = 5 + size.pow(3);
+        if data.len() < len {
+            return Err(VolError::Truncated { expected: len, got: data.len() });
+        }
 
-        let mut i = 0;
-        out[i] = 'v' as u32 as u8; i += 1;
-        out[i] = 'o' as u32 as u8; i += 1;
-        out[i] = 'x' as u32 as u8; i += 1;
-        out[i] = 1;                i += 1;
-        out[i] = self.size as u8;
+        Ok(((size, size, size), data[5..len].to_vec()))
+    }
+
+    /// Reads the run length encoded versions. Version 2 stores a single
+    /// edge size, version 3 stores width, height and depth.
+    fn deserialize_rle(data: &[u8], header_len: usize)
+        -> Result<((usize, usize, usize), std::vec::Vec<u8>), VolError>
+    {
+        if data.len() < header_len + 4 {
+            return Err(VolError::Truncated {
+                expected: header_len + 4,
+                got: data.len()
+            });
+        }
 
-        for (i, c) in self.data.iter().enumerate() {
-            out[i + 5] = c.color.into();
+        let dims =
+            if header_len == VOL_V2_HEADER_LEN {
+                let size = read_u32_le(&data[4..8]) as usize;
+                (size, size, size)
+            } else {
+                (read_u32_le(&data[4..8])   as usize,
+                 read_u32_le(&data[8..12])  as usize,
+                 read_u32_le(&data[12..16]) as usize)
+            };
+        let payload_len =
+            read_u32_le(&data[(header_len - 4)..header_len]) as usize;
+        let len = header_len + payload_len + 4;
+        if data.len() < len {
+            return Err(VolError::Truncated { expected: len, got: data.len() });
+        }
+
+        let expected_crc = read_u32_le(&data[(len - 4)..len]);
+        let crc          = crc32(&data[0..(len - 4)]);
+        if crc != expected_crc {
+            return Err(VolError::ChecksumMismatch {
+                expected: expected_crc,
+                got:      crc
+            });
         }
 
+        let voxel_count =
+            dims.0.checked_mul(dims.1)
+                .and_then(|s| s.checked_mul(dims.2))
+                .filter(|c| *c <= VOL_MAX_VOXELS)
+                .ok_or(VolError::SizeTooBig(dims.0.max(dims.1).max(dims.2)))?;
+
+        let colors =
+            rle_decode(&data[header_len..(len - 4)], voxel_count)?;
+        Ok((dims, colors))
+    }
+
+    /// Writes the volume in the version 3 format:
+    ///
+    /// - `"vox"` and the version byte `3`
+    /// - width, height and depth as little endian `u32`
+    /// - the payload length as little endian `u32`
+    /// - the payload: colors as runs of a LEB128 run length
+    ///   followed by the color byte
+    /// - a CRC-32 of everything before it as little endian `u32`
+    pub fn serialize(&self) -> Vec<u8> {
+        let payload = rle_encode(self.data.iter().map(|v| v.color.into()));
+
+        let mut out : Vec<u8> = vec![];
+        out.extend_from_slice(b"vox");
+        out.push(3);
+        out.extend_from_slice(&(self.w as u32).to_le_bytes());
+        out.extend_from_slice(&(self.h as u32).to_le_bytes());
+        out.extend_from_slice(&(self.d as u32).to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&payload);
+
+        let crc = crc32(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+
         out
     }
 
     pub fn set(&mut self, x: PInt, y: PInt, z: PInt, v: Voxel<C>) {
-        self.data[z as usize * self.size * self.size + y as usize * self.size + x as usize] = v;
+        let i = self.idx(x, y, z);
+        self.data[i] = v;
     }
 
     pub fn at(&self, pos: Pos) -> &Voxel<C> {
-        &self.data[pos.z as usize * self.size * self.size + pos.y as usize * self.size + pos.x as usize]
+        &self.data[self.idx(pos.x, pos.y, pos.z)]
     }
 
     pub fn color_at(&self, pos: Pos) -> &C {
-        &self.data[pos.z as usize * self.size * self.size + pos.y as usize * self.size + pos.x as usize].color
+        &self.data[self.idx(pos.x, pos.y, pos.z)].color
     }
 
     pub fn get(&mut self, pos: Pos) -> &Voxel<C> {
         let mut faces: u8 = 0x0;
 
         let clr_def = C::default();
-        let size        = self.size;
-        let last : PInt = (self.size - 1) as PInt;
+        let last_x : PInt = (self.w - 1) as PInt;
+        let last_y : PInt = (self.h - 1) as PInt;
+        let last_z : PInt = (self.d - 1) as PInt;
 
-        if pos.x == 0    { faces |= F_LEFT; }
-        if pos.x == last { faces |= F_RIGHT; }
+        if pos.x == 0      { faces |= F_LEFT; }
+        if pos.x == last_x { faces |= F_RIGHT; }
         if pos.x > 0 {
-            let clr1 = self.data[pos.z as usize * size * size + pos.y as usize * size + pos.x as usize - 1].color;
+            let clr1 = self.data[self.idx(pos.x - 1, pos.y, pos.z)].color;
             if clr1 == clr_def { faces |= 0x08; }
         }
-        if pos.x < last {
-            let clr1 = self.data[pos.z as usize * size * size + pos.y as usize * size + pos.x as usize + 1].color;
+        if pos.x < last_x {
+            let clr1 = self.data[self.idx(pos.x + 1, pos.y, pos.z)].color;
             if clr1 == clr_def { faces |= 0x10; }
         }
 
-        if pos.y == 0         { faces |= F_TOP; }
-        else if pos.y == last { faces |= F_BOTTOM; }
+        if pos.y == 0           { faces |= F_TOP; }
+        else if pos.y == last_y { faces |= F_BOTTOM; }
         if pos.y > 0 {
-            let clr1 = self.data[pos.z as usize * size * size + (pos.y as usize - 1) * size + pos.x as usize].color;
+            let clr1 = self.data[self.idx(pos.x, pos.y - 1, pos.z)].color;
             if clr1 == clr_def { faces |= F_TOP; }
         }
-        if pos.y < last {
-            let clr2 = self.data[pos.z as usize * size * size + (pos.y as usize + 1) * size + pos.x as usize].color;
+        if pos.y < last_y {
+            let clr2 = self.data[self.idx(pos.x, pos.y + 1, pos.z)].color;
             if clr2 == clr_def { faces |= F_BOTTOM; }
         }
 
-        if pos.z == 0         { faces |= F_FRONT; }
-        else if pos.z == last { faces |= F_BACK; }
+        if pos.z == 0           { faces |= F_FRONT; }
+        else if pos.z == last_z { faces |= F_BACK; }
         if pos.z > 0 {
-            let clr1 = self.data[(pos.z as usize - 1) * size * size + pos.y as usize * size + pos.x as usize].color;
+            let clr1 = self.data[self.idx(pos.x, pos.y, pos.z - 1)].color;
             if clr1 == clr_def { faces |= F_FRONT; }
         }
-        if pos.z < last {
-            let clr2 = self.data[(pos.z as usize + 1) * size * size + pos.y as usize * size + pos.x as usize].color;
+        if pos.z < last_z {
+            let clr2 = self.data[self.idx(pos.x, pos.y, pos.z + 1)].color;
             if clr2 == clr_def { faces |= F_BACK; }
         }
 
-        let vox = &mut self.data[
-                  pos.z as usize * size * size
-                + pos.y as usize * size
-                + pos.x as usize];
+        let i = self.idx(pos.x, pos.y, pos.z);
+        let vox = &mut self.data[i];
         vox.faces = faces;
         vox
     }
@@ -186,555 +455,5113 @@ impl<C> Vol<C> where C: VoxelColor {
             }
         }
     }
-}
 
-#[derive(Clone, Copy, PartialEq, Default)]
-pub struct Pos {
-    pub x: PInt,
-    pub y: PInt,
-    pub z: PInt,
-}
+    /// Calls `f` for every voxel whose center lies inside `shape` and
+    /// stores the returned voxel. Returns the number of voxels written.
+    pub fn rasterize<F>(&mut self, shape: &Shape, mut f: F) -> usize
+        where F: FnMut(&Voxel<C>) -> Voxel<C>
+    {
+        let (min, max) =
+            shape.bounds().unwrap_or((
+                [0.0, 0.0, 0.0],
+                [self.w as f32, self.h as f32, self.d as f32]));
 
-impl std::fmt::Debug for Pos {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-//        use std::fmt;
-        write!(f, "({}, {}, {})", self.x, self.y, self.z)
-    }
-}
+        let range = |min: f32, max: f32, len: usize| {
+            let a = min.floor().max(0.0) as usize;
+            let b = (max.ceil().max(0.0) as usize).min(len);
+            a..b
+        };
 
+        let mut count = 0;
+        for z in range(min[2], max[2], self.d) {
+            for y in range(min[1], max[1], self.h) {
+                for x in range(min[0], max[0], self.w) {
+                    let center = [x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5];
+                    if !shape.contains(center) { continue; }
 
-impl Pos {
-    pub fn new(x: PInt, y: PInt, z: PInt) -> Self {
-        Self { x, y, z }
+                    let (x, y, z) = (x as PInt, y as PInt, z as PInt);
+                    let v = f(self.at(Pos::new(x, y, z)));
+                    self.set(x, y, z, v);
+                    count += 1;
+                }
+            }
+        }
+        count
     }
 
-    pub fn offs(&self, xo: PInt, yo: PInt, zo: PInt) -> Self {
-        Self { x: self.x + xo, y: self.y + yo, z: self.z + zo }
+    /// Like `set`, but records the change in `journal`.
+    pub fn set_journaled(&mut self, journal: &mut EditJournal<C>,
+                         x: PInt, y: PInt, z: PInt, v: Voxel<C>)
+    {
+        let old = self.at(Pos::new(x, y, z)).color;
+        journal.record(Pos::new(x, y, z), old, v.color);
+        self.set(x, y, z, v);
     }
 
-    pub fn mul(&self, m: PInt) -> Self {
-        Self { x: self.x * m, y: self.y * m, z: self.z * m }
+    /// Writes the edits returned by `EditJournal::undo`, `redo`
+    /// or `EditJournal::deserialize_delta`.
+    pub fn apply_edits(&mut self, edits: &[(Pos, C)]) {
+        for (pos, c) in edits.iter() {
+            self.set(pos.x, pos.y, pos.z, Voxel { color: *c, faces: 0 });
+        }
     }
-}
 
-#[derive(Clone, Copy, PartialEq, Default)]
-pub struct TreePos {
-    pub offs:  usize,
-    pub level: usize,
-    pub x: u8,
-    pub y: u8,
-    pub z: u8,
-}
+    /// Covers the solid voxels with boxes that don't overlap, grown
+    /// greedily along x, then y and then z. Colors are ignored.
+    pub fn greedy_boxes(&self) -> std::vec::Vec<VoxelBox> {
+        let empty = C::default();
+        let (w, h, d) = (self.w, self.h, self.d);
+        let idx  = |x: usize, y: usize, z: usize| z * w * h + y * w + x;
+        let free = |done: &[bool], x: usize, y: usize, z: usize| {
+            !done[idx(x, y, z)]
+            && self.at(Pos::new(x as PInt, y as PInt, z as PInt)).color != empty
+        };
 
-impl std::fmt::Debug for TreePos {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-//        use std::fmt;
-        write!(f, "[lvl={},offs={}]+({}, {}, {})=>{}",
-                  self.level, self.offs,
-                  self.x, self.y, self.z, self.idx())
-    }
-}
+        let mut done  = vec![false; w * h * d];
+        let mut boxes = vec![];
+        for z in 0..d {
+            for y in 0..h {
+                for x in 0..w {
+                    if !free(&done, x, y, z) { continue; }
 
-impl TreePos {
-    pub fn new() -> Self {
-        Self { offs: 0, level: 0, x: 0, y: 0, z: 0 }
-    }
+                    let mut bw = 1;
+                    while x + bw < w && free(&done, x + bw, y, z) { bw += 1; }
+                    let mut bh = 1;
+                    while y + bh < h
+                        && (x..x + bw).all(|ix| free(&done, ix, y + bh, z))
+                    {
+                        bh += 1;
+                    }
+                    let mut bd = 1;
+                    while z + bd < d
+                        && (y..y + bh).all(|iy|
+                            (x..x + bw).all(|ix| free(&done, ix, iy, z + bd)))
+                    {
+                        bd += 1;
+                    }
 
-    pub fn lvl_offs(&self, xo: u8, yo: u8, zo: u8) -> Self {
-        let add_offs =
-            if self.level == 0 { 1 }
-            else { 8_usize.pow(self.level as u32) };
-        Self {
-            offs:  self.offs + add_offs,
-            level: self.level + 1,
-            x: (self.x << 1) + xo,
-            y: (self.y << 1) + yo,
-            z: (self.z << 1) + zo,
+                    for iz in z..z + bd {
+                        for iy in y..y + bh {
+                            for ix in x..x + bw {
+                                done[idx(ix, iy, iz)] = true;
+                            }
+                        }
+                    }
+                    boxes.push(VoxelBox {
+                        pos:  Pos::new(x as PInt, y as PInt, z as PInt),
+                        size: [bw, bh, bd],
+                    });
+                }
+            }
         }
+        boxes
     }
 
-    pub fn idx(&self) -> usize {
-        if self.level == 0 { return 0; }
-        let edge_size = 2_usize.pow(self.level as u32);
-        self.offs
-        + self.z as usize * edge_size * edge_size
-        + self.y as usize * edge_size
-        + self.x as usize
-    }
-}
+    /// Merges the exposed faces of the voxels into as few rectangles
+    /// of the same color as the greedy algorithm finds. Faces at the
+    /// volume border are exposed.
+    pub fn greedy_quads(&self) -> std::vec::Vec<FaceQuad<C>> {
+        let empty = C::default();
+        let dims  = [self.w, self.h, self.d];
+        let mut quads = vec![];
 
-#[derive(Debug, Clone, Copy, PartialEq, Default)]
-pub struct OctNode<C: VoxelColor> {
-    pub voxel: Option<Voxel<C>>,
-    pub pos:   Pos,
-    pub empty: bool,
-    pub tree_pos: TreePos,
-}
+        for face in [F_FRONT, F_TOP, F_BACK, F_LEFT, F_RIGHT, F_BOTTOM].iter() {
+            let (axis, dir) = face_normal_axis(*face);
+            let (u, v) = match axis { 0 => (1, 2), 1 => (0, 2), _ => (0, 1) };
+            let (du, dv) = (dims[u], dims[v]);
 
-impl<C> OctNode<C> where C: VoxelColor {
-    pub fn new() -> Self {
-        Self {
-            voxel:  None,
-            pos:    Pos::default(),
-            empty:  true,
-            tree_pos: TreePos::default(),
-        }
-    }
-}
+            let mut mask : std::vec::Vec<Option<C>> = vec![None; du * dv];
+            for layer in 0..dims[axis] {
+                // Collect the exposed faces of this layer:
+                for iv in 0..dv {
+                    for iu in 0..du {
+                        let mut p = [0; 3];
+                        p[axis] = layer;
+                        p[u]    = iu;
+                        p[v]    = iv;
 
-#[derive(Debug, Clone, PartialEq, Default)]
-pub struct Octree<C: VoxelColor> {
-    nodes: std::vec::Vec<OctNode<C>>,
-    nodes_size: usize,
-    pub vol: Vol<C>,
-}
+                        let c = self.at(Pos::new(p[0] as PInt, p[1] as PInt, p[2] as PInt)).color;
+                        let n = p[axis] as i32 + dir;
+                        let exposed =
+                            if n < 0 || n >= dims[axis] as i32 { true }
+                            else {
+                                let mut np = p;
+                                np[axis] = n as usize;
+                                self.at(Pos::new(np[0] as PInt, np[1] as PInt, np[2] as PInt))
+                                    .color == empty
+                            };
 
-impl<C> Octree<C> where C: VoxelColor {
-    pub fn new_from_size(size: usize) -> Self {
-        let v : Vol<C> = Vol::new(size);
-        Octree::new(size * size * size, v)
-    }
+                        mask[iv * du + iu] =
+                            if c != empty && exposed { Some(c) } else { None };
+                    }
+                }
 
-    pub fn new(_node_count: usize, vol: Vol<C>) -> Self {
-        let mut size = vol.size >> 1;
-        let mut alloc = 1;
-        let mut subtree_size : usize = 2;
-        while size > 1 {
-            alloc += subtree_size.pow(3);
-            subtree_size *= 2;
-//            println!("SIZ {} {}", size, alloc);
-            size = size >> 1;
-        }
-        alloc += 1; // For the root node
-        let mut nodes = std::vec::Vec::new();
-        nodes.resize(alloc, OctNode::default());
-        Self {
-            nodes,
-            nodes_size: vol.size >> 1,
-            vol,
+                // Grow rectangles along u first, then along v:
+                for iv in 0..dv {
+                    let mut iu = 0;
+                    while iu < du {
+                        let c =
+                            match mask[iv * du + iu] {
+                                Some(c) => c,
+                                None    => { iu += 1; continue; },
+                            };
+
+                        let mut w = 1;
+                        while iu + w < du && mask[iv * du + iu + w] == Some(c) {
+                            w += 1;
+                        }
+
+                        let mut h = 1;
+                        'grow: while iv + h < dv {
+                            for k in 0..w {
+                                if mask[(iv + h) * du + iu + k] != Some(c) {
+                                    break 'grow;
+                                }
+                            }
+                            h += 1;
+                        }
+
+                        for jv in 0..h {
+                            for k in 0..w {
+                                mask[(iv + jv) * du + iu + k] = None;
+                            }
+                        }
+
+                        let mut p    = [0; 3];
+                        let mut size = [1; 3];
+                        p[axis] = layer;
+                        p[u]    = iu;
+                        p[v]    = iv;
+                        size[u] = w;
+                        size[v] = h;
+                        quads.push(FaceQuad {
+                            face:  *face,
+                            pos:   Pos::new(p[0] as PInt, p[1] as PInt, p[2] as PInt),
+                            size,
+                            color: c,
+                        });
+
+                        iu += w;
+                    }
+                }
+            }
         }
+
+        quads
     }
 
-    pub fn draw<F>(&self, f: &mut F) where F: FnMut(usize, &Pos, Voxel<C>) -> () {
-        self.draw_level(TreePos::new(), self.vol.size, Pos { x: 0, y: 0, z: 0 }, f);
+    /// Sets every voxel inside `shape` to `v`.
+    pub fn draw_shape(&mut self, shape: &Shape, v: Voxel<C>) -> usize {
+        self.rasterize(shape, |_| v)
     }
 
-    pub fn draw_level<F>(&self, tp: TreePos, size: usize, top_left: Pos, f: &mut F)
-        where F: FnMut(usize, &Pos, Voxel<C>) -> ()
-    {
-        if size == 1 {
-            let v = self.vol.at(top_left);
-            if v.color != C::default() {
-                f(1, &top_left, *v);
+    /// Combines `src` into this volume, with the origin of `src` placed
+    /// at `offs`. Parts of `src` outside of this volume are clipped.
+    pub fn combine(&mut self, src: &Vol<C>, offs: Pos, op: CsgOp) {
+        let empty = C::default();
+
+        if op == CsgOp::Intersect {
+            // Everything outside of `src` is empty in `src`:
+            for z in 0..self.d {
+                for y in 0..self.h {
+                    for x in 0..self.w {
+                        let inside =
+                               x >= offs.x as usize && x < offs.x as usize + src.w
+                            && y >= offs.y as usize && y < offs.y as usize + src.h
+                            && z >= offs.z as usize && z < offs.z as usize + src.d;
+                        if !inside {
+                            self.set(x as PInt, y as PInt, z as PInt, Voxel::default());
+                        }
+                    }
+                }
             }
-            return
         }
 
-        let n = self.nodes[tp.idx()];
-        if n.empty { return; }
-
-        if let Some(v) = n.voxel {
-            f(size, &top_left, v);
-            return;
-        }
+        for z in 0..src.d {
+            let dz = z + offs.z as usize;
+            if dz >= self.d { break; }
+            for y in 0..src.h {
+                let dy = y + offs.y as usize;
+                if dy >= self.h { break; }
+                for x in 0..src.w {
+                    let dx = x + offs.x as usize;
+                    if dx >= self.w { break; }
 
-        for z in 0..2 {
-            for y in 0..2 {
-                for x in 0..2 {
-                    self.draw_level(
-                        tp.lvl_offs(x, y, z),
-                        size >> 1,
-                        top_left.offs(
-                            (x as usize * (size >> 1)) as PInt,
-                            (y as usize * (size >> 1)) as PInt,
-                            (z as usize * (size >> 1)) as PInt),
-                        f);
+                    let sv = *src.at(Pos::new(x as PInt, y as PInt, z as PInt));
+                    let (dx, dy, dz) = (dx as PInt, dy as PInt, dz as PInt);
+                    match op {
+                        CsgOp::Union => {
+                            if sv.color != empty { self.set(dx, dy, dz, sv); }
+                        },
+                        CsgOp::Subtract => {
+                            if sv.color != empty {
+                                self.set(dx, dy, dz, Voxel::default());
+                            }
+                        },
+                        CsgOp::Intersect => {
+                            if sv.color == empty {
+                                self.set(dx, dy, dz, Voxel::default());
+                            }
+                        },
+                        CsgOp::Replace => {
+                            self.set(dx, dy, dz, sv);
+                        },
+                    }
                 }
             }
         }
     }
 
-    pub fn get_inv_y(&self, x: PInt, y: PInt, z: PInt) -> Voxel<C> {
-        self.get(x, (self.vol.size - 1) as u16 - y, z)
+    pub fn union(&mut self, src: &Vol<C>, offs: Pos) {
+        self.combine(src, offs, CsgOp::Union);
     }
 
-    pub fn get(&self, x: PInt, y: PInt, z: PInt) -> Voxel<C> {
-        *self.vol.at(Pos::new(x, y, z))
+    pub fn subtract(&mut self, src: &Vol<C>, offs: Pos) {
+        self.combine(src, offs, CsgOp::Subtract);
     }
 
-    pub fn set_inv_y(&mut self, x: PInt, y: PInt, z: PInt, v: Voxel<C>) {
-        self.vol.set(x, (self.vol.size - 1) as u16 - y, z, v);
+    pub fn intersect(&mut self, src: &Vol<C>, offs: Pos) {
+        self.combine(src, offs, CsgOp::Intersect);
     }
 
-    pub fn set(&mut self, x: PInt, y: PInt, z: PInt, v: Voxel<C>) {
-        self.vol.set(x, y, z, v);
+    /// Overwrites the region at `offs` with `src`, including its
+    /// empty voxels.
+    pub fn paste(&mut self, src: &Vol<C>, offs: Pos) {
+        self.combine(src, offs, CsgOp::Replace);
     }
 
-    pub fn fill(&mut self, x: PInt, y: PInt, z: PInt,
-                w: PInt, h: PInt, d: PInt, v: Voxel<C>)
-    {
-        self.vol.fill(x, y, z, w, h, d, v);
+    /// Copies the region of size `w`, `h` and `d` at `pos` into a new
+    /// volume. The region is clipped to this volume.
+    pub fn copy_region(&self, pos: Pos, w: usize, h: usize, d: usize) -> Vol<C> {
+        let w = w.min(self.w.saturating_sub(pos.x as usize));
+        let h = h.min(self.h.saturating_sub(pos.y as usize));
+        let d = d.min(self.d.saturating_sub(pos.z as usize));
+
+        let mut out = Vol::new_dims(w, h, d);
+        for z in 0..d {
+            for y in 0..h {
+                for x in 0..w {
+                    out.set(x as PInt, y as PInt, z as PInt,
+                            *self.at(pos.offs(x as PInt, y as PInt, z as PInt)));
+                }
+            }
+        }
+        out
     }
 
-    pub fn recompute(&mut self) -> OctNode<C> {
-        let n = self.compute_node(TreePos::new(), self.vol.size, Pos { x: 0, y: 0, z: 0 });
-        n
+    /// Returns the volume rotated by `quarter_turns` times 90° around
+    /// `axis`. One turn around Y maps (x, y, z) to (d - 1 - z, y, x),
+    /// around X to (x, d - 1 - z, y) and around Z to (h - 1 - y, x, z).
+    pub fn rotated(&self, axis: Axis, quarter_turns: usize) -> Vol<C> {
+        let mut out = self.clone();
+        for _ in 0..(quarter_turns % 4) {
+            out = out.rotated_once(axis);
+        }
+        out
     }
 
-    fn compute_node(&mut self, tp: TreePos, size: usize, top_left: Pos) -> OctNode<C> {
-        if size == 1 {
-            let v = self.vol.get(top_left);
-            let mut n = OctNode::default();
-            if v.color == C::default() {
-                n.empty = true;
-                n.pos = top_left;
-            } else {
-                n.voxel = Some(*v);
-                n.empty = false;
-                n.pos = top_left;
+    fn rotated_once(&self, axis: Axis) -> Vol<C> {
+        let (w, h, d) = (self.w, self.h, self.d);
+        let mut out =
+            match axis {
+                Axis::X => Vol::new_dims(w, d, h),
+                Axis::Y => Vol::new_dims(d, h, w),
+                Axis::Z => Vol::new_dims(h, w, d),
+            };
+
+        for z in 0..d {
+            for y in 0..h {
+                for x in 0..w {
+                    let (nx, ny, nz) =
+                        match axis {
+                            Axis::X => (x, d - 1 - z, y),
+                            Axis::Y => (d - 1 - z, y, x),
+                            Axis::Z => (h - 1 - y, x, z),
+                        };
+                    out.set(nx as PInt, ny as PInt, nz as PInt,
+                            *self.at(Pos::new(x as PInt, y as PInt, z as PInt)));
+                }
             }
-//            dbg!(nidx, size, top_left, n.voxel, n.empty);
-            return n;
         }
+        out
+    }
 
-        let mut faces : u8 = 0x0;
-        let mut color : C = C::default();
+    /// Returns the volume mirrored along `axis`.
+    pub fn mirrored(&self, axis: Axis) -> Vol<C> {
+        let mut out = Vol::new_dims(self.w, self.h, self.d);
+        for z in 0..self.d {
+            for y in 0..self.h {
+                for x in 0..self.w {
+                    let (nx, ny, nz) =
+                        match axis {
+                            Axis::X => (self.w - 1 - x, y, z),
+                            Axis::Y => (x, self.h - 1 - y, z),
+                            Axis::Z => (x, y, self.d - 1 - z),
+                        };
+                    out.set(nx as PInt, ny as PInt, nz as PInt,
+                            *self.at(Pos::new(x as PInt, y as PInt, z as PInt)));
+                }
+            }
+        }
+        out
+    }
 
-        let mut first       = true;
-        let mut equal_color = true;
-        let mut all_empty   = true;
-        for z in 0..2 {
-            for y in 0..2 {
-                for x in 0..2 {
-                    let n = self.compute_node(
-                        tp.lvl_offs(x, y, z),
-                        size >> 1,
-                        top_left.offs(
-                            (x as usize * (size >> 1)) as PInt,
-                            (y as usize * (size >> 1)) as PInt,
-                            (z as usize * (size >> 1)) as PInt));
+    /// Splits the solid voxels into connected components and returns
+    /// all but the largest one. The voxels are not removed.
+    pub fn islands(&self) -> std::vec::Vec<VoxelIsland<C>> {
+        let (w, h, d) = (self.w, self.h, self.d);
+        let all =
+            (0..(w * h * d)).map(|i|
+                Pos::new((i % w) as PInt, ((i / w) % h) as PInt, (i / (w * h)) as PInt));
 
-                    if !n.empty { all_empty = false; }
-                    if let Some(v) = n.voxel {
-                        if first { color = v.color; first = false; }
-                        else if color != v.color { equal_color = false; }
+        find_islands(
+            (w, h, d), all, None,
+            |p| *self.color_at(p) != C::default())
+        .iter()
+        .map(|voxels| VoxelIsland::from_positions(voxels, |p| *self.at(p)))
+        .collect()
+    }
 
-                        faces |= v.faces;
-                    } else {
-                        equal_color = false;
+    /// Checks if removing the voxel at `pos` detached parts of the volume.
+    /// Components that are bigger than `max_size` voxels are considered
+    /// to be still attached. See also `find_islands`.
+    pub fn islands_near(&self, pos: Pos, max_size: usize)
+        -> std::vec::Vec<VoxelIsland<C>>
+    {
+        find_islands(
+            (self.w, self.h, self.d),
+            neighbours6(pos, (self.w, self.h, self.d)),
+            Some(max_size),
+            |p| *self.color_at(p) != C::default())
+        .iter()
+        .map(|voxels| VoxelIsland::from_positions(voxels, |p| *self.at(p)))
+        .collect()
+    }
+}
+
+const MAGICA_VOX_VERSION : u32   = 150;
+const MAGICA_VOX_MAX_DIM : usize = 256;
+
+/// An RGBA palette indexed by the voxel color. Entry 0 is unused,
+/// as color 0 is empty.
+pub type VoxPalette = [[u8; 4]; 256];
+
+/// The first model of a MagicaVoxel `.vox` file, converted to `Vol` axes.
+/// MagicaVoxel's z axis points up, which is the inverted y axis here,
+/// and its y axis becomes z.
+#[derive(Debug, Clone)]
+pub struct MagicaVoxModel {
+    pub w:       usize,
+    pub h:       usize,
+    pub d:       usize,
+    pub voxels:  std::vec::Vec<(Pos, u8)>,
+    /// `None` if the file has no RGBA chunk and uses the default palette.
+    pub palette: Option<VoxPalette>,
+}
+
+fn vox_chunk_header(data: &[u8], offs: usize) -> Result<(&[u8], usize, usize), VolError> {
+    if data.len() < offs + 12 {
+        return Err(VolError::Truncated { expected: offs + 12, got: data.len() });
+    }
+    let content_len  = read_u32_le(&data[(offs + 4)..]) as usize;
+    let children_len = read_u32_le(&data[(offs + 8)..]) as usize;
+    let end = offs + 12 + content_len + children_len;
+    if data.len() < end {
+        return Err(VolError::Truncated { expected: end, got: data.len() });
+    }
+    Ok((&data[offs..(offs + 4)], content_len, children_len))
+}
+
+impl MagicaVoxModel {
+    /// Parses the SIZE, XYZI and RGBA chunks of the first model.
+    /// Other chunks, and further models, are skipped.
+    pub fn parse(data: &[u8]) -> Result<Self, VolError> {
+        if data.len() < 8 {
+            return Err(VolError::Truncated { expected: 8, got: data.len() });
+        }
+        if &data[0..4] != b"VOX " { return Err(VolError::NotMagicaVox); }
+
+        let (id, content_len, children_len) = vox_chunk_header(data, 8)?;
+        if id != b"MAIN" { return Err(VolError::MissingVoxChunk("MAIN")); }
+
+        let mut size    = None;
+        let mut xyzi    = None;
+        let mut palette = None;
+
+        let end      = 20 + content_len + children_len;
+        let mut offs = 20 + content_len;
+        while offs < end {
+            let (id, content_len, children_len) = vox_chunk_header(data, offs)?;
+            let content = &data[(offs + 12)..(offs + 12 + content_len)];
+
+            match id {
+                b"SIZE" if size.is_none() => {
+                    if content.len() < 12 {
+                        return Err(VolError::Truncated {
+                            expected: 12, got: content.len() });
+                    }
+                    size = Some((
+                        read_u32_le(&content[0..]) as usize,
+                        read_u32_le(&content[4..]) as usize,
+                        read_u32_le(&content[8..]) as usize));
+                },
+                b"XYZI" if xyzi.is_none() => {
+                    if content.len() < 4 {
+                        return Err(VolError::Truncated {
+                            expected: 4, got: content.len() });
+                    }
+                    let count = read_u32_le(content) as usize;
+                    if content.len() < 4 + count * 4 {
+                        return Err(VolError::Truncated {
+                            expected: 4 + count * 4, got: content.len() });
+                    }
+                    xyzi = Some(&content[4..(4 + count * 4)]);
+                },
+                b"RGBA" => {
+                    if content.len() < 256 * 4 {
+                        return Err(VolError::Truncated {
+                            expected: 256 * 4, got: content.len() });
                     }
+                    // Palette entry i holds the color of index i + 1:
+                    let mut p = [[0; 4]; 256];
+                    for i in 0..255 {
+                        let c = &content[(i * 4)..(i * 4 + 4)];
+                        p[i + 1] = [c[0], c[1], c[2], c[3]];
+                    }
+                    palette = Some(p);
+                },
+                _ => (),
+            }
+
+            offs += 12 + content_len + children_len;
+        }
+
+        let (sx, sy, sz) = size.ok_or(VolError::MissingVoxChunk("SIZE"))?;
+        let xyzi = xyzi.ok_or(VolError::MissingVoxChunk("XYZI"))?;
+        if sx > MAGICA_VOX_MAX_DIM || sy > MAGICA_VOX_MAX_DIM || sz > MAGICA_VOX_MAX_DIM {
+            return Err(VolError::SizeTooBig(sx.max(sy).max(sz)));
+        }
+
+        let mut voxels = vec![];
+        for v in xyzi.chunks(4) {
+            let (x, y, z, c) = (v[0] as usize, v[1] as usize, v[2] as usize, v[3]);
+            if c == 0 || x >= sx || y >= sy || z >= sz { continue; }
+            voxels.push((Pos::new(x as PInt, (sz - 1 - z) as PInt, y as PInt), c));
+        }
+
+        Ok(Self { w: sx, h: sz, d: sy, voxels, palette })
+    }
+
+    /// Writes the voxels into `vol`. Voxels outside of `vol` are skipped,
+    /// and if any part of the model did not fit `VolError::ModelClipped`
+    /// is returned after writing the rest.
+    pub fn write_into<C: VoxelColor>(&self, vol: &mut Vol<C>) -> Result<(), VolError> {
+        for (pos, c) in self.voxels.iter() {
+            if vol.contains(*pos) {
+                vol.set(pos.x, pos.y, pos.z, Voxel { color: C::from(*c), faces: 0 });
+            }
+        }
+
+        if self.w > vol.w || self.h > vol.h || self.d > vol.d {
+            return Err(VolError::ModelClipped {
+                model: (self.w, self.h, self.d),
+                vol:   (vol.w, vol.h, vol.d),
+            });
+        }
+        Ok(())
+    }
+
+    /// Creates a volume with the size of the model.
+    pub fn to_vol<C: VoxelColor>(&self) -> Vol<C> {
+        let mut vol = Vol::new_dims(self.w, self.h, self.d);
+        // Can't be clipped, the volume has the size of the model:
+        let _ = self.write_into(&mut vol);
+        vol
+    }
+}
+
+fn push_vox_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(content);
+}
+
+impl<C> Vol<C> where C: VoxelColor {
+    /// Writes the volume as MagicaVoxel `.vox` file. Without a palette
+    /// the file uses MagicaVoxel's default palette.
+    pub fn to_magica_vox(&self, palette: Option<&VoxPalette>) -> Result<Vec<u8>, VolError> {
+        let max_dim = self.w.max(self.h).max(self.d);
+        if max_dim > MAGICA_VOX_MAX_DIM {
+            return Err(VolError::SizeTooBig(max_dim));
+        }
+
+        let mut size = vec![];
+        size.extend_from_slice(&(self.w as u32).to_le_bytes());
+        size.extend_from_slice(&(self.d as u32).to_le_bytes());
+        size.extend_from_slice(&(self.h as u32).to_le_bytes());
+
+        let mut xyzi = vec![0, 0, 0, 0];
+        let mut count : u32 = 0;
+        for z in 0..self.d {
+            for y in 0..self.h {
+                for x in 0..self.w {
+                    let c : u8 =
+                        (*self.at(Pos::new(x as PInt, y as PInt, z as PInt))).color.into();
+                    if c == 0 { continue; }
+                    xyzi.extend_from_slice(
+                        &[x as u8, z as u8, (self.h - 1 - y) as u8, c]);
+                    count += 1;
                 }
             }
         }
+        xyzi[0..4].copy_from_slice(&count.to_le_bytes());
 
-        let mut n = OctNode::default();
-        n.pos      = top_left;
-        n.tree_pos = tp;
+        let mut children = vec![];
+        push_vox_chunk(&mut children, b"SIZE", &size);
+        push_vox_chunk(&mut children, b"XYZI", &xyzi);
+        if let Some(palette) = palette {
+            let mut rgba = vec![];
+            for i in 0..256 {
+                rgba.extend_from_slice(&palette[(i + 1) % 256]);
+            }
+            push_vox_chunk(&mut children, b"RGBA", &rgba);
+        }
 
-        if !all_empty && equal_color {
-            let mut v = Voxel::default();
-            v.color = color;
-            v.faces = faces;
+        let mut out = vec![];
+        out.extend_from_slice(b"VOX ");
+        out.extend_from_slice(&MAGICA_VOX_VERSION.to_le_bytes());
+        out.extend_from_slice(b"MAIN");
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&(children.len() as u32).to_le_bytes());
+        out.extend_from_slice(&children);
+        Ok(out)
+    }
+}
 
-            n.empty = false;
-            n.voxel = Some(v);
+const DELTA_VERSION    : u8    = 1;
+const DELTA_HEADER_LEN : usize = 20;
 
-        } else if !all_empty {
-            n.empty       = false;
-            n.voxel       = None;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxelEdit<C: VoxelColor> {
+    pub pos: Pos,
+    pub old: C,
+    pub new: C,
+}
+
+pub const STRUCTURE_MIN_CHUNK_SIZE : usize = 4;
+/// Chunks are octrees, so this stays below `OCTREE_MAX_SIZE`.
+pub const STRUCTURE_MAX_CHUNK_SIZE : usize = 64;
+/// Upper bound for each dimension of a structure, positions are `PInt`.
+pub const STRUCTURE_MAX_SIZE       : usize = 4096;
+
+/// The dimensions of a voxel structure and the size of the chunks
+/// it is meshed in, see `ChunkedVolume`. Structures can be larger than
+/// one octree can index, they are only ever meshed chunk by chunk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StructureLayout {
+    pub w:          usize,
+    pub h:          usize,
+    pub d:          usize,
+    pub chunk_size: usize,
+}
+
+impl Default for StructureLayout {
+    fn default() -> Self {
+        Self { w: 128, h: 128, d: 128, chunk_size: 16 }
+    }
+}
+
+impl StructureLayout {
+    pub fn new(w: usize, h: usize, d: usize, chunk_size: usize) -> Result<Self, VolError> {
+        if    chunk_size < STRUCTURE_MIN_CHUNK_SIZE
+           || chunk_size > STRUCTURE_MAX_CHUNK_SIZE {
+            return Err(VolError::BadChunkSize(chunk_size));
+        }
+        for s in [w, h, d].iter() {
+            if *s > STRUCTURE_MAX_SIZE { return Err(VolError::SizeTooBig(*s)); }
+        }
+        let voxels = w * h * d;
+        if voxels == 0 || voxels > VOL_MAX_VOXELS {
+            return Err(VolError::SizeTooBig(voxels));
+        }
+
+        Ok(Self { w, h, d, chunk_size })
+    }
+
+    pub fn dims(&self) -> (usize, usize, usize) { (self.w, self.h, self.d) }
+
+    /// The largest mining tool size, see `MiningTool::from_name`.
+    /// A tool then covers at most the chunks next to the targeted one.
+    pub fn max_tool_size(&self) -> usize { self.chunk_size }
+
+    /// Checks a mining tool `size` given by a script, negative sizes
+    /// are 0. `None` if the size is beyond `max_tool_size`.
+    pub fn tool_size(&self, size: i64) -> Option<u16> {
+        if size > self.max_tool_size() as i64 { return None; }
+        Some(size.max(0) as u16)
+    }
+
+    pub fn new_vol<C: VoxelColor>(&self) -> Vol<C> {
+        Vol::new_dims(self.w, self.h, self.d)
+    }
+
+    pub fn new_chunks<C: VoxelColor>(&self) -> ChunkedVolume<C> {
+        ChunkedVolume::new(self.w, self.h, self.d, self.chunk_size)
+    }
+}
+
+/// Records voxel changes in steps that can be undone and redone.
+/// Changes are recorded into an open step until `commit` is called.
+#[derive(Debug, Clone, Default)]
+pub struct EditJournal<C: VoxelColor> {
+    /// Edits from an earlier session, part of `delta` but not undoable.
+    base:    std::vec::Vec<VoxelEdit<C>>,
+    steps:   std::vec::Vec<std::vec::Vec<VoxelEdit<C>>>,
+    /// Number of steps that are applied, the ones after it can be redone.
+    applied: usize,
+    open:    std::vec::Vec<VoxelEdit<C>>,
+}
+
+impl<C> EditJournal<C> where C: VoxelColor {
+    pub fn new() -> Self {
+        Self { base: vec![], steps: vec![], applied: 0, open: vec![] }
+    }
+
+    /// A journal that starts with the restored edits of an earlier
+    /// session, given as position, generated and restored color.
+    pub fn with_base(base: &[(Pos, C, C)]) -> Self {
+        let base =
+            base.iter()
+                .filter(|(_, old, new)| old != new)
+                .map(|(pos, old, new)| VoxelEdit { pos: *pos, old: *old, new: *new })
+                .collect();
+        Self { base, steps: vec![], applied: 0, open: vec![] }
+    }
+
+    /// Records a change, dropping all steps that could be redone.
+    pub fn record(&mut self, pos: Pos, old: C, new: C) {
+        if old == new { return; }
+        self.steps.truncate(self.applied);
+        self.open.push(VoxelEdit { pos, old, new });
+    }
+
+    /// Closes the open step.
+    pub fn commit(&mut self) {
+        if self.open.is_empty() { return; }
+        let step = std::mem::replace(&mut self.open, vec![]);
+        self.steps.push(step);
+        self.applied = self.steps.len();
+    }
+
+    pub fn can_undo(&self) -> bool { self.applied > 0 || !self.open.is_empty() }
+    pub fn can_redo(&self) -> bool { self.applied < self.steps.len() }
+
+    /// Steps back and returns the voxels to write to revert the last step.
+    pub fn undo(&mut self) -> Option<std::vec::Vec<(Pos, C)>> {
+        self.commit();
+        if self.applied == 0 { return None; }
+        self.applied -= 1;
+        Some(self.steps[self.applied].iter().rev().map(|e| (e.pos, e.old)).collect())
+    }
+
+    /// Returns the voxels to write to repeat the last undone step.
+    pub fn redo(&mut self) -> Option<std::vec::Vec<(Pos, C)>> {
+        self.commit();
+        if self.applied >= self.steps.len() { return None; }
+        self.applied += 1;
+        Some(self.steps[self.applied - 1].iter().map(|e| (e.pos, e.new)).collect())
+    }
+
+    /// The net changes of all applied steps against the volume the
+    /// journal started with, ordered like the voxels of a `Vol`.
+    pub fn delta(&self) -> std::vec::Vec<(Pos, C)> {
+        let mut changes = std::collections::BTreeMap::new();
+        let steps = self.steps[0..self.applied].iter().flatten();
+        for e in self.base.iter().chain(steps).chain(self.open.iter()) {
+            changes.entry((e.pos.z, e.pos.y, e.pos.x))
+                   .or_insert((e.pos, e.old, e.new))
+                   .2 = e.new;
+        }
+
+        changes.values()
+               .filter(|(_, old, new)| old != new)
+               .map(|(pos, _, new)| (*pos, *new))
+               .collect()
+    }
+
+    /// Serializes `delta()` for a volume with the dimensions `dims`.
+    /// Each entry holds the distance in voxels to the previous entry
+    /// as LEB128 integer and the new color.
+    pub fn serialize_delta(&self, dims: (usize, usize, usize)) -> Vec<u8> {
+        let delta = self.delta();
+
+        let mut out = vec![];
+        out.extend_from_slice(b"vdl");
+        out.push(DELTA_VERSION);
+        out.extend_from_slice(&(dims.0 as u32).to_le_bytes());
+        out.extend_from_slice(&(dims.1 as u32).to_le_bytes());
+        out.extend_from_slice(&(dims.2 as u32).to_le_bytes());
+        out.extend_from_slice(&(delta.len() as u32).to_le_bytes());
+
+        let mut next = 0;
+        for (pos, c) in delta {
+            let idx =
+                  pos.z as usize * dims.0 * dims.1
+                + pos.y as usize * dims.0
+                + pos.x as usize;
+            rle_push_run(&mut out, idx - next, c.into());
+            next = idx + 1;
+        }
+
+        let crc = crc32(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Reads a delta written by `serialize_delta` for a volume with the
+    /// dimensions `dims`. The result can be written with `apply_edits`.
+    pub fn deserialize_delta(data: &[u8], dims: (usize, usize, usize))
+        -> Result<std::vec::Vec<(Pos, C)>, VolError>
+    {
+        if data.len() < DELTA_HEADER_LEN + 4 {
+            return Err(VolError::Truncated {
+                expected: DELTA_HEADER_LEN + 4, got: data.len() });
+        }
+        if &data[0..3] != b"vdl" { return Err(VolError::NotADelta); }
+        if data[3] != DELTA_VERSION {
+            return Err(VolError::UnsupportedVersion(data[3]));
+        }
+
+        let got = (
+            read_u32_le(&data[4..])  as usize,
+            read_u32_le(&data[8..])  as usize,
+            read_u32_le(&data[12..]) as usize);
+        if got != dims {
+            return Err(VolError::DeltaSizeMismatch { expected: dims, got });
+        }
+        let count = read_u32_le(&data[16..]) as usize;
+
+        let crc_offs = data.len() - 4;
+        let expected = read_u32_le(&data[crc_offs..]);
+        let got      = crc32(&data[0..crc_offs]);
+        if expected != got {
+            return Err(VolError::ChecksumMismatch { expected, got });
+        }
+
+        let payload    = &data[DELTA_HEADER_LEN..crc_offs];
+        let voxels     = dims.0 * dims.1 * dims.2;
+        let mut out    = vec![];
+        let mut next   = 0;
+        let mut i      = 0;
+        while i < payload.len() {
+            let entry_start = i;
+            let bad = VolError::BadDeltaEntry { offset: entry_start };
+            let gap = read_varint(payload, &mut i).ok_or(bad.clone())?;
+            if i >= payload.len() || next + gap >= voxels { return Err(bad); }
+
+            let idx = next + gap;
+            let pos = Pos::new(
+                (idx % dims.0) as PInt,
+                ((idx / dims.0) % dims.1) as PInt,
+                (idx / (dims.0 * dims.1)) as PInt);
+            out.push((pos, C::from(payload[i])));
+            i += 1;
+            next = idx + 1;
+        }
+
+        if out.len() != count {
+            return Err(VolError::VoxelCountMismatch { expected: count, got: out.len() });
+        }
+        Ok(out)
+    }
+}
+
+/// The serialized `EditJournal` deltas of all structures the player
+/// changed, keyed by system and entity id, for the savegame.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VoxelEditStore {
+    deltas: std::collections::BTreeMap<(i64, i64), std::vec::Vec<u8>>,
+}
+
+impl VoxelEditStore {
+    pub fn new() -> Self {
+        Self { deltas: std::collections::BTreeMap::new() }
+    }
+
+    pub fn len(&self) -> usize { self.deltas.len() }
+    pub fn is_empty(&self) -> bool { self.deltas.is_empty() }
 
+    /// Stores the net changes of `journal`, forgetting the structure
+    /// if there are none.
+    pub fn store<C: VoxelColor>(&mut self, key: (i64, i64),
+                                journal: &EditJournal<C>,
+                                dims: (usize, usize, usize))
+    {
+        if journal.delta().is_empty() {
+            self.deltas.remove(&key);
         } else {
-            n.empty       = true;
-            n.voxel       = None;
+            self.deltas.insert(key, journal.serialize_delta(dims));
         }
+    }
 
-//        dbg!(tp, size, top_left, n.voxel, n.empty);
-        if size > 1 {
-            self.nodes[tp.idx()] = n;
+    /// The stored changes of a structure, empty if there are none.
+    pub fn restore<C: VoxelColor>(&self, key: (i64, i64), dims: (usize, usize, usize))
+        -> Result<std::vec::Vec<(Pos, C)>, VolError>
+    {
+        match self.deltas.get(&key) {
+            Some(data) => EditJournal::deserialize_delta(data, dims),
+            None       => Ok(vec![]),
         }
+    }
 
-        n
+    /// The deltas as system id, entity id and base64 data.
+    pub fn to_entries(&self) -> std::vec::Vec<(i64, i64, String)> {
+        self.deltas.iter()
+            .map(|((sys, ent), data)| (*sys, *ent, base64_encode(data)))
+            .collect()
     }
 
-    fn node_at(&self, offs: usize,  x: usize, y: usize, z: usize) -> &OctNode<C> {
-        &self.nodes[offs + (z * 2 * 2) + (y * 2) + x]
+    /// Reads the entries written by `to_entries`. The deltas are only
+    /// checked by `restore`, as the volume dimensions are needed for that.
+    pub fn from_entries(entries: &[(i64, i64, String)]) -> Result<Self, String> {
+        let mut store = Self::new();
+        for (sys, ent, data) in entries.iter() {
+            let data =
+                base64_decode(data).ok_or_else(|| {
+                    format!("bad voxel edits of entity {} in system {}", ent, sys)
+                })?;
+            store.deltas.insert((*sys, *ent), data);
+        }
+        Ok(store)
     }
+}
 
-    fn node(&mut self, offs: usize, x: usize, y: usize, z: usize) -> &mut OctNode<C> {
-        &mut self.nodes[offs + (z * 2 * 2) + (y * 2) + x]
-//        println!("ANOD lvl={}, pos={:?} => {:?}", level, pos, self.nodes);
+fn v3_sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn v3_dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Returns the parameter `t` of the point on the line through `a` and `b`
+/// closest to `p`, and the squared distance to that point.
+/// With `clamp` set, the closest point is limited to the segment.
+fn segment_closest(p: [f32; 3], a: [f32; 3], b: [f32; 3], clamp: bool) -> (f32, f32) {
+    let ab  = v3_sub(b, a);
+    let len = v3_dot(ab, ab);
+    let mut t = if len > 0.0 { v3_dot(v3_sub(p, a), ab) / len } else { 0.0 };
+    if clamp { t = t.max(0.0).min(1.0); }
+
+    let q = [a[0] + ab[0] * t, a[1] + ab[1] * t, a[2] + ab[2] * t];
+    let d = v3_sub(p, q);
+    (t, v3_dot(d, d))
+}
+
+/// Returns true if the segment from `a` to `b` touches the axis aligned
+/// box from `min` to `max`.
+fn segment_hits_box(a: [f32; 3], b: [f32; 3], min: [f32; 3], max: [f32; 3]) -> bool {
+    let mut t0 : f32 = 0.0;
+    let mut t1 : f32 = 1.0;
+    for i in 0..3 {
+        let d = b[i] - a[i];
+        if d.abs() < std::f32::EPSILON {
+            if a[i] < min[i] || a[i] > max[i] { return false; }
+            continue;
+        }
+        let ta = (min[i] - a[i]) / d;
+        let tb = (max[i] - a[i]) / d;
+        t0 = t0.max(ta.min(tb));
+        t1 = t1.min(ta.max(tb));
+        if t0 > t1 { return false; }
     }
+    true
+}
 
-//    fn compute_voxel_node(&mut self, top_left: Pos) -> OctNode<C> {
-//        let mut faces : u8 = 0x0;
-//        let mut color : C  = C::default();
-//
-//        let size = self.vol.size;
-//
-//        let mut first       = true;
-//        let mut equal_color = true;
-//        let mut all_empty   = true;
-//
-//        for z in 0..2 {
-//            for y in 0..2 {
-//                for x in 0..2 {
-//                    let vox = self.vol.get(top_left.offs(x, y, z));
-//
-//                    if first { color = vox.color; first = false; }
-//                    else if color != vox.color { equal_color = false; }
-//                    if color != C::default() { all_empty = false; }
-//
-//                    faces |= xyz2facemask(x, y, z) & vox.faces;
-//                    eprintln!("{},{},{} :: {:?} :: {:x} {:x}",
-//                              x, y, z, top_left, vox.faces, faces);
-//                }
-//            }
-//        }
-//
-//        let mut n = OctNode::default();
-//        if !all_empty && equal_color {
-//            let mut v = Voxel::default();
-//            v.color = color;
-//            v.faces = faces;
-//
-//            n.empty = false;
-//            n.voxel = Some(v);
-//
-//        } else if !all_empty {
-//            n.empty       = false;
-//            n.voxel       = None;
-//
-//        } else {
-//            n.empty       = true;
-//            n.voxel       = None;
-//        }
-//
-//        n
-//    }
+/// A solid primitive that can be rasterized into a `Vol` with
+/// `Vol::rasterize`. All coordinates are in voxel units, the center of
+/// the voxel at (x, y, z) is at (x + 0.5, y + 0.5, z + 0.5).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Sphere      { center: [f32; 3], radius: f32 },
+    Ellipsoid   { center: [f32; 3], radii: [f32; 3] },
+    /// A cylinder with flat caps around the axis from `a` to `b`.
+    Cylinder    { a: [f32; 3], b: [f32; 3], radius: f32 },
+    /// A cylinder with round caps around the axis from `a` to `b`.
+    Capsule     { a: [f32; 3], b: [f32; 3], radius: f32 },
+    /// A cone with its base disc at `base` and its tip at `apex`.
+    Cone        { base: [f32; 3], apex: [f32; 3], radius: f32 },
+    /// A line from `a` to `b`. Every voxel the line passes through is
+    /// hit, so a thickness of 1.0 or less gives a gap free thin line.
+    Line        { a: [f32; 3], b: [f32; 3], thickness: f32 },
+    /// The intersection of half spaces `dot(normal, p) <= dist`.
+    Polyhedron  { planes: std::vec::Vec<([f32; 3], f32)> },
+}
+
+impl Shape {
+    pub fn contains(&self, p: [f32; 3]) -> bool {
+        match self {
+            Shape::Sphere { center, radius } => {
+                let d = v3_sub(p, *center);
+                v3_dot(d, d) <= radius * radius
+            },
+            Shape::Ellipsoid { center, radii } => {
+                let mut sum = 0.0;
+                for i in 0..3 {
+                    if radii[i] <= 0.0 { return false; }
+                    let d = (p[i] - center[i]) / radii[i];
+                    sum += d * d;
+                }
+                sum <= 1.0
+            },
+            Shape::Cylinder { a, b, radius } => {
+                let (t, dist_sq) = segment_closest(p, *a, *b, false);
+                t >= 0.0 && t <= 1.0 && dist_sq <= radius * radius
+            },
+            Shape::Capsule { a, b, radius } => {
+                let (_, dist_sq) = segment_closest(p, *a, *b, true);
+                dist_sq <= radius * radius
+            },
+            Shape::Cone { base, apex, radius } => {
+                let (t, dist_sq) = segment_closest(p, *base, *apex, false);
+                let r = radius * (1.0 - t);
+                t >= 0.0 && t <= 1.0 && dist_sq <= r * r
+            },
+            Shape::Line { a, b, thickness } => {
+                let r = thickness * 0.5;
+                let (_, dist_sq) = segment_closest(p, *a, *b, true);
+                dist_sq <= r * r
+                || segment_hits_box(
+                    *a, *b,
+                    [p[0] - 0.5, p[1] - 0.5, p[2] - 0.5],
+                    [p[0] + 0.5, p[1] + 0.5, p[2] + 0.5])
+            },
+            Shape::Polyhedron { planes } => {
+                planes.iter().all(|(n, d)| v3_dot(*n, p) <= *d)
+            },
+        }
+    }
+
+    /// The axis aligned bounds of the shape, or `None` if it is not
+    /// known and the whole volume has to be scanned.
+    pub fn bounds(&self) -> Option<([f32; 3], [f32; 3])> {
+        let around = |c: [f32; 3], r: [f32; 3]| {
+            Some(([c[0] - r[0], c[1] - r[1], c[2] - r[2]],
+                  [c[0] + r[0], c[1] + r[1], c[2] + r[2]]))
+        };
+        let segment = |a: [f32; 3], b: [f32; 3], r: f32| {
+            Some(([a[0].min(b[0]) - r, a[1].min(b[1]) - r, a[2].min(b[2]) - r],
+                  [a[0].max(b[0]) + r, a[1].max(b[1]) + r, a[2].max(b[2]) + r]))
+        };
+
+        match self {
+            Shape::Sphere { center, radius } =>
+                around(*center, [*radius, *radius, *radius]),
+            Shape::Ellipsoid { center, radii } =>
+                around(*center, *radii),
+            Shape::Cylinder { a, b, radius }
+            | Shape::Capsule { a, b, radius } =>
+                segment(*a, *b, *radius),
+            Shape::Cone { base, apex, radius } =>
+                segment(*base, *apex, *radius),
+            Shape::Line { a, b, thickness } =>
+                segment(*a, *b, (thickness * 0.5).max(1.0)),
+            Shape::Polyhedron { .. } => None,
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// How `Vol::combine` merges the source into the destination volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsgOp {
+    /// Solid source voxels overwrite the destination.
+    Union,
+    /// Solid source voxels clear the destination.
+    Subtract,
+    /// Only destination voxels where the source is solid are kept.
+    Intersect,
+    /// All source voxels, also empty ones, overwrite the destination.
+    Replace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+/// A group of solid voxels that is not connected to the rest of a volume.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelIsland<C: VoxelColor> {
+    /// Position of the island's `vol` in the volume it came from.
+    pub offset:      Pos,
+    pub vol:         Vol<C>,
+    pub voxel_count: usize,
+}
+
+impl<C> VoxelIsland<C> where C: VoxelColor {
+    /// Builds a volume that just fits the voxels at `positions`.
+    pub fn from_positions<F>(positions: &[Pos], voxel_at: F) -> Self
+        where F: Fn(Pos) -> Voxel<C>
+    {
+        let mut min = Pos::new(PInt::max_value(), PInt::max_value(), PInt::max_value());
+        let mut max = Pos::new(0, 0, 0);
+        for p in positions.iter() {
+            min = Pos::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+            max = Pos::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+        }
+        if positions.is_empty() { min = max; }
+
+        let mut vol = Vol::new_dims(
+            (max.x - min.x + 1) as usize,
+            (max.y - min.y + 1) as usize,
+            (max.z - min.z + 1) as usize);
+        for p in positions.iter() {
+            vol.set(p.x - min.x, p.y - min.y, p.z - min.z, voxel_at(*p));
+        }
+
+        Self { offset: min, vol, voxel_count: positions.len() }
+    }
+}
+
+/// Returns the 6 direct neighbours of `pos` inside of `dims`.
+pub fn neighbours6(pos: Pos, dims: (usize, usize, usize)) -> std::vec::Vec<Pos> {
+    let mut n = vec![];
+    if pos.x > 0 { n.push(Pos::new(pos.x - 1, pos.y, pos.z)); }
+    if pos.y > 0 { n.push(Pos::new(pos.x, pos.y - 1, pos.z)); }
+    if pos.z > 0 { n.push(Pos::new(pos.x, pos.y, pos.z - 1)); }
+    if (pos.x as usize) + 1 < dims.0 { n.push(Pos::new(pos.x + 1, pos.y, pos.z)); }
+    if (pos.y as usize) + 1 < dims.1 { n.push(Pos::new(pos.x, pos.y + 1, pos.z)); }
+    if (pos.z as usize) + 1 < dims.2 { n.push(Pos::new(pos.x, pos.y, pos.z + 1)); }
+    n
+}
+
+/// Flood fills the solid voxels over 6-neighbourhoods, starting at each
+/// of the `seeds`, and returns the positions of the found components
+/// that are detached from the main body.
+///
+/// Without `max_size` every component is filled and the largest one is
+/// considered the main body. With `max_size` a fill stops as soon as it
+/// exceeds that many voxels or reaches the voxels of such a fill, that
+/// component is then considered the main body. If no fill exceeds it,
+/// the largest component is the main body again. The latter keeps
+/// checks after small edits cheap.
+pub fn find_islands<I, F>(dims: (usize, usize, usize), seeds: I,
+                          max_size: Option<usize>, is_solid: F)
+    -> std::vec::Vec<std::vec::Vec<Pos>>
+    where I: IntoIterator<Item=Pos>,
+          F: Fn(Pos) -> bool
+{
+    let (w, h, _d) = dims;
+    let pos2idx = |p: Pos| p.z as usize * w * h + p.y as usize * w + p.x as usize;
+
+    // Sparse marks for the bounded fills, which only touch a few voxels.
+    // A mark is true if the voxel belongs to a fill that got too big:
+    let mut visited_dense  = vec![];
+    let mut visited_sparse = std::collections::HashMap::new();
+    if max_size.is_none() {
+        visited_dense.resize(dims.0 * dims.1 * dims.2, false);
+    }
+
+    let mut components = vec![];
+    let mut main_body_found = false;
+
+    for seed in seeds {
+        if !is_solid(seed) { continue; }
+
+        let mut component = vec![seed];
+        let mut todo      = vec![seed];
+        let mut too_big   = false;
+
+        // Returns whether `p` is new, and whether it belongs to the
+        // main body found by an earlier fill:
+        let mut mark = |p: Pos| {
+            let i = pos2idx(p);
+            if max_size.is_none() {
+                let new = !visited_dense[i];
+                visited_dense[i] = true;
+                return (new, false);
+            }
+            match visited_sparse.get(&i) {
+                Some(main_body) => (false, *main_body),
+                None => { visited_sparse.insert(i, false); (true, false) },
+            }
+        };
+
+        if !mark(seed).0 { continue; }
+
+        while let Some(p) = todo.pop() {
+            for n in neighbours6(p, dims) {
+                if !is_solid(n) { continue; }
+                let (new, main_body) = mark(n);
+                // A fill that stopped early leaves unmarked voxels, so
+                // this fill may be the same component coming from
+                // elsewhere:
+                if main_body { too_big = true; }
+                if !new { continue; }
+                component.push(n);
+                todo.push(n);
+            }
+
+            if let Some(max) = max_size {
+                if component.len() > max { too_big = true; }
+            }
+            if too_big { break; }
+        }
+
+        if too_big {
+            for p in component.iter() {
+                visited_sparse.insert(pos2idx(*p), true);
+            }
+            main_body_found = true;
+        } else {
+            components.push(component);
+        }
+    }
+
+    if !main_body_found && !components.is_empty() {
+        let mut largest = 0;
+        for (i, c) in components.iter().enumerate() {
+            if c.len() > components[largest].len() { largest = i; }
+        }
+        components.remove(largest);
+    }
+
+    components
+}
+
+/// The voxels a mining action removes around the targeted voxel.
+/// Positions use the `_inv_y` coordinates, as the cursor does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MiningTool {
+    /// Just the targeted voxel.
+    Single,
+    /// All voxels at most `radius` away from the targeted one.
+    Sphere { radius: u16 },
+    /// A column of `depth` voxels, starting at the targeted one
+    /// and going in the direction of `dir`.
+    Drill { depth: u16, dir: Face },
+    /// A square of `2 * radius + 1` voxels around the targeted
+    /// one, perpendicular to `normal`.
+    Plane { radius: u16, normal: Face },
+}
+
+impl Default for MiningTool {
+    fn default() -> Self { MiningTool::Single }
+}
+
+impl MiningTool {
+    /// The tool "single", "sphere", "drill" or "plane" of the given
+    /// `size`, for a hit on the voxel face `hit`. Drills go into the
+    /// voxel opposite to the face, planes are parallel to it.
+    pub fn from_name(name: &str, size: u16, hit: Face) -> Option<Self> {
+        match name {
+            "single" => Some(MiningTool::Single),
+            "sphere" => Some(MiningTool::Sphere { radius: size }),
+            "drill"  => Some(MiningTool::Drill  { depth: size.max(1), dir: hit.opposite() }),
+            "plane"  => Some(MiningTool::Plane  { radius: size, normal: hit }),
+            _        => None,
+        }
+    }
+
+    /// The positions the tool covers around `center`, clipped to a
+    /// volume of the dimensions `dims`.
+    pub fn positions(&self, center: Pos, dims: (usize, usize, usize)) -> std::vec::Vec<Pos> {
+        let c = [center.x as i32, center.y as i32, center.z as i32];
+        let mut offsets = vec![];
+        match *self {
+            MiningTool::Single => offsets.push([0, 0, 0]),
+            MiningTool::Sphere { radius } => {
+                let r = radius as i32;
+                for z in -r..=r {
+                    for y in -r..=r {
+                        for x in -r..=r {
+                            if x * x + y * y + z * z <= r * r {
+                                offsets.push([x, y, z]);
+                            }
+                        }
+                    }
+                }
+            },
+            MiningTool::Drill { depth, dir } => {
+                let d = dir.offset();
+                for i in 0..(depth as i32) {
+                    offsets.push([d[0] * i, d[1] * i, d[2] * i]);
+                }
+            },
+            MiningTool::Plane { radius, normal } => {
+                let r = radius as i32;
+                let n = normal.offset();
+                for a in -r..=r {
+                    for b in -r..=r {
+                        offsets.push(
+                            if n[0] != 0      { [0, a, b] }
+                            else if n[1] != 0 { [a, 0, b] }
+                            else              { [a, b, 0] });
+                    }
+                }
+            },
+        }
+
+        offsets.iter()
+            .map(|o| [c[0] + o[0], c[1] + o[1], c[2] + o[2]])
+            .filter(|p| {
+                   p[0] >= 0 && (p[0] as usize) < dims.0
+                && p[1] >= 0 && (p[1] as usize) < dims.1
+                && p[2] >= 0 && (p[2] as usize) < dims.2
+            })
+            .map(|p| Pos::new(p[0] as PInt, p[1] as PInt, p[2] as PInt))
+            .collect()
+    }
+}
+
+/// The number of voxels of each material removed by a mining action.
+#[derive(Clone, Copy)]
+pub struct MiningYield {
+    pub counts: [u32; 256],
+}
+
+impl MiningYield {
+    pub fn new() -> Self {
+        Self { counts: [0; 256] }
+    }
+
+    pub fn add<C: VoxelColor>(&mut self, c: C) {
+        self.counts[c.into() as usize] += 1;
+    }
+
+    pub fn total(&self) -> u32 {
+        self.counts.iter().sum()
+    }
+
+    /// The materials with a count above zero.
+    pub fn materials(&self) -> std::vec::Vec<(u8, u32)> {
+        self.counts.iter().enumerate()
+            .filter(|(_, cnt)| **cnt > 0)
+            .map(|(c, cnt)| (c as u8, *cnt))
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct Pos {
+    pub x: PInt,
+    pub y: PInt,
+    pub z: PInt,
+}
+
+impl std::fmt::Debug for Pos {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+//        use std::fmt;
+        write!(f, "({}, {}, {})", self.x, self.y, self.z)
+    }
+}
+
+
+impl Pos {
+    pub fn new(x: PInt, y: PInt, z: PInt) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn offs(&self, xo: PInt, yo: PInt, zo: PInt) -> Self {
+        Self { x: self.x + xo, y: self.y + yo, z: self.z + zo }
+    }
+
+    pub fn mul(&self, m: PInt) -> Self {
+        Self { x: self.x * m, y: self.y * m, z: self.z * m }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct TreePos {
+    pub offs:  usize,
+    pub level: usize,
+    pub x: u8,
+    pub y: u8,
+    pub z: u8,
+}
+
+impl std::fmt::Debug for TreePos {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+//        use std::fmt;
+        write!(f, "[lvl={},offs={}]+({}, {}, {})=>{}",
+                  self.level, self.offs,
+                  self.x, self.y, self.z, self.idx())
+    }
+}
+
+impl TreePos {
+    pub fn new() -> Self {
+        Self { offs: 0, level: 0, x: 0, y: 0, z: 0 }
+    }
+
+    pub fn lvl_offs(&self, xo: u8, yo: u8, zo: u8) -> Self {
+        let add_offs =
+            if self.level == 0 { 1 }
+            else { 8_usize.pow(self.level as u32) };
+        Self {
+            offs:  self.offs + add_offs,
+            level: self.level + 1,
+            x: (self.x << 1) + xo,
+            y: (self.y << 1) + yo,
+            z: (self.z << 1) + zo,
+        }
+    }
+
+    /// Returns the tree position of the node at `level` whose
+    /// node coordinates are `x`, `y` and `z`.
+    pub fn at_level(level: usize, x: u8, y: u8, z: u8) -> Self {
+        let mut offs = 0;
+        for l in 0..level {
+            offs += 8_usize.pow(l as u32);
+        }
+        Self { offs, level, x, y, z }
+    }
+
+    pub fn idx(&self) -> usize {
+        if self.level == 0 { return 0; }
+        let edge_size = 2_usize.pow(self.level as u32);
+        self.offs
+        + self.z as usize * edge_size * edge_size
+        + self.y as usize * edge_size
+        + self.x as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct OctNode<C: VoxelColor> {
+    pub voxel: Option<Voxel<C>>,
+    pub pos:   Pos,
+    pub empty: bool,
+    pub tree_pos: TreePos,
+}
+
+impl<C> OctNode<C> where C: VoxelColor {
+    pub fn new() -> Self {
+        Self {
+            voxel:  None,
+            pos:    Pos::default(),
+            empty:  true,
+            tree_pos: TreePos::default(),
+        }
+    }
+}
+
+/// Result of `Octree::raycast`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit<C: VoxelColor> {
+    /// Position of the first solid voxel along the ray.
+    pub pos:   Pos,
+    pub voxel: Voxel<C>,
+    /// The face (one of the `F_*` bits) through which the ray entered
+    /// the voxel, or 0 if the ray started inside of it.
+    pub face:  u8,
+    /// Distance from the ray origin to the entry point.
+    pub dist:  f32,
+}
+
+/// The largest edge length of an `Octree`, the `TreePos` coordinates
+/// are `u8`.
+pub const OCTREE_MAX_SIZE : usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Octree<C: VoxelColor> {
+    nodes: std::vec::Vec<OctNode<C>>,
+    nodes_size: usize,
+    /// Edge length of the tree. That is the largest volume dimension
+    /// rounded up to the next power of two. Voxels outside of the
+    /// volume dimensions are treated as empty padding.
+    size: usize,
+    /// Inclusive (min, max) boxes of voxels written by `set`,
+    /// `set_inv_y` and `fill` since the last recompute.
+    dirty: std::vec::Vec<(Pos, Pos)>,
+    computed: bool,
+    pub vol: Vol<C>,
+}
+
+impl<C> Octree<C> where C: VoxelColor {
+    pub fn new_from_size(size: usize) -> Self {
+        let v : Vol<C> = Vol::new(size);
+        Octree::new(size * size * size, v)
+    }
+
+    pub fn new_from_dims(w: usize, h: usize, d: usize) -> Self {
+        let v : Vol<C> = Vol::new_dims(w, h, d);
+        Octree::new(w * h * d, v)
+    }
+
+    pub fn new(_node_count: usize, vol: Vol<C>) -> Self {
+        let tree_size = vol.w.max(vol.h).max(vol.d).next_power_of_two();
+        assert!(tree_size <= OCTREE_MAX_SIZE,
+                "Volume of {}x{}x{} is too big for an octree", vol.w, vol.h, vol.d);
+        let mut size = tree_size >> 1;
+        let mut alloc = 1;
+        let mut subtree_size : usize = 2;
+        while size > 1 {
+            alloc += subtree_size.pow(3);
+            subtree_size *= 2;
+//            println!("SIZ {} {}", size, alloc);
+            size = size >> 1;
+        }
+        alloc += 1; // For the root node
+        let mut nodes = std::vec::Vec::new();
+        nodes.resize(alloc, OctNode::default());
+        Self {
+            nodes,
+            nodes_size: tree_size >> 1,
+            size: tree_size,
+            dirty: vec![],
+            computed: false,
+            vol,
+        }
+    }
+
+    /// The root node as of the last `recompute` or `update_dirty`.
+    pub fn root(&self) -> OctNode<C> {
+        self.nodes[0]
+    }
+
+    /// Returns true if voxels were written since the last
+    /// `recompute` or `update_dirty`.
+    pub fn is_dirty(&self) -> bool { !self.dirty.is_empty() }
+
+    /// Marks the inclusive box from `min` to `max` as modified.
+    /// Writes that go directly to `vol` need to be reported with this,
+    /// otherwise `update_dirty` won't see them.
+    pub fn mark_dirty(&mut self, min: Pos, max: Pos) {
+        self.dirty.push((min, max));
+    }
+
+    pub fn draw<F>(&self, f: &mut F) where F: FnMut(usize, &Pos, Voxel<C>) -> () {
+        self.draw_level(TreePos::new(), self.size, Pos { x: 0, y: 0, z: 0 }, f);
+    }
+
+    pub fn draw_level<F>(&self, tp: TreePos, size: usize, top_left: Pos, f: &mut F)
+        where F: FnMut(usize, &Pos, Voxel<C>) -> ()
+    {
+        if size == 1 {
+            if !self.vol.contains(top_left) { return; }
+            let v = self.vol.at(top_left);
+            if v.color != C::default() {
+                f(1, &top_left, *v);
+            }
+            return
+        }
+
+        let n = self.nodes[tp.idx()];
+        if n.empty { return; }
+
+        if let Some(v) = n.voxel {
+            f(size, &top_left, v);
+            return;
+        }
+
+        for z in 0..2 {
+            for y in 0..2 {
+                for x in 0..2 {
+                    self.draw_level(
+                        tp.lvl_offs(x, y, z),
+                        size >> 1,
+                        top_left.offs(
+                            (x as usize * (size >> 1)) as PInt,
+                            (y as usize * (size >> 1)) as PInt,
+                            (z as usize * (size >> 1)) as PInt),
+                        f);
+                }
+            }
+        }
+    }
+
+    /// Iterates over the same non-empty nodes that `draw` visits,
+    /// as tuples of node size, top left position and voxel.
+    pub fn leaves(&self) -> OctreeLeafIter<'_, C> {
+        OctreeLeafIter {
+            tree:  self,
+            stack: vec![(TreePos::new(), self.size, Pos::default())],
+        }
+    }
+
+    /// Counts the voxels of each color. Index 0 counts the voxels
+    /// with the default color, including the empty volume.
+    pub fn color_histogram(&self) -> std::vec::Vec<usize> {
+        let mut hist = vec![0; 256];
+        let mut solid = 0;
+        for (size, _pos, v) in self.leaves() {
+            let c : u8 = v.color.into();
+            hist[c as usize] += size * size * size;
+            solid += size * size * size;
+        }
+        hist[0] += self.vol.data.len() - solid;
+        hist
+    }
+
+    /// The ambient occlusion levels of the given corners of a face of
+    /// the box at `pos` with the extents `size`, see `vertex_ao`.
+    /// Cells outside of the volume are treated as empty.
+    pub fn face_ao(&self, pos: Pos, size: [usize; 3], face: u8, corners: &[[u8; 3]; 4]) -> [u8; 4] {
+        let empty = C::default();
+        let is_solid = |x: i32, y: i32, z: i32| {
+            if x < 0 || y < 0 || z < 0 { return false; }
+            let p = Pos::new(x as PInt, y as PInt, z as PInt);
+            self.vol.contains(p) && self.vol.at(p).color != empty
+        };
+
+        let mut ao = [3; 4];
+        for (i, c) in corners.iter().enumerate() {
+            ao[i] = vertex_ao(pos, size, face, *c, &is_solid);
+        }
+        ao
+    }
+
+    /// One box for each solid uniform node, clipped to the volume.
+    pub fn node_boxes(&self) -> std::vec::Vec<VoxelBox> {
+        self.leaves().map(|(size, pos, _v)| {
+            VoxelBox {
+                pos,
+                size: [
+                    size.min(self.vol.w - pos.x as usize),
+                    size.min(self.vol.h - pos.y as usize),
+                    size.min(self.vol.d - pos.z as usize),
+                ],
+            }
+        }).collect()
+    }
+
+    /// One quad for each exposed face of every node, like the cube mesher
+    /// draws them.
+    pub fn face_quads(&self) -> std::vec::Vec<FaceQuad<C>> {
+        let mut quads = vec![];
+        self.draw(&mut |size: usize, pos: &Pos, v: Voxel<C>| {
+            for face in [F_FRONT, F_TOP, F_BACK, F_LEFT, F_RIGHT, F_BOTTOM].iter() {
+                if v.faces & face == 0 { continue; }
+
+                let (axis, dir) = face_normal_axis(*face);
+                let mut p    = [pos.x, pos.y, pos.z];
+                let mut dims = [size; 3];
+                if dir > 0 { p[axis] += size as PInt - 1; }
+                dims[axis] = 1;
+                quads.push(FaceQuad {
+                    face:  *face,
+                    pos:   Pos::new(p[0], p[1], p[2]),
+                    size:  dims,
+                    color: v.color,
+                });
+            }
+        });
+        quads
+    }
+
+    /// Returns the inclusive (min, max) bounds of all solid voxels.
+    pub fn solid_bounds(&self) -> Option<(Pos, Pos)> {
+        let mut bounds : Option<(Pos, Pos)> = None;
+        for (size, pos, _v) in self.leaves() {
+            let max = pos.offs(size as PInt - 1, size as PInt - 1, size as PInt - 1);
+            bounds =
+                match bounds {
+                    None => Some((pos, max)),
+                    Some((bmin, bmax)) => Some((
+                        Pos::new(bmin.x.min(pos.x), bmin.y.min(pos.y), bmin.z.min(pos.z)),
+                        Pos::new(bmax.x.max(max.x), bmax.y.max(max.y), bmax.z.max(max.z)),
+                    )),
+                };
+        }
+        bounds
+    }
+
+    /// Counts the exposed voxel faces, that is the surface area of
+    /// all solid voxels in unit faces. Only the outer layer of uniform
+    /// nodes is looked at, as their inside can't have exposed faces.
+    pub fn exposed_face_count(&self) -> usize {
+        let mut count = 0;
+        for (size, pos, v) in self.leaves() {
+            if size == 1 {
+                count += v.faces.count_ones() as usize;
+                continue;
+            }
+
+            let s = size as PInt;
+            for a in 0..s {
+                for b in 0..s {
+                    let faces = [
+                        (pos.offs(a, b, 0),     F_FRONT),
+                        (pos.offs(a, b, s - 1), F_BACK),
+                        (pos.offs(a, 0, b),     F_TOP),
+                        (pos.offs(a, s - 1, b), F_BOTTOM),
+                        (pos.offs(0, a, b),     F_LEFT),
+                        (pos.offs(s - 1, a, b), F_RIGHT),
+                    ];
+                    for (p, f) in faces.iter() {
+                        if self.vol.at(*p).faces & f != 0 {
+                            count += 1;
+                        }
+                    }
+                }
+            }
+        }
+        count
+    }
+
+    /// Returns the deepest node that contains the voxel at `pos` and
+    /// is either empty, of uniform color or a single voxel.
+    /// As tuple of node, node size and top left position.
+    fn uniform_node_at(&self, pos: Pos) -> (OctNode<C>, usize, Pos) {
+        let mut tp       = TreePos::new();
+        let mut size     = self.size;
+        let mut top_left = Pos::default();
+
+        while size > 1 {
+            let n = self.nodes[tp.idx()];
+            if n.empty || n.voxel.is_some() {
+                return (n, size, top_left);
+            }
+
+            let half = (size >> 1) as PInt;
+            let x = if pos.x >= top_left.x + half { 1 } else { 0 };
+            let y = if pos.y >= top_left.y + half { 1 } else { 0 };
+            let z = if pos.z >= top_left.z + half { 1 } else { 0 };
+            tp       = tp.lvl_offs(x, y, z);
+            top_left = top_left.offs(x as PInt * half, y as PInt * half, z as PInt * half);
+            size     = half as usize;
+        }
+
+        (self.leaf_node(pos), 1, pos)
+    }
+
+    /// Casts a ray through the volume and returns the first solid voxel
+    /// that is hit within `max_dist`. `origin` is given in voxel
+    /// coordinates, where the voxel at (x, y, z) spans from (x, y, z)
+    /// to (x + 1, y + 1, z + 1). `dir` does not need to be normalized,
+    /// the returned distance is measured in voxel units.
+    ///
+    /// The traversal is a DDA that steps over whole empty octree
+    /// nodes at once, so the nodes need to be up to date
+    /// (see `recompute` and `update_dirty`).
+    pub fn raycast(&self, origin: [f32; 3], dir: [f32; 3], max_dist: f32)
+        -> Option<RayHit<C>>
+    {
+        let len =
+            ((dir[0] as f64).powi(2)
+             + (dir[1] as f64).powi(2)
+             + (dir[2] as f64).powi(2)).sqrt();
+        if len == 0.0 { return None; }
+
+        let o = [origin[0] as f64, origin[1] as f64, origin[2] as f64];
+        let d = [dir[0] as f64 / len, dir[1] as f64 / len, dir[2] as f64 / len];
+        let dims = [self.vol.w as f64, self.vol.h as f64, self.vol.d as f64];
+        let last = [dims[0] - 1.0, dims[1] - 1.0, dims[2] - 1.0];
+
+        // Faces entered when moving along an axis in positive
+        // and negative direction:
+        const ENTRY_FACES : [[u8; 2]; 3] = [
+            [F_LEFT,  F_RIGHT],
+            [F_TOP,   F_BOTTOM],
+            [F_FRONT, F_BACK],
+        ];
+        let entry_face = |axis: usize| {
+            if d[axis] > 0.0 { ENTRY_FACES[axis][0] }
+            else             { ENTRY_FACES[axis][1] }
+        };
+
+        // Clip the ray against the volume bounds:
+        let mut t_enter    = 0.0_f64;
+        let mut t_leave    = std::f64::INFINITY;
+        let mut enter_axis = None;
+        for a in 0..3 {
+            if d[a] == 0.0 {
+                if o[a] < 0.0 || o[a] >= dims[a] { return None; }
+                continue;
+            }
+
+            let (t0, t1) =
+                if d[a] > 0.0 { ((0.0 - o[a]) / d[a], (dims[a] - o[a]) / d[a]) }
+                else          { ((dims[a] - o[a]) / d[a], (0.0 - o[a]) / d[a]) };
+            if t0 > t_enter { t_enter = t0; enter_axis = Some(a); }
+            if t1 < t_leave { t_leave = t1; }
+        }
+        if t_enter >= t_leave || t_enter > max_dist as f64 {
+            return None;
+        }
+
+        let mut t    = t_enter;
+        let mut face = 0;
+        let mut cell = [0.0; 3];
+        for a in 0..3 {
+            cell[a] = (o[a] + d[a] * t).floor().max(0.0).min(last[a]);
+        }
+        if let Some(a) = enter_axis {
+            cell[a] = if d[a] > 0.0 { 0.0 } else { last[a] };
+            face    = entry_face(a);
+        }
+
+        loop {
+            let pos = Pos::new(cell[0] as PInt, cell[1] as PInt, cell[2] as PInt);
+            let (n, size, top_left) = self.uniform_node_at(pos);
+
+            if !n.empty {
+                return Some(RayHit {
+                    pos,
+                    voxel: *self.vol.at(pos),
+                    face,
+                    dist:  t as f32,
+                });
+            }
+
+            // Skip to where the ray leaves the empty node:
+            let min  = [top_left.x as f64, top_left.y as f64, top_left.z as f64];
+            let size = size as f64;
+
+            let mut exit_axis = 0;
+            let mut t_exit    = std::f64::INFINITY;
+            for a in 0..3 {
+                if d[a] == 0.0 { continue; }
+                let bound = if d[a] > 0.0 { min[a] + size } else { min[a] };
+                let ta = (bound - o[a]) / d[a];
+                if ta < t_exit {
+                    t_exit    = ta;
+                    exit_axis = a;
+                }
+            }
+
+            if t_exit > max_dist as f64 || t_exit >= t_leave {
+                return None;
+            }
+
+            t = t_exit.max(t);
+            for a in 0..3 {
+                cell[a] =
+                    (o[a] + d[a] * t).floor()
+                        .max(min[a])
+                        .min(min[a] + size - 1.0);
+            }
+            cell[exit_axis] =
+                if d[exit_axis] > 0.0 { min[exit_axis] + size }
+                else                  { min[exit_axis] - 1.0 };
+            if cell[exit_axis] < 0.0 || cell[exit_axis] > last[exit_axis] {
+                return None;
+            }
+            face = entry_face(exit_axis);
+        }
+    }
+
+    /// Like `draw`, but does not descend deeper than `max_depth` levels
+    /// below the root. Mixed nodes at that depth are drawn as one cube
+    /// in the majority color of their voxels, mostly empty ones are left
+    /// out. Their faces are those sides that don't touch another solid
+    /// node of the same level. Nodes reaching into the padding are
+    /// descended further, so no cube sticks out of the volume.
+    pub fn draw_lod<F>(&self, max_depth: usize, f: &mut F)
+        where F: FnMut(usize, &Pos, Voxel<C>) -> ()
+    {
+        let lod_size = (self.size >> max_depth).max(1);
+        self.draw_lod_level(TreePos::new(), self.size, lod_size, Pos::default(), f);
+    }
+
+    fn draw_lod_level<F>(&self, tp: TreePos, size: usize, lod_size: usize,
+                         top_left: Pos, f: &mut F)
+        where F: FnMut(usize, &Pos, Voxel<C>) -> ()
+    {
+        if size > lod_size || size == 1 || !self.node_in_vol(top_left, size) {
+            if size == 1 {
+                if !self.vol.contains(top_left) { return; }
+                let v = self.vol.at(top_left);
+                if v.color != C::default() {
+                    f(1, &top_left, *v);
+                }
+                return;
+            }
+
+            let n = self.nodes[tp.idx()];
+            if n.empty { return; }
+            if let Some(v) = n.voxel {
+                f(size, &top_left, v);
+                return;
+            }
+
+            let half = size >> 1;
+            for z in 0..2 {
+                for y in 0..2 {
+                    for x in 0..2 {
+                        self.draw_lod_level(
+                            tp.lvl_offs(x, y, z), half, lod_size,
+                            top_left.offs(
+                                (x as usize * half) as PInt,
+                                (y as usize * half) as PInt,
+                                (z as usize * half) as PInt),
+                            f);
+                    }
+                }
+            }
+            return;
+        }
+
+        let n = self.nodes[tp.idx()];
+        if n.empty { return; }
+        if let Some(v) = n.voxel {
+            f(size, &top_left, v);
+            return;
+        }
+
+        let color =
+            match self.lod_color(tp, size, top_left) {
+                Some(c) => c,
+                None    => return,
+            };
+
+        let s = size as i32;
+        let neighbours = [
+            (0, 0, -s, F_FRONT),
+            (0, -s, 0, F_TOP),
+            (0, 0,  s, F_BACK),
+            (-s, 0, 0, F_LEFT),
+            ( s, 0, 0, F_RIGHT),
+            (0,  s, 0, F_BOTTOM),
+        ];
+        let mut faces = 0;
+        for (xo, yo, zo, face) in neighbours.iter() {
+            let x = top_left.x as i32 + xo;
+            let y = top_left.y as i32 + yo;
+            let z = top_left.z as i32 + zo;
+            if x < 0 || y < 0 || z < 0
+               || !self.vol.contains(Pos::new(x as PInt, y as PInt, z as PInt))
+               || !self.is_solid_at_lod(Pos::new(x as PInt, y as PInt, z as PInt), lod_size)
+            {
+                faces |= face;
+            }
+        }
+
+        f(size, &top_left, Voxel { color, faces });
+    }
+
+    /// Returns true if the node doesn't reach into the padding.
+    fn node_in_vol(&self, top_left: Pos, size: usize) -> bool {
+           top_left.x as usize + size <= self.vol.w
+        && top_left.y as usize + size <= self.vol.h
+        && top_left.z as usize + size <= self.vol.d
+    }
+
+    /// The majority color of the voxels of a node, the empty voxels
+    /// counted too. `None` if most of the node is empty.
+    fn lod_color(&self, tp: TreePos, size: usize, top_left: Pos) -> Option<C> {
+        let mut hist = [0_usize; 256];
+        let sub = OctreeLeafIter { tree: self, stack: vec![(tp, size, top_left)] };
+        for (s, _, v) in sub {
+            let c : u8 = v.color.into();
+            hist[c as usize] += s * s * s;
+        }
+        let solid : usize = hist[1..].iter().sum();
+        hist[0] = size * size * size - solid;
+
+        // Ties go to the empty voxels:
+        let mut majority = 0;
+        for (c, cnt) in hist.iter().enumerate() {
+            if *cnt > hist[majority] { majority = c; }
+        }
+        if majority == 0 { return None; }
+        Some((majority as u8).into())
+    }
+
+    /// Returns true if the node containing `pos` that `draw_lod` draws
+    /// with `lod_size` is solid.
+    fn is_solid_at_lod(&self, pos: Pos, lod_size: usize) -> bool {
+        let mut tp       = TreePos::new();
+        let mut size     = self.size;
+        let mut top_left = Pos::default();
+
+        while size > 1 {
+            let n = self.nodes[tp.idx()];
+            if n.empty              { return false; }
+            if n.voxel.is_some()    { return true; }
+            if size <= lod_size && self.node_in_vol(top_left, size) {
+                return self.lod_color(tp, size, top_left).is_some();
+            }
+
+            let half = (size >> 1) as PInt;
+            let x = if pos.x >= top_left.x + half { 1 } else { 0 };
+            let y = if pos.y >= top_left.y + half { 1 } else { 0 };
+            let z = if pos.z >= top_left.z + half { 1 } else { 0 };
+            tp       = tp.lvl_offs(x, y, z);
+            top_left = top_left.offs(x as PInt * half, y as PInt * half, z as PInt * half);
+            size     = half as usize;
+        }
+
+        !self.leaf_node(pos).empty
+    }
+
+    pub fn get_inv_y(&self, x: PInt, y: PInt, z: PInt) -> Voxel<C> {
+        self.get(x, (self.vol.h - 1) as u16 - y, z)
+    }
+
+    pub fn get(&self, x: PInt, y: PInt, z: PInt) -> Voxel<C> {
+        *self.vol.at(Pos::new(x, y, z))
+    }
+
+    pub fn set_inv_y(&mut self, x: PInt, y: PInt, z: PInt, v: Voxel<C>) {
+        self.set(x, (self.vol.h - 1) as u16 - y, z, v);
+    }
+
+    /// Like `set`, but records the change in `journal`.
+    pub fn set_journaled(&mut self, journal: &mut EditJournal<C>,
+                         x: PInt, y: PInt, z: PInt, v: Voxel<C>)
+    {
+        let old = self.get(x, y, z).color;
+        journal.record(Pos::new(x, y, z), old, v.color);
+        self.set(x, y, z, v);
+    }
+
+    /// Writes the edits returned by `EditJournal::undo`, `redo`
+    /// or `EditJournal::deserialize_delta`.
+    pub fn apply_edits(&mut self, edits: &[(Pos, C)]) {
+        for (pos, c) in edits.iter() {
+            self.set(pos.x, pos.y, pos.z, Voxel { color: *c, faces: 0 });
+        }
+    }
+
+    pub fn set(&mut self, x: PInt, y: PInt, z: PInt, v: Voxel<C>) {
+        self.vol.set(x, y, z, v);
+        self.mark_dirty(Pos::new(x, y, z), Pos::new(x, y, z));
+    }
+
+    pub fn fill(&mut self, x: PInt, y: PInt, z: PInt,
+                w: PInt, h: PInt, d: PInt, v: Voxel<C>)
+    {
+        self.vol.fill(x, y, z, w, h, d, v);
+        if w > 0 && h > 0 && d > 0 {
+            self.mark_dirty(
+                Pos::new(x, y, z),
+                Pos::new(x + w - 1, y + h - 1, z + d - 1));
+        }
+    }
+
+    pub fn recompute(&mut self) -> OctNode<C> {
+        let n = self.compute_node(TreePos::new(), self.size, Pos { x: 0, y: 0, z: 0 });
+        self.dirty.clear();
+        self.computed = true;
+        n
+    }
+
+    /// Recomputes only the nodes on the paths from the voxels written
+    /// since the last update (and their neighbours, whose faces might
+    /// have changed) up to the root.
+    ///
+    /// Returns every node whose value changed, including the
+    /// written voxels themselves as nodes with size 1.
+    /// If the tree was never computed or an eighth or more of the
+    /// volume was written, this falls back to `recompute` and only reports the root node.
+    pub fn update_dirty(&mut self) -> std::vec::Vec<OctNode<C>> {
+        if !self.computed { return vec![self.recompute()]; }
+        if self.dirty.is_empty() { return vec![]; }
+
+        let size = self.size;
+        let dirty_volume : usize =
+            self.dirty.iter().map(|(min, max)|
+                  (max.x - min.x + 1) as usize
+                * (max.y - min.y + 1) as usize
+                * (max.z - min.z + 1) as usize).sum();
+
+        if dirty_volume * 8 >= self.vol.data.len() {
+            return vec![self.recompute()];
+        }
+
+        let dirty = std::mem::replace(&mut self.dirty, vec![]);
+        let depth  = size.trailing_zeros() as usize;
+        let last_x = (self.vol.w - 1) as PInt;
+        let last_y = (self.vol.h - 1) as PInt;
+        let last_z = (self.vol.d - 1) as PInt;
+
+        let pos2idx = |p: &Pos| {
+            p.z as usize * size * size + p.y as usize * size + p.x as usize
+        };
+        let idx2pos = |i: usize| {
+            Pos::new((i % size) as PInt,
+                     ((i / size) % size) as PInt,
+                     (i / (size * size)) as PInt)
+        };
+
+        let mut written   = vec![];
+        let mut neighbors = vec![];
+        for (min, max) in dirty.iter() {
+            for z in min.z..=max.z {
+                for y in min.y..=max.y {
+                    for x in min.x..=max.x {
+                        written.push(pos2idx(&Pos::new(x, y, z)));
+                    }
+                }
+            }
+
+            for z in min.z.saturating_sub(1)..=(max.z + 1).min(last_z) {
+                for y in min.y.saturating_sub(1)..=(max.y + 1).min(last_y) {
+                    for x in min.x.saturating_sub(1)..=(max.x + 1).min(last_x) {
+                        neighbors.push(pos2idx(&Pos::new(x, y, z)));
+                    }
+                }
+            }
+        }
+        written.sort();
+        written.dedup();
+        neighbors.sort();
+        neighbors.dedup();
+
+        let mut changed = vec![];
+        let mut cur_level : std::vec::Vec<Pos> = vec![];
+
+        for i in neighbors {
+            let pos = idx2pos(i);
+            let old_faces = self.vol.at(pos).faces;
+            let v = *self.vol.get(pos);
+
+            let is_written = written.binary_search(&i).is_ok();
+            if is_written
+               || (v.color != C::default() && v.faces != old_faces)
+            {
+                let mut n = self.leaf_node(pos);
+                n.tree_pos = TreePos::at_level(depth, pos.x as u8, pos.y as u8, pos.z as u8);
+                changed.push(n);
+                cur_level.push(pos);
+            }
+        }
+
+        let mut node_size = 2;
+        while node_size <= size && !cur_level.is_empty() {
+            let level = depth - node_size.trailing_zeros() as usize;
+            let ns    = node_size as PInt;
+
+            let mut parents : std::vec::Vec<Pos> =
+                cur_level.iter().map(|p|
+                    Pos::new((p.x / ns) * ns, (p.y / ns) * ns, (p.z / ns) * ns))
+                .collect();
+            parents.sort_by_key(|p| pos2idx(p));
+            parents.dedup();
+
+            cur_level.clear();
+            for top_left in parents {
+                let tp = TreePos::at_level(
+                    level,
+                    (top_left.x / ns) as u8,
+                    (top_left.y / ns) as u8,
+                    (top_left.z / ns) as u8);
+                let n = self.merge_node(tp, node_size, top_left);
+                if self.nodes[tp.idx()] != n {
+                    self.nodes[tp.idx()] = n;
+                    changed.push(n);
+                    cur_level.push(top_left);
+                }
+            }
+
+            node_size <<= 1;
+        }
+
+        changed
+    }
+
+    fn leaf_node(&self, pos: Pos) -> OctNode<C> {
+        let mut n = OctNode::default();
+        n.pos = pos;
+        if !self.vol.contains(pos) {
+            n.empty = true;
+            return n;
+        }
+
+        let v = self.vol.at(pos);
+        if v.color == C::default() {
+            n.empty = true;
+        } else {
+            n.voxel = Some(*v);
+            n.empty = false;
+        }
+        n
+    }
+
+    fn child_node(&self, tp: TreePos, size: usize, top_left: Pos) -> OctNode<C> {
+        if size == 1 {
+            self.leaf_node(top_left)
+        } else {
+            self.nodes[tp.idx()]
+        }
+    }
+
+    fn compute_node(&mut self, tp: TreePos, size: usize, top_left: Pos) -> OctNode<C> {
+        if size == 1 {
+            if self.vol.contains(top_left) {
+                self.vol.get(top_left);
+            }
+//            dbg!(nidx, size, top_left, n.voxel, n.empty);
+            return self.leaf_node(top_left);
+        }
+
+        // Nodes completely in the padding don't need to be descended:
+        if !self.vol.contains(top_left) {
+            let mut n = OctNode::default();
+            n.pos      = top_left;
+            n.tree_pos = tp;
+            n.empty    = true;
+            self.nodes[tp.idx()] = n;
+            return n;
+        }
+
+        for z in 0..2 {
+            for y in 0..2 {
+                for x in 0..2 {
+                    self.compute_node(
+                        tp.lvl_offs(x, y, z),
+                        size >> 1,
+                        top_left.offs(
+                            (x as usize * (size >> 1)) as PInt,
+                            (y as usize * (size >> 1)) as PInt,
+                            (z as usize * (size >> 1)) as PInt));
+                }
+            }
+        }
+
+        let n = self.merge_node(tp, size, top_left);
+//        dbg!(tp, size, top_left, n.voxel, n.empty);
+        self.nodes[tp.idx()] = n;
+        n
+    }
+
+    /// Computes the node at `tp` from its already computed children.
+    fn merge_node(&self, tp: TreePos, size: usize, top_left: Pos) -> OctNode<C> {
+        let mut faces : u8 = 0x0;
+        let mut color : C = C::default();
+
+        let mut first       = true;
+        let mut equal_color = true;
+        let mut all_empty   = true;
+        for z in 0..2 {
+            for y in 0..2 {
+                for x in 0..2 {
+                    let n = self.child_node(
+                        tp.lvl_offs(x, y, z),
+                        size >> 1,
+                        top_left.offs(
+                            (x as usize * (size >> 1)) as PInt,
+                            (y as usize * (size >> 1)) as PInt,
+                            (z as usize * (size >> 1)) as PInt));
+
+                    if !n.empty { all_empty = false; }
+                    if let Some(v) = n.voxel {
+                        if first { color = v.color; first = false; }
+                        else if color != v.color { equal_color = false; }
+
+                        faces |= v.faces;
+                    } else {
+                        equal_color = false;
+                    }
+                }
+            }
+        }
+
+        let mut n = OctNode::default();
+        n.pos      = top_left;
+        n.tree_pos = tp;
+
+        if !all_empty && equal_color {
+            let mut v = Voxel::default();
+            v.color = color;
+            v.faces = faces;
+
+            n.empty = false;
+            n.voxel = Some(v);
+
+        } else if !all_empty {
+            n.empty       = false;
+            n.voxel       = None;
+
+        } else {
+            n.empty       = true;
+            n.voxel       = None;
+        }
+
+        n
+    }
+
+    fn node_at(&self, offs: usize,  x: usize, y: usize, z: usize) -> &OctNode<C> {
+        &self.nodes[offs + (z * 2 * 2) + (y * 2) + x]
+    }
+
+    fn node(&mut self, offs: usize, x: usize, y: usize, z: usize) -> &mut OctNode<C> {
+        &mut self.nodes[offs + (z * 2 * 2) + (y * 2) + x]
+//        println!("ANOD lvl={}, pos={:?} => {:?}", level, pos, self.nodes);
+    }
+
+//    fn compute_voxel_node(&mut self, top_left: Pos) -> OctNode<C> {
+//        let mut faces : u8 = 0x0;
+//        let mut color : C  = C::default();
+//
+//        let size = self.vol.size;
+//
+//        let mut first       = true;
+//        let mut equal_color = true;
+//        let mut all_empty   = true;
+//
+//        for z in 0..2 {
+//            for y in 0..2 {
+//                for x in 0..2 {
+//                    let vox = self.vol.get(top_left.offs(x, y, z));
+//
+//                    if first { color = vox.color; first = false; }
+//                    else if color != vox.color { equal_color = false; }
+//                    if color != C::default() { all_empty = false; }
+//
+//                    faces |= xyz2facemask(x, y, z) & vox.faces;
+//                    eprintln!("{},{},{} :: {:?} :: {:x} {:x}",
+//                              x, y, z, top_left, vox.faces, faces);
+//                }
+//            }
+//        }
+//
+//        let mut n = OctNode::default();
+//        if !all_empty && equal_color {
+//            let mut v = Voxel::default();
+//            v.color = color;
+//            v.faces = faces;
+//
+//            n.empty = false;
+//            n.voxel = Some(v);
+//
+//        } else if !all_empty {
+//            n.empty       = false;
+//            n.voxel       = None;
+//
+//        } else {
+//            n.empty       = true;
+//            n.voxel       = None;
+//        }
+//
+//        n
+//    }
+}
+
+pub struct OctreeLeafIter<'a, C: VoxelColor> {
+    tree:  &'a Octree<C>,
+    stack: std::vec::Vec<(TreePos, usize, Pos)>,
+}
+
+impl<'a, C> Iterator for OctreeLeafIter<'a, C> where C: VoxelColor {
+    type Item = (usize, Pos, Voxel<C>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((tp, size, top_left)) = self.stack.pop() {
+            if size == 1 {
+                if !self.tree.vol.contains(top_left) { continue; }
+                let v = self.tree.vol.at(top_left);
+                if v.color != C::default() {
+                    return Some((1, top_left, *v));
+                }
+                continue;
+            }
+
+            let n = self.tree.nodes[tp.idx()];
+            if n.empty { continue; }
+
+            if let Some(v) = n.voxel {
+                return Some((size, top_left, v));
+            }
+
+            // Pushed in reverse, to be visited in the order of `draw`:
+            for z in (0..2).rev() {
+                for y in (0..2).rev() {
+                    for x in (0..2).rev() {
+                        self.stack.push((
+                            tp.lvl_offs(x, y, z),
+                            size >> 1,
+                            top_left.offs(
+                                (x as usize * (size >> 1)) as PInt,
+                                (y as usize * (size >> 1)) as PInt,
+                                (z as usize * (size >> 1)) as PInt)));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Returns the axis (0 = x, 1 = y, 2 = z) and direction of the normal
+/// of a face given as one of the `F_*` bits.
+fn face_normal_axis(face: u8) -> (usize, i32) {
+    match face {
+        F_FRONT  => (2, -1),
+        F_BACK   => (2,  1),
+        F_LEFT   => (0, -1),
+        F_RIGHT  => (0,  1),
+        F_TOP    => (1, -1),
+        _        => (1,  1),
+    }
+}
+
+/// Ambient occlusion level of a face corner, from 0 (fully occluded)
+/// to 3 (not occluded). The face belongs to the box at `pos` with the
+/// extents `size` in voxels, `corner` selects the box corner with 0 or 1
+/// per axis. The level is computed from the three cells in front of the
+/// face that touch the corner from outside the face.
+pub fn vertex_ao<F>(pos: Pos, size: [usize; 3], face: u8, corner: [u8; 3], is_solid: F) -> u8
+    where F: Fn(i32, i32, i32) -> bool
+{
+    let (axis, dir) = face_normal_axis(face);
+    let p    = [pos.x as i32, pos.y as i32, pos.z as i32];
+    let size = [size[0] as i32, size[1] as i32, size[2] as i32];
+
+    let mut inside  = [0; 3];
+    let mut outside = [0; 3];
+    for i in 0..3 {
+        if i == axis {
+            let layer = if dir < 0 { p[i] - 1 } else { p[i] + size[i] };
+            inside[i]  = layer;
+            outside[i] = layer;
+        } else if corner[i] == 0 {
+            inside[i]  = p[i];
+            outside[i] = p[i] - 1;
+        } else {
+            inside[i]  = p[i] + size[i] - 1;
+            outside[i] = p[i] + size[i];
+        }
+    }
+
+    let (u, v) = match axis { 0 => (1, 2), 1 => (0, 2), _ => (0, 1) };
+    let cell = |use_u_out: bool, use_v_out: bool| {
+        let mut c = inside;
+        if use_u_out { c[u] = outside[u]; }
+        if use_v_out { c[v] = outside[v]; }
+        is_solid(c[0], c[1], c[2])
+    };
+
+    let side1  = cell(true, false);
+    let side2  = cell(false, true);
+    let corner = cell(true, true);
+    if side1 && side2 { return 0; }
+    3 - (side1 as u8 + side2 as u8 + corner as u8)
+}
+
+/// A rectangle of exposed faces with the same color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaceQuad<C: VoxelColor> {
+    /// One of the `F_*` bits.
+    pub face:  u8,
+    /// The voxel with the smallest coordinates the quad covers.
+    pub pos:   Pos,
+    /// The extents in voxels along each axis, 1 along the face normal.
+    pub size:  [usize; 3],
+    pub color: C,
+}
+
+impl<C> FaceQuad<C> where C: VoxelColor {
+    /// Calls `f` with the position of each voxel face the quad covers.
+    pub fn for_each_unit_face<F: FnMut(Pos)>(&self, mut f: F) {
+        for z in 0..self.size[2] {
+            for y in 0..self.size[1] {
+                for x in 0..self.size[0] {
+                    f(self.pos.offs(x as PInt, y as PInt, z as PInt));
+                }
+            }
+        }
+    }
+}
+
+/// An axis aligned box of solid voxels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxelBox {
+    /// The voxel with the smallest coordinates.
+    pub pos:  Pos,
+    pub size: [usize; 3],
+}
+
+impl VoxelBox {
+    pub fn voxel_count(&self) -> usize {
+        self.size[0] * self.size[1] * self.size[2]
+    }
+
+    pub fn contains(&self, p: Pos) -> bool {
+        let (p, o) = ([p.x as usize, p.y as usize, p.z as usize],
+                      [self.pos.x as usize, self.pos.y as usize, self.pos.z as usize]);
+        (0..3).all(|i| p[i] >= o[i] && p[i] < o[i] + self.size[i])
+    }
+}
+
+/// Returns true if a quad with the corner occlusion levels `ao`, given
+/// in winding order, should be split along the diagonal from corner 1
+/// to 3 instead of 0 to 2. The diagonal with the brighter ends is used,
+/// so the occlusion is interpolated the same for every orientation.
+pub fn ao_split_13(ao: [u8; 4]) -> bool {
+    (ao[0] as u16 + ao[2] as u16) < (ao[1] as u16 + ao[3] as u16)
+}
+
+/// A volume split into octree chunks of `chunk_size` voxels per edge.
+/// Chunks are only allocated on the first write of a solid voxel, and
+/// are shared as `Arc<RwLock<_>>` so they can be meshed on other threads.
+/// Coordinates follow the `Vol` convention, the `_inv_y` accessors take
+/// an y axis that points up, like the one of the meshes.
+/// Clones share the allocated chunks, for reading them on other threads.
+#[derive(Clone)]
+pub struct ChunkedVolume<C: VoxelColor> {
+    pub w:          usize,
+    pub h:          usize,
+    pub d:          usize,
+    pub chunk_size: usize,
+    chunks_w:       usize,
+    chunks_h:       usize,
+    chunks_d:       usize,
+    chunks:         std::vec::Vec<Option<std::sync::Arc<std::sync::RwLock<Octree<C>>>>>,
+    /// Chunks that changed since the last `take_dirty`.
+    dirty:          std::vec::Vec<bool>,
+}
+
+impl<C> ChunkedVolume<C> where C: VoxelColor {
+    pub fn new(w: usize, h: usize, d: usize, chunk_size: usize) -> Self {
+        let chunks_w = (w + chunk_size - 1) / chunk_size;
+        let chunks_h = (h + chunk_size - 1) / chunk_size;
+        let chunks_d = (d + chunk_size - 1) / chunk_size;
+        let count    = chunks_w * chunks_h * chunks_d;
+
+        let mut chunks = vec![];
+        chunks.resize_with(count, || None);
+
+        Self {
+            w, h, d, chunk_size,
+            chunks_w, chunks_h, chunks_d,
+            chunks,
+            dirty: vec![false; count],
+        }
+    }
+
+    /// Number of chunks along each axis.
+    pub fn chunk_dims(&self) -> (usize, usize, usize) {
+        (self.chunks_w, self.chunks_h, self.chunks_d)
+    }
+
+    pub fn chunk_count(&self) -> usize { self.chunks.len() }
+
+    pub fn allocated_chunk_count(&self) -> usize {
+        self.chunks.iter().filter(|c| c.is_some()).count()
+    }
+
+    pub fn chunk_index(&self, cx: usize, cy: usize, cz: usize) -> usize {
+        cz * self.chunks_w * self.chunks_h + cy * self.chunks_w + cx
+    }
+
+    pub fn chunk_coords(&self, idx: usize) -> (usize, usize, usize) {
+        (idx % self.chunks_w,
+         (idx / self.chunks_w) % self.chunks_h,
+         idx / (self.chunks_w * self.chunks_h))
+    }
+
+    /// Returns the index of the chunk holding `pos` and the position
+    /// inside of that chunk.
+    pub fn locate(&self, pos: Pos) -> (usize, Pos) {
+        let cs = self.chunk_size;
+        let (x, y, z) = (pos.x as usize, pos.y as usize, pos.z as usize);
+        (self.chunk_index(x / cs, y / cs, z / cs),
+         Pos::new((x % cs) as PInt, (y % cs) as PInt, (z % cs) as PInt))
+    }
+
+    /// The dimensions of a chunk, which are smaller than `chunk_size`
+    /// at the far edges if the volume size is not a multiple of it.
+    pub fn chunk_extent(&self, idx: usize) -> (usize, usize, usize) {
+        let o  = self.chunk_origin(idx);
+        let cs = self.chunk_size;
+        (cs.min(self.w - o.x as usize),
+         cs.min(self.h - o.y as usize),
+         cs.min(self.d - o.z as usize))
+    }
+
+    pub fn chunk_origin(&self, idx: usize) -> Pos {
+        let (cx, cy, cz) = self.chunk_coords(idx);
+        let cs = self.chunk_size;
+        Pos::new((cx * cs) as PInt, (cy * cs) as PInt, (cz * cs) as PInt)
+    }
+
+    /// The chunk origin with the y axis pointing up, that is the lowest
+    /// corner of the chunk in the coordinates of the `_inv_y` accessors.
+    pub fn chunk_origin_inv_y(&self, idx: usize) -> Pos {
+        let o = self.chunk_origin(idx);
+        let (_, ch, _) = self.chunk_extent(idx);
+        Pos::new(o.x, (self.h - (o.y as usize + ch)) as PInt, o.z)
+    }
+
+    pub fn chunk(&self, idx: usize) -> Option<&std::sync::Arc<std::sync::RwLock<Octree<C>>>> {
+        self.chunks[idx].as_ref()
+    }
+
+    pub fn contains(&self, pos: Pos) -> bool {
+        (pos.x as usize) < self.w && (pos.y as usize) < self.h && (pos.z as usize) < self.d
+    }
+
+    pub fn get(&self, pos: Pos) -> Voxel<C> {
+        let (idx, lpos) = self.locate(pos);
+        match &self.chunks[idx] {
+            Some(ot) => ot.read().unwrap().get(lpos.x, lpos.y, lpos.z),
+            None     => Voxel::default(),
+        }
+    }
+
+    /// Writes a voxel into its chunk, allocating the chunk if needed.
+    /// This waits for the lock of the chunk, so while a render job
+    /// updates or meshes that chunk, the write blocks until it's done.
+    /// Writes to other chunks don't wait.
+    pub fn set(&mut self, pos: Pos, v: Voxel<C>) {
+        let (idx, lpos) = self.locate(pos);
+        if self.chunks[idx].is_none() {
+            if v.color == C::default() { return; }
+
+            let (cw, ch, cd) = self.chunk_extent(idx);
+            self.chunks[idx] =
+                Some(std::sync::Arc::new(std::sync::RwLock::new(
+                    Octree::new_from_dims(cw, ch, cd))));
+        }
+
+        if let Some(ot) = &self.chunks[idx] {
+            ot.write().unwrap().set(lpos.x, lpos.y, lpos.z, v);
+        }
+        self.dirty[idx] = true;
+    }
+
+    pub fn get_inv_y(&self, x: PInt, y: PInt, z: PInt) -> Voxel<C> {
+        self.get(Pos::new(x, (self.h - 1) as PInt - y, z))
+    }
+
+    pub fn set_inv_y(&mut self, x: PInt, y: PInt, z: PInt, v: Voxel<C>) {
+        self.set(Pos::new(x, (self.h - 1) as PInt - y, z), v);
+    }
+
+    pub fn is_dirty(&self, idx: usize) -> bool { self.dirty[idx] }
+
+    pub fn mark_dirty(&mut self, idx: usize) { self.dirty[idx] = true; }
+
+    pub fn mark_all_dirty(&mut self) {
+        for d in self.dirty.iter_mut() { *d = true; }
+    }
+
+    /// Iterates over the indices of all dirty chunks.
+    pub fn dirty_chunks<'a>(&'a self) -> impl Iterator<Item=usize> + 'a {
+        self.dirty.iter().enumerate().filter(|(_, d)| **d).map(|(i, _)| i)
+    }
+
+    /// Returns the indices of all dirty chunks and clears their flags.
+    pub fn take_dirty(&mut self) -> std::vec::Vec<usize> {
+        let dirty : std::vec::Vec<usize> = self.dirty_chunks().collect();
+        for i in dirty.iter() { self.dirty[*i] = false; }
+        dirty
+    }
+
+    /// Drops all chunks and marks them dirty.
+    pub fn clear(&mut self) {
+        for c in self.chunks.iter_mut() { *c = None; }
+        self.mark_all_dirty();
+    }
+
+    /// Replaces the contents with `vol`, which is clipped to this volume.
+    /// Only chunks with solid voxels are allocated.
+    pub fn write_vol(&mut self, vol: &Vol<C>) {
+        self.clear();
+
+        let empty = C::default();
+        for idx in 0..self.chunks.len() {
+            let (cw, ch, cd) = self.chunk_extent(idx);
+            let sub = vol.copy_region(self.chunk_origin(idx), cw, ch, cd);
+            if sub.data.iter().all(|v| v.color == empty) { continue; }
+
+            let mut chunk_vol = Vol::new_dims(cw, ch, cd);
+            chunk_vol.paste(&sub, Pos::new(0, 0, 0));
+            self.chunks[idx] =
+                Some(std::sync::Arc::new(std::sync::RwLock::new(
+                    Octree::new(cw * ch * cd, chunk_vol))));
+        }
+    }
+
+    pub fn to_vol(&self) -> Vol<C> {
+        let mut vol = Vol::new_dims(self.w, self.h, self.d);
+        for (idx, c) in self.chunks.iter().enumerate() {
+            if let Some(ot) = c {
+                vol.paste(&ot.read().unwrap().vol, self.chunk_origin(idx));
+            }
+        }
+        vol
+    }
+
+    /// Counts the voxels of each color, unallocated chunks count as empty.
+    pub fn color_histogram(&self) -> std::vec::Vec<usize> {
+        let mut hist = vec![0; 256];
+        let mut allocated = 0;
+        for c in self.chunks.iter() {
+            if let Some(ot) = c {
+                let ot = ot.read().unwrap();
+                for v in ot.vol.data.iter() {
+                    let c : u8 = v.color.into();
+                    hist[c as usize] += 1;
+                }
+                allocated += ot.vol.data.len();
+            }
+        }
+        hist[0] += self.w * self.h * self.d - allocated;
+        hist
+    }
+}
+
+/// The sides of a voxel, as drawn by the mesher. The directions are
+/// the ones of the mesh, where y points up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Face {
+    Front,  // x,       y,      z - 1
+    Top,    // x,       y + 1,  z
+    Back,   // x,       y,      z + 1
+    Left,   // x - 1,   y,      z
+    Right,  // x + 1,   y,      z
+    Bottom, // x,       y - 1,  z
+}
+
+const CUBE_VERTICES : [[f32; 3]; 8] = [
+  [ 0., 0., 0. ], // 0
+  [ 0., 1., 0. ], // 1
+  [ 1., 1., 0. ], // 2
+  [ 1., 0., 0. ], // 3
+
+  [ 0., 0., 1. ], // 4
+  [ 0., 1., 1. ], // 5
+  [ 1., 1., 1. ], // 6
+  [ 1., 0., 1. ], // 7
+];
+
+const CUBE_NORMALS : [[f32; 3]; 6] = [
+  [  0.,  0., -1. ],
+  [  0.,  1.,  0. ],
+  [  0.,  0.,  1. ],
+  [ -1.,  0.,  0. ],
+  [  1.,  0.,  0. ],
+  [  0., -1.,  0. ],
+];
+
+/// Indices into the CUBE_VERTICES constant:
+const FACE_TRIANGLE_VERTEX_IDX : [[usize; 10]; 6] = [
+// Cube Vertex Idx | relative indexes of those:
+   [0, 1, 2, 3,      2, 1, 0,  0, 3, 2, ],
+   [1, 5, 6, 2,      2, 1, 0,  0, 3, 2, ],
+   [4, 5, 6, 7,      1, 2, 3,  3, 0, 1, ],
+   [0, 1, 5, 4,      1, 2, 3,  3, 0, 1, ],
+   [3, 7, 6, 2,      2, 3, 0,  0, 1, 2, ],
+   [0, 4, 7, 3,      1, 2, 3,  3, 0, 1, ],
+];
+
+/// The triangles of FACE_TRIANGLE_VERTEX_IDX split along the other
+/// diagonal of the quad, with the same winding.
+const FACE_TRIANGLE_FLIPPED_IDX : [[usize; 6]; 6] = [
+   [3, 2, 1,  1, 0, 3, ],
+   [3, 2, 1,  1, 0, 3, ],
+   [0, 1, 2,  2, 3, 0, ],
+   [0, 1, 2,  2, 3, 0, ],
+   [1, 2, 3,  3, 0, 1, ],
+   [0, 1, 2,  2, 3, 0, ],
+];
+
+/// Whether FACE_TRIANGLE_VERTEX_IDX splits the quad along the diagonal
+/// from vertex 1 to 3 instead of 0 to 2.
+const FACE_TRIANGLE_SPLIT_13 : [bool; 6] = [false, false, true, true, false, true];
+
+/// Brightness of a vertex for each ambient occlusion level.
+const AO_BRIGHTNESS : [f32; 4] = [0.45, 0.65, 0.85, 1.0];
+
+//const FACE_TRIANGLE_VERTEX_UV : [[f32; 2]; 8] = [
+const FACE_TRIANGLE_VERTEX_UV : [[f32; 2]; 4] = [
+    [0., 0.],
+    [0., 1.],
+    [1., 1.],
+    [1., 0.],
+];
+
+const FACES : [Face; 6] = [
+    Face::Front, Face::Top, Face::Back, Face::Left, Face::Right, Face::Bottom,
+];
+
+impl Face {
+    fn index(&self) -> usize {
+        match self {
+            Face::Front  => 0,
+            Face::Top    => 1,
+            Face::Back   => 2,
+            Face::Left   => 3,
+            Face::Right  => 4,
+            Face::Bottom => 5,
+        }
+    }
+
+    /// The `F_*` bit of the face in `Voxel::faces`.
+    pub fn bit(&self) -> u8 {
+        match self {
+            Face::Front  => F_FRONT,
+            Face::Top    => F_TOP,
+            Face::Back   => F_BACK,
+            Face::Left   => F_LEFT,
+            Face::Right  => F_RIGHT,
+            Face::Bottom => F_BOTTOM,
+        }
+    }
+
+    pub fn from_bit(bit: u8) -> Option<Face> {
+        FACES.iter().find(|f| f.bit() == bit).copied()
+    }
+
+    /// The offset to the neighbour behind the face, the y axis pointing up.
+    pub fn offset(&self) -> [i32; 3] {
+        match self {
+            Face::Front  => [ 0,  0, -1],
+            Face::Top    => [ 0,  1,  0],
+            Face::Back   => [ 0,  0,  1],
+            Face::Left   => [-1,  0,  0],
+            Face::Right  => [ 1,  0,  0],
+            Face::Bottom => [ 0, -1,  0],
+        }
+    }
+
+    pub fn opposite(&self) -> Face {
+        match self {
+            Face::Front  => Face::Back,
+            Face::Top    => Face::Bottom,
+            Face::Back   => Face::Front,
+            Face::Left   => Face::Right,
+            Face::Right  => Face::Left,
+            Face::Bottom => Face::Top,
+        }
+    }
+
+    /// The face `n` points out of, going by its largest component.
+    pub fn from_normal(n: [f32; 3]) -> Face {
+        let (ax, ay, az) = (n[0].abs(), n[1].abs(), n[2].abs());
+        if ax >= ay && ax >= az {
+            if n[0] < 0.0 { Face::Left } else { Face::Right }
+        } else if ay >= az {
+            if n[1] < 0.0 { Face::Bottom } else { Face::Top }
+        } else if n[2] < 0.0 {
+            Face::Front
+        } else {
+            Face::Back
+        }
+    }
+
+    /// The corners of the face vertices as used by `Octree::face_ao`.
+    /// The volume y axis points down, the one of the mesh up.
+    pub fn vol_corners(&self) -> [[u8; 3]; 4] {
+        let tris = &FACE_TRIANGLE_VERTEX_IDX[self.index()];
+        let mut corners = [[0; 3]; 4];
+        for i in 0..4 {
+            let v = CUBE_VERTICES[tris[i]];
+            corners[i] = [v[0] as u8, 1 - v[1] as u8, v[2] as u8];
+        }
+        corners
+    }
+}
+
+/// The algorithm `mesh_octree` builds the faces with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MeshBackend {
+    /// One quad per exposed face of each octree node.
+    OctreeCubes,
+    /// Merges coplanar faces of the same color into maximal rectangles,
+    /// see `Vol::greedy_quads`.
+    Greedy,
+}
+
+impl Default for MeshBackend {
+    fn default() -> Self { MeshBackend::OctreeCubes }
+}
+
+impl MeshBackend {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "octree" | "cubes" => Some(MeshBackend::OctreeCubes),
+            "greedy"           => Some(MeshBackend::Greedy),
+            _                  => None,
+        }
+    }
+}
+
+/// The collision geometry `mesh_octree` builds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollisionMode {
+    /// The triangles of the mesh.
+    Trimesh,
+    /// One box per solid octree node, see `Octree::node_boxes`.
+    NodeBoxes,
+    /// Merged boxes, see `Vol::greedy_boxes`.
+    GreedyBoxes,
+}
+
+impl Default for CollisionMode {
+    fn default() -> Self { CollisionMode::Trimesh }
+}
+
+impl CollisionMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "trimesh" => Some(CollisionMode::Trimesh),
+            "nodes"   => Some(CollisionMode::NodeBoxes),
+            "boxes"   => Some(CollisionMode::GreedyBoxes),
+            _         => None,
+        }
+    }
+}
+
+/// Options for `mesh_octree`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MeshOptions {
+    /// Only render up to this depth of the octree (see `Octree::draw_lod`).
+    /// The greedy backend always renders the full resolution.
+    pub lod_depth:         Option<usize>,
+    /// Darken the face corners by the neighbouring voxels.
+    pub ambient_occlusion: bool,
+    pub backend:           MeshBackend,
+    pub collision:         CollisionMode,
+}
+
+/// The triangle arrays of a voxel mesh, independent of the engine
+/// drawing them. All vertex arrays have the same length.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeshData {
+    pub positions:      std::vec::Vec<[f32; 3]>,
+    pub normals:        std::vec::Vec<[f32; 3]>,
+    /// RGBA, with the ambient occlusion applied.
+    pub colors:         std::vec::Vec<[f32; 4]>,
+    pub uvs:            std::vec::Vec<[f32; 2]>,
+    /// The extents of the face in voxels, for tiling textures.
+    pub uvs2:           std::vec::Vec<[f32; 2]>,
+    pub indices:        std::vec::Vec<u32>,
+    /// The corners of each triangle in `indices`, three per triangle.
+    /// Empty if the collision is made of `collision_boxes`.
+    pub collision_tris: std::vec::Vec<[f32; 3]>,
+    pub collision_boxes: std::vec::Vec<CollisionBox>,
+}
+
+/// A box of the collision geometry in mesh coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollisionBox {
+    pub center:       [f32; 3],
+    pub half_extents: [f32; 3],
+}
+
+impl CollisionBox {
+    /// The box around the voxels of `vb` in a volume with the height
+    /// `vol_h`, with the y axis flipped like the meshes.
+    pub fn from_voxel_box(vb: &VoxelBox, vol_h: usize) -> Self {
+        let half = [vb.size[0] as f32 * 0.5, vb.size[1] as f32 * 0.5, vb.size[2] as f32 * 0.5];
+        Self {
+            center: [
+                vb.pos.x as f32 + half[0],
+                vol_h as f32 - (vb.pos.y as f32 + half[1]),
+                vb.pos.z as f32 + half[2],
+            ],
+            half_extents: half,
+        }
+    }
+}
+
+impl MeshData {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn vertex_count(&self) -> usize { self.positions.len() }
+
+    pub fn triangle_count(&self) -> usize { self.indices.len() / 3 }
+
+    pub fn is_empty(&self) -> bool { self.indices.is_empty() }
+
+    /// Appends the mesh `md`, moved by `offs`.
+    pub fn append(&mut self, md: &MeshData, offs: [f32; 3]) {
+        let first = self.positions.len() as u32;
+        let moved = |p: &[f32; 3]| [p[0] + offs[0], p[1] + offs[1], p[2] + offs[2]];
+
+        self.positions.extend(md.positions.iter().map(&moved));
+        self.normals.extend_from_slice(&md.normals);
+        self.colors.extend_from_slice(&md.colors);
+        self.uvs.extend_from_slice(&md.uvs);
+        self.uvs2.extend_from_slice(&md.uvs2);
+        self.indices.extend(md.indices.iter().map(|i| i + first));
+        self.collision_tris.extend(md.collision_tris.iter().map(&moved));
+        self.collision_boxes.extend(md.collision_boxes.iter().map(|b| CollisionBox {
+            center:       moved(&b.center),
+            half_extents: b.half_extents,
+        }));
+    }
+
+    /// Appends a quad for `face` of the box at `offs` with the extents
+    /// `size`, both in mesh coordinates. Without a `tile` the UVs run
+    /// from 0 to 1 over the face and UV2 holds its extents. With the
+    /// origin of an atlas tile, UV counts voxels from the upper left
+    /// corner of the face as seen from outside and UV2 is the tile origin.
+    pub fn add_face(&mut self,
+                    face: Face,
+                    color: [f32; 4],
+                    ao: Option<[u8; 4]>,
+                    tile: Option<[f32; 2]>,
+                    offs: [f32; 3],
+                    size: [f32; 3],
+                    scale: f32) {
+
+        let tris   = &FACE_TRIANGLE_VERTEX_IDX[face.index()];
+        let normal = CUBE_NORMALS[face.index()];
+
+        let mut tri_idx = [tris[4], tris[5], tris[6], tris[7], tris[8], tris[9]];
+        if let Some(ao) = ao {
+            if ao_split_13(ao) != FACE_TRIANGLE_SPLIT_13[face.index()] {
+                tri_idx = FACE_TRIANGLE_FLIPPED_IDX[face.index()];
+            }
+        }
+
+        // The UV.y axis runs from vertex 0 to 1, UV.x from vertex 1 to 2:
+        let extent = |a: usize, b: usize| {
+            let (a, b) = (CUBE_VERTICES[tris[a]], CUBE_VERTICES[tris[b]]);
+            (0..3).find(|i| a[*i] != b[*i]).map(|i| size[i]).unwrap_or(1.0)
+        };
+        let uv2 = [extent(1, 2), extent(0, 1)];
+
+        let first = self.positions.len();
+        for i in 0..4 {
+            let cv = CUBE_VERTICES[tris[i]];
+            self.positions.push([
+                (cv[0] * size[0] + offs[0]) * scale,
+                (cv[1] * size[1] + offs[1]) * scale,
+                (cv[2] * size[2] + offs[2]) * scale,
+            ]);
+            self.normals.push(normal);
+            match tile {
+                Some(tile) => {
+                    self.uvs.push(atlas_face_uv(face, cv, size));
+                    self.uvs2.push(tile);
+                },
+                None => {
+                    self.uvs.push(FACE_TRIANGLE_VERTEX_UV[i]);
+                    self.uvs2.push(uv2);
+                },
+            }
+            self.colors.push(
+                match ao {
+                    Some(ao) => {
+                        let b = AO_BRIGHTNESS[ao[i] as usize];
+                        [color[0] * b, color[1] * b, color[2] * b, color[3]]
+                    },
+                    None => color,
+                });
+        }
+
+        for idx in tri_idx.iter() {
+            let vi = first + *idx;
+            self.indices.push(vi as u32);
+            self.collision_tris.push(self.positions[vi]);
+        }
+    }
+}
+
+/// The atlas UV of the cube corner `cv` of a face with the extents
+/// `size`, so that textures are upright on the sides and not mirrored.
+fn atlas_face_uv(face: Face, cv: [f32; 3], size: [f32; 3]) -> [f32; 2] {
+    match face {
+        Face::Front  => [(1.0 - cv[0]) * size[0], (1.0 - cv[1]) * size[1]],
+        Face::Back   => [cv[0] * size[0],         (1.0 - cv[1]) * size[1]],
+        Face::Left   => [cv[2] * size[2],         (1.0 - cv[1]) * size[1]],
+        Face::Right  => [(1.0 - cv[2]) * size[2], (1.0 - cv[1]) * size[1]],
+        Face::Top    => [cv[0] * size[0],         cv[2] * size[2]],
+        Face::Bottom => [cv[0] * size[0],         (1.0 - cv[2]) * size[2]],
+    }
+}
+
+/// The tiles of a voxel color in a `TextureAtlas`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AtlasTiles {
+    pub top:    u16,
+    pub side:   u16,
+    pub bottom: u16,
+}
+
+impl AtlasTiles {
+    pub fn all(tile: u16) -> Self {
+        Self { top: tile, side: tile, bottom: tile }
+    }
+}
+
+/// Tiles are numbered by `u16`, an atlas can't have more than this.
+pub const ATLAS_MAX_TILES : usize = u16::MAX as usize + 1;
+
+/// Maps the voxel colors to the tiles of a texture atlas with
+/// `columns` times `rows` tiles of the same size, numbered row by row
+/// from the upper left.
+#[derive(Clone, Copy)]
+pub struct TextureAtlas {
+    pub columns: u16,
+    pub rows:    u16,
+    pub tiles:   [AtlasTiles; 256],
+}
+
+impl TextureAtlas {
+    /// Creates an atlas with every voxel color on the first tile.
+    pub fn new(columns: u16, rows: u16) -> Self {
+        Self {
+            columns: columns.max(1),
+            rows:    rows.max(1),
+            tiles:   [AtlasTiles::default(); 256],
+        }
+    }
+
+    pub fn set(&mut self, c: u8, tiles: AtlasTiles) {
+        self.tiles[c as usize] = tiles;
+    }
+
+    /// The tile of the voxel color `c` for a face direction.
+    pub fn tile_for(&self, c: u8, face: Face) -> u16 {
+        let t = &self.tiles[c as usize];
+        match face {
+            Face::Top    => t.top,
+            Face::Bottom => t.bottom,
+            _            => t.side,
+        }
+    }
+
+    /// The extents of a tile in texture coordinates.
+    pub fn tile_size(&self) -> [f32; 2] {
+        [1.0 / self.columns as f32, 1.0 / self.rows as f32]
+    }
+
+    /// The upper left corner of a tile in texture coordinates,
+    /// tiles beyond the atlas wrap around.
+    pub fn tile_origin(&self, tile: u16) -> [f32; 2] {
+        let columns = self.columns as usize;
+        let tile    = tile as usize % (columns * self.rows as usize);
+        let ts      = self.tile_size();
+        [(tile % columns) as f32 * ts[0],
+         (tile / columns) as f32 * ts[1]]
+    }
+}
+
+/// Builds the mesh of the solid voxels of `vt`, with `color` mapping
+/// the voxel colors to RGBA. The volume y axis is flipped, so that
+/// the mesh y axis points up. The padding of the octree is never drawn.
+pub fn mesh_octree<C, F>(vt: &Octree<C>, opts: MeshOptions, color: F) -> MeshData
+    where C: VoxelColor, F: Fn(C) -> [f32; 4]
+{
+    mesh_octree_tiles(vt, opts, color, |_, _| None)
+}
+
+/// Meshes every chunk of `cv` like `mesh_octree` into one mesh, placed
+/// by the chunk origins with the y axis pointing up. Each chunk is
+/// meshed on its own, so faces between chunks are kept. Chunks that
+/// changed since their last render are brought up to date.
+pub fn mesh_chunked_volume<C, F>(cv: &ChunkedVolume<C>, opts: MeshOptions, color: F) -> MeshData
+    where C: VoxelColor, F: Fn(C) -> [f32; 4]
+{
+    let mut md = MeshData::new();
+    for idx in 0..cv.chunk_count() {
+        let ot =
+            match cv.chunk(idx) {
+                Some(ot) => ot,
+                None     => continue,
+            };
+        let mut ot = ot.write().unwrap();
+        ot.update_dirty();
+
+        let o = cv.chunk_origin_inv_y(idx);
+        md.append(&mesh_octree(&*ot, opts, &color), [o.x as f32, o.y as f32, o.z as f32]);
+    }
+    md
+}
+
+/// Like `mesh_octree`, with the UVs of the `atlas` tiles of the voxel
+/// colors, see `MeshData::add_face`. The vertex colors are white and
+/// only carry the ambient occlusion.
+pub fn mesh_octree_atlas<C>(vt: &Octree<C>, opts: MeshOptions, atlas: &TextureAtlas) -> MeshData
+    where C: VoxelColor
+{
+    mesh_octree_tiles(
+        vt, opts, |_| [1.0; 4],
+        |c: C, face| Some(atlas.tile_origin(atlas.tile_for(c.into(), face))))
+}
+
+fn mesh_octree_tiles<C, F, T>(vt: &Octree<C>, opts: MeshOptions, color: F, tile: T) -> MeshData
+    where C: VoxelColor, F: Fn(C) -> [f32; 4], T: Fn(C, Face) -> Option<[f32; 2]>
+{
+    let mut md = MeshData::new();
+    let empty  = C::default();
+    let vol_h  = vt.vol.h;
+
+    match opts.backend {
+        MeshBackend::OctreeCubes => {
+            let mut draw_cube = |cube_size: usize, pos: &Pos, v: Voxel<C>| {
+                if v.color == empty { return; }
+
+                let clr = color(v.color);
+                let p = [
+                    pos.x as f32,
+                    (vol_h - cube_size - pos.y as usize) as f32,
+                    pos.z as f32,
+                ];
+
+                for face in FACES.iter() {
+                    if v.faces & face.bit() == 0 { continue; }
+
+                    let ao =
+                        if opts.ambient_occlusion {
+                            Some(vt.face_ao(
+                                *pos, [cube_size; 3], face.bit(), &face.vol_corners()))
+                        } else {
+                            None
+                        };
+
+                    md.add_face(
+                        *face, clr, ao, tile(v.color, *face), p,
+                        [cube_size as f32; 3], 1.0);
+                }
+            };
+
+            match opts.lod_depth {
+                Some(depth) => vt.draw_lod(depth, &mut draw_cube),
+                None        => vt.draw(&mut draw_cube),
+            }
+        },
+        MeshBackend::Greedy => {
+            for q in vt.vol.greedy_quads().iter() {
+                let face =
+                    match Face::from_bit(q.face) {
+                        Some(face) => face,
+                        None       => continue,
+                    };
+
+                let p = [
+                    q.pos.x as f32,
+                    (vol_h - (q.pos.y as usize + q.size[1])) as f32,
+                    q.pos.z as f32,
+                ];
+
+                let ao =
+                    if opts.ambient_occlusion {
+                        Some(vt.face_ao(q.pos, q.size, q.face, &face.vol_corners()))
+                    } else {
+                        None
+                    };
+
+                md.add_face(
+                    face, color(q.color), ao, tile(q.color, face), p,
+                    [q.size[0] as f32, q.size[1] as f32, q.size[2] as f32], 1.0);
+            }
+        },
+    }
+
+    let boxes =
+        match opts.collision {
+            CollisionMode::Trimesh     => None,
+            CollisionMode::NodeBoxes   => Some(vt.node_boxes()),
+            CollisionMode::GreedyBoxes => Some(vt.vol.greedy_boxes()),
+        };
+    if let Some(boxes) = boxes {
+        md.collision_tris.clear();
+        md.collision_boxes =
+            boxes.iter().map(|b| CollisionBox::from_voxel_box(b, vol_h)).collect();
+    }
+
+    md
+}
+
+/// A scalar field sampled at the voxel centers, as meshed by
+/// `mesh_surface_nets`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DensityField {
+    pub w:    usize,
+    pub h:    usize,
+    pub d:    usize,
+    pub data: std::vec::Vec<f32>,
+}
+
+impl DensityField {
+    pub fn new(w: usize, h: usize, d: usize) -> Self {
+        Self { w, h, d, data: vec![0.0; w * h * d] }
+    }
+
+    pub fn from_vol<C>(vol: &Vol<C>) -> Self where C: VoxelColor + Into<f64> {
+        let mut df = Self::new(vol.w, vol.h, vol.d);
+        for z in 0..vol.d {
+            for y in 0..vol.h {
+                for x in 0..vol.w {
+                    let p = Pos::new(x as PInt, y as PInt, z as PInt);
+                    let v : f64 = vol.at(p).color.into();
+                    df.set(p, v as f32);
+                }
+            }
+        }
+        df
+    }
+
+    fn idx(&self, pos: Pos) -> usize {
+        pos.z as usize * self.w * self.h + pos.y as usize * self.w + pos.x as usize
+    }
+
+    pub fn contains(&self, pos: Pos) -> bool {
+        (pos.x as usize) < self.w && (pos.y as usize) < self.h && (pos.z as usize) < self.d
+    }
+
+    pub fn at(&self, pos: Pos) -> f32 { self.data[self.idx(pos)] }
+
+    pub fn set(&mut self, pos: Pos, v: f32) {
+        let i = self.idx(pos);
+        self.data[i] = v;
+    }
+}
+
+/// Builds a smooth surface around the samples of a density field that
+/// are above `iso`, with the surface nets algorithm. `density` is called
+/// with positions inside of `dims`, samples outside count as empty.
+///
+/// Only the quads whose inner sample lies in the region at `min` with
+/// the extents `size` are built, so meshing regions that tile the volume
+/// gives seamless meshes. The vertices are relative to the lowest corner
+/// of the region with the y axis pointing up, the origin being
+/// `ChunkedVolume::chunk_origin_inv_y` for chunk regions. A vertex gets
+/// the `color` of the densest inner sample of its cell.
+pub fn mesh_surface_nets<D, M>(dims: [usize; 3], min: Pos, size: [usize; 3],
+                               iso: f32, density: D, color: M) -> MeshData
+    where D: Fn(Pos) -> f32, M: Fn(Pos) -> [f32; 4]
+{
+    let sample = |p: [i32; 3]| -> f32 {
+        for i in 0..3 {
+            if p[i] < 0 || p[i] >= dims[i] as i32 { return iso; }
+        }
+        density(Pos::new(p[0] as PInt, p[1] as PInt, p[2] as PInt))
+    };
+
+    let min  = [min.x as i32, min.y as i32, min.z as i32];
+    let top  = (min[1] + size[1] as i32) as f32;
+    let mut md = MeshData::new();
+    // Vertex index by the lowest sample of its cell:
+    let mut cells : std::collections::HashMap<[i32; 3], u32> =
+        std::collections::HashMap::new();
+
+    let mut cell_vertex = |md: &mut MeshData, s: [i32; 3]| -> u32 {
+        if let Some(idx) = cells.get(&s) { return *idx; }
+
+        let mut vals = [0.0; 8];
+        for (i, v) in vals.iter_mut().enumerate() {
+            *v = sample([s[0] + (i & 1) as i32, s[1] + (i >> 1 & 1) as i32, s[2] + (i >> 2) as i32]);
+        }
+
+        let mut sum   = [0.0; 3];
+        let mut count = 0;
+        for i in 0..8 {
+            for axis in 0..3 {
+                let j = i | 1 << axis;
+                if i == j || (vals[i] > iso) == (vals[j] > iso) { continue; }
+
+                let t = (iso - vals[i]) / (vals[j] - vals[i]);
+                for k in 0..3 {
+                    let c = (i >> k & 1) as f32;
+                    sum[k] += if k == axis { c + t } else { c };
+                }
+                count += 1;
+            }
+        }
+
+        let mut densest = None;
+        for i in 0..8 {
+            if vals[i] <= iso { continue; }
+            match densest {
+                Some(d) if vals[d] >= vals[i] => (),
+                _ => densest = Some(i),
+            }
+        }
+        let clr =
+            match densest {
+                Some(i) => color(Pos::new(
+                    (s[0] + (i & 1) as i32) as PInt,
+                    (s[1] + (i >> 1 & 1) as i32) as PInt,
+                    (s[2] + (i >> 2) as i32) as PInt)),
+                None => [0.0, 0.0, 0.0, 1.0],
+            };
+
+        // The samples are at the voxel centers:
+        let n = count.max(1) as f32;
+        let p = [
+            s[0] as f32 + 0.5 + sum[0] / n,
+            s[1] as f32 + 0.5 + sum[1] / n,
+            s[2] as f32 + 0.5 + sum[2] / n,
+        ];
+
+        let idx = md.positions.len() as u32;
+        md.positions.push([p[0] - min[0] as f32, top - p[1], p[2] - min[2] as f32]);
+        md.normals.push([0.0; 3]);
+        md.colors.push(clr);
+        md.uvs.push([0.0, 0.0]);
+        md.uvs2.push([1.0, 1.0]);
+        cells.insert(s, idx);
+        idx
+    };
+
+    for z in min[2]..(min[2] + size[2] as i32) {
+        for y in min[1]..(min[1] + size[1] as i32) {
+            for x in min[0]..(min[0] + size[0] as i32) {
+                let q = [x, y, z];
+                if sample(q) <= iso { continue; }
+
+                for axis in 0..3 {
+                    for dir in [-1, 1].iter() {
+                        let mut nb = q;
+                        nb[axis] += dir;
+                        if sample(nb) > iso { continue; }
+
+                        // The edge from p to p + 1 along the axis, and
+                        // the cells around it, counter clockwise seen
+                        // from the positive axis in volume coordinates:
+                        let mut p = q;
+                        if *dir < 0 { p = nb; }
+                        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                        let mut quad = [0; 4];
+                        for (i, (du, dv)) in [(-1, -1), (0, -1), (0, 0), (-1, 0)].iter().enumerate() {
+                            let mut s = p;
+                            s[u] += du;
+                            s[v] += dv;
+                            quad[i] = cell_vertex(&mut md, s);
+                        }
+                        // The y axis flip of the mesh turns that clockwise
+                        // seen from outside, if outside is the positive axis:
+                        if *dir < 0 { quad.reverse(); }
+
+                        for tri in [[quad[0], quad[1], quad[2]], [quad[0], quad[2], quad[3]]].iter() {
+                            let a = md.positions[tri[0] as usize];
+                            let b = md.positions[tri[1] as usize];
+                            let c = md.positions[tri[2] as usize];
+                            let e1 = v3_sub(c, a);
+                            let e2 = v3_sub(b, a);
+                            let n = [e1[1] * e2[2] - e1[2] * e2[1],
+                                     e1[2] * e2[0] - e1[0] * e2[2],
+                                     e1[0] * e2[1] - e1[1] * e2[0]];
+                            for idx in tri.iter() {
+                                let vn = &mut md.normals[*idx as usize];
+                                for k in 0..3 { vn[k] += n[k]; }
+                                md.indices.push(*idx);
+                                md.collision_tris.push(md.positions[*idx as usize]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for n in md.normals.iter_mut() {
+        let len = v3_dot(*n, *n).sqrt();
+        if len > 0.0 {
+            for k in 0..3 { n[k] /= len; }
+        }
+    }
+
+    md
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_octree_n1_filled() {
+        let mut t : Octree<u8> = Octree::new_from_size(2);
+        t.fill(0, 0, 0, 2, 2, 2, 10.into());
+        t.recompute();
+
+        let mut log = vec![];
+        t.draw(&mut |size, pos, v| {
+            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
+        });
+
+        assert_eq!(log[0], (2, (0, 0, 0), 10, 63));
+    }
+
+    #[test]
+    fn check_octree_n1_partial_minus_1() {
+        let mut t : Octree<u8> = Octree::new_from_size(4);
+        t.fill(0, 0, 0, 4, 4, 4, 10.into());
+        t.set(0, 0, 0, 12.into());
+        t.recompute();
+
+        let mut log = vec![];
+        t.draw(&mut |size, pos, v| {
+            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
+        });
+        for l in log.iter() { println!("E {:?} {:x}", l, l.3); }
+        for (i, n) in t.nodes.iter().enumerate() {
+            println!("AT {}: {:?}", i, n);
+        }
+
+        assert_eq!(log[0], (1, (0, 0, 0), 12, F_FRONT | F_LEFT | F_TOP));
+        assert_eq!(log[1], (1, (1, 0, 0), 10, F_FRONT | F_TOP));
+        assert_eq!(log[2], (1, (0, 1, 0), 10, F_FRONT | F_LEFT));
+        assert_eq!(log[3], (1, (1, 1, 0), 10, F_FRONT));
+        assert_eq!(log[4], (1, (0, 0, 1), 10, F_LEFT | F_TOP));
+        assert_eq!(log[5], (1, (1, 0, 1), 10, F_TOP));
+        assert_eq!(log[6], (1, (0, 1, 1), 10, F_LEFT));
+        assert_eq!(log[7], (1, (1, 1, 1), 10, 0x00));
+        assert_eq!(log[8], (2, (2, 0, 0), 10, F_RIGHT | F_FRONT | F_TOP));
+    }
+
+    #[test]
+    fn check_octree_n2_filled() {
+        let v : Vol<u8> = Vol::new(4);
+        let mut t : Octree<u8> = Octree::new(64, v);
+        t.fill(0, 0, 0, 4, 4, 4, 10.into());
+        let n = t.recompute();
+//        println!("NODES: {:?}", t.nodes);
+
+        assert_eq!(n.voxel.unwrap().color, 10);
+        assert_eq!(n.voxel.unwrap().faces, 0x3F);
+        assert_eq!(t.nodes[0].voxel.unwrap().color, 10);
+        assert_eq!(t.nodes[0].empty, false);
+
+        let mut first : Option<(usize, Pos, Voxel<u8>)> = None;
+        let mut last : Option<(usize, Pos, Voxel<u8>)> = None;
+        t.draw(&mut |size, pos, v| {
+            if first.is_none() { first = Some((size, *pos, v)); }
+            last = Some((size, *pos, v));
+        });
+
+        let first = first.unwrap();
+        assert_eq!((first.0, first.1.x, first.1.y, first.1.z), (4, 0, 0, 0));
+        assert_eq!(first.2.color, 10);
+        assert_eq!(first.2.faces, 0x3f); // all faces
+
+        let last = last.unwrap();
+        assert_eq!((last.0, last.1.x, last.1.y, last.1.z), (4, 0, 0, 0));
+        assert_eq!(last.2.color, 10);
+        assert_eq!(last.2.faces, 0x3f); // all faces
+    }
+
+    #[test]
+    fn check_octree_n2_broken() {
+        let v : Vol<u8> = Vol::new(4);
+        let mut t : Octree<u8> = Octree::new(64, v);
+        t.fill(0, 0, 0, 4, 4, 4, 10.into());
+        t.set(3, 3, 3, 20.into());
+        let n = t.recompute();
+//        println!("NODES: {:?}", t.nodes);
+
+        assert_eq!(n.voxel, None);
+        // first layer:
+        assert_eq!(t.nodes[1].voxel.unwrap().color, 10);
+        assert_eq!(t.nodes[1].empty, false);
+        assert_eq!(t.nodes[2].voxel.unwrap().color, 10);
+        assert_eq!(t.nodes[2].empty, false);
+        assert_eq!(t.nodes[3].voxel.unwrap().color, 10);
+        assert_eq!(t.nodes[3].empty, false);
+        assert_eq!(t.nodes[4].voxel.unwrap().color, 10);
+        assert_eq!(t.nodes[4].empty, false);
+
+        // second layer:
+        assert_eq!(t.nodes[5].voxel.unwrap().color, 10);
+        assert_eq!(t.nodes[5].empty, false);
+        assert_eq!(t.nodes[6].voxel.unwrap().color, 10);
+        assert_eq!(t.nodes[6].empty, false);
+        assert_eq!(t.nodes[7].voxel.unwrap().color, 10);
+        assert_eq!(t.nodes[7].empty, false);
+        assert_eq!(t.nodes[8].voxel, None);
+        assert_eq!(t.nodes[8].empty, false);
+
+        let mut first : Option<(usize, Pos, Voxel<u8>)> = None;
+        let mut last : Option<(usize, Pos, Voxel<u8>)> = None;
+        t.draw(&mut |size, pos, v| {
+//            println!("DRAWING: size={}, pos={:?} v={:?}", size, pos, v);
+            if first.is_none() { first = Some((size, *pos, v)); }
+            last = Some((size, *pos, v));
+        });
+
+        let first = first.unwrap();
+        assert_eq!((first.0, first.1.x, first.1.y, first.1.z), (2, 0, 0, 0));
+        assert_eq!(first.2.color, 10);
+        assert_eq!(first.2.faces, F_FRONT | F_LEFT | F_TOP);
+
+        let last = last.unwrap();
+        assert_eq!((last.0, last.1.x, last.1.y, last.1.z), (1, 3, 3, 3));
+        assert_eq!(last.2.color, 20);
+        assert_eq!(last.2.faces, F_BACK | F_RIGHT | F_BOTTOM);
+    }
+
+    #[test]
+    fn check_n3_filled() {
+        let v : Vol<u8> = Vol::new(8);
+        let mut t : Octree<u8> = Octree::new(8 * 8 * 8, v);
+        t.fill(0, 0, 0, 8, 8, 8, 11.into());
+        let n = t.recompute();
+
+        assert_eq!(n.voxel.unwrap().color, 11);
+        assert_eq!(n.voxel.unwrap().faces, 0x3F);
+        assert_eq!(t.nodes[0].voxel.unwrap().color, 11);
+        assert_eq!(t.nodes[0].empty, false);
+
+        let mut first : Option<(usize, Pos, Voxel<u8>)> = None;
+        let mut last : Option<(usize, Pos, Voxel<u8>)> = None;
+        t.draw(&mut |size, pos, v| {
+            if first.is_none() { first = Some((size, *pos, v)); }
+            last = Some((size, *pos, v));
+        });
+
+        let first = first.unwrap();
+        assert_eq!((first.0, first.1.x, first.1.y, first.1.z), (8, 0, 0, 0));
+        assert_eq!(first.2.color, 11);
+        assert_eq!(first.2.faces, 0x3f); // all faces
+
+        let last = last.unwrap();
+        assert_eq!((last.0, last.1.x, last.1.y, last.1.z), (8, 0, 0, 0));
+        assert_eq!(last.2.color, 11);
+        assert_eq!(last.2.faces, 0x3f); // all faces
+    }
+
+    #[test]
+    fn check_n256_filled() {
+        let v : Vol<u8> = Vol::new(256);
+        let mut t : Octree<u8> = Octree::new(256 * 256 * 256, v);
+        let d = std::time::Instant::now();
+        t.fill(0, 0, 0, 256, 256, 256, 11.into());
+        eprintln!("TF={}",d.elapsed().as_millis());
+        let n = t.recompute();
+
+        eprintln!("T={}",d.elapsed().as_millis());
+
+        assert_eq!(n.voxel.unwrap().color, 11);
+        assert_eq!(n.voxel.unwrap().faces, 0x3F);
+        assert_eq!(t.nodes[0].voxel.unwrap().color, 11);
+        assert_eq!(t.nodes[0].empty, false);
+
+        let mut first : Option<(usize, Pos, Voxel<u8>)> = None;
+        let mut last : Option<(usize, Pos, Voxel<u8>)> = None;
+        t.draw(&mut |size, pos, v| {
+            if first.is_none() { first = Some((size, *pos, v)); }
+            last = Some((size, *pos, v));
+        });
+
+        let first = first.unwrap();
+        assert_eq!((first.0, first.1.x, first.1.y, first.1.z), (256, 0, 0, 0));
+        assert_eq!(first.2.color, 11);
+        assert_eq!(first.2.faces, 0x3f); // all faces
+
+        let last = last.unwrap();
+        assert_eq!((last.0, last.1.x, last.1.y, last.1.z), (256, 0, 0, 0));
+        assert_eq!(last.2.color, 11);
+        assert_eq!(last.2.faces, 0x3f); // all faces
+    }
+
+    #[test]
+    fn check_size8_1() {
+        let mut ot : Octree<u8> = Octree::new_from_size(8);
+        ot.fill(0, 0, 0, 4, 4, 4, 1.into());
+        ot.fill(4, 4, 4, 4, 4, 4, 2.into());
+        ot.recompute();
+
+        let mut log = vec![];
+        ot.draw(&mut |size, pos, v| {
+            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
+        });
+        for l in log.iter() { println!("E {:?} {:x}", l, l.3); }
+
+        assert_eq!(log[0], (4, (0, 0, 0), 1, 63));
+        assert_eq!(log[1], (4, (4, 4, 4), 2, 63));
+        assert_eq!(log.len(), 2);
+    }
+
+    #[test]
+    fn check_smal() {
+        let mut ot : Octree<u8> = Octree::new_from_size(4);
+        ot.set(2, 2, 2, 1.into());
+        ot.recompute();
+        let mut log = vec![];
+        ot.draw(&mut |size, pos, v| {
+            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
+        });
+//
+//        for (i, n) in ot.nodes.iter().enumerate() {
+//            println!("AT {}: {:?}", i, n);
+//        }
+
+
+        assert_eq!(log[0], (1, (2, 2, 2), 1, 63));
+    }
+
+    #[test]
+    fn check_update_dirty_matches_recompute() {
+        let mut ot : Octree<u8> = Octree::new_from_size(16);
+        ot.fill(0, 0, 0, 16, 8, 16, 3.into());
+        ot.fill(4, 8, 4, 8, 4, 8, 5.into());
+        ot.recompute();
+
+        let mut seed : u32 = 0x1234_5678;
+        for i in 0..200 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let x = ((seed >> 8)  % 16) as u16;
+            let y = ((seed >> 12) % 16) as u16;
+            let z = ((seed >> 16) % 16) as u16;
+            let c = if i % 3 == 0 { 0 } else { ((seed >> 20) % 4) as u8 };
+            ot.set(x, y, z, c.into());
+            if i % 5 == 0 {
+                ot.fill(x / 2, y / 2, z / 2, 2, 3, 1, c.into());
+            }
+
+            if i % 7 == 0 {
+                ot.update_dirty();
+                let mut full = ot.clone();
+                full.recompute();
+                assert_eq!(ot.nodes, full.nodes, "nodes at edit {}", i);
+                assert_eq!(ot.vol, full.vol, "voxels at edit {}", i);
+            }
+        }
+    }
+
+    #[test]
+    fn check_update_dirty_reports_path() {
+        let mut ot : Octree<u8> = Octree::new_from_size(8);
+        ot.fill(0, 0, 0, 8, 8, 8, 1.into());
+        ot.recompute();
+        assert!(!ot.is_dirty());
+
+        ot.set(7, 7, 7, 0.into());
+        assert!(ot.is_dirty());
+        let changed = ot.update_dirty();
+        assert!(!ot.is_dirty());
+
+        let mut sizes : Vec<usize> =
+            changed.iter()
+                   .filter(|n| n.tree_pos.level < 3)
+                   .map(|n| 8 >> n.tree_pos.level)
+                   .collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![2, 4, 8]);
+        assert!(changed.iter().any(|n| n.pos == Pos::new(7, 7, 7) && n.empty));
+        // The 3 neighbours of the removed voxel got a new face each:
+        assert_eq!(changed.iter().filter(|n| n.tree_pos.level == 3).count(), 4);
+        assert_eq!(ot.root().voxel, None);
+        assert!(!ot.root().empty);
+
+        assert!(ot.update_dirty().is_empty());
+    }
+
+    #[test]
+    fn check_update_dirty_computes_new_tree() {
+        // A tree built from a volume was never computed, even though
+        // nothing was written to it since:
+        let mut vol : Vol<u8> = Vol::new(4);
+        vol.fill(0, 0, 0, 4, 4, 4, 7.into());
+        let mut ot = Octree::new(0, vol);
+        assert!(!ot.is_dirty());
+
+        let changed = ot.update_dirty();
+        assert_eq!(changed.len(), 1);
+        assert!(!ot.root().empty);
+        assert_eq!(ot.root().voxel.map(|v| (v.color, v.faces)), Some((7, 63)));
+
+        let mut log = vec![];
+        ot.draw(&mut |size, pos, v| {
+            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
+        });
+        assert_eq!(log, vec![(4, (0, 0, 0), 7, 63)]);
+
+        assert!(ot.update_dirty().is_empty());
+    }
+
+    #[test]
+    fn check_vol_serialize_roundtrip() {
+        let mut v : Vol<u8> = Vol::new(16);
+        v.fill(2, 3, 4, 5, 6, 7, 9.into());
+        v.set(15, 15, 15, 200.into());
+
+        let data = v.serialize();
+        assert_eq!(&data[0..4], b"vox\x03");
+        assert!(data.len() < 16 * 16 * 16 / 8);
+
+        let mut v2 : Vol<u8> = Vol::new(1);
+        v2.deserialize(&data).unwrap();
+        assert_eq!((v2.w, v2.h, v2.d), (16, 16, 16));
+        assert_eq!(v2, v);
+
+        // Version 2 only differs in the size field:
+        let mut v2_data = b"vox\x02".to_vec();
+        v2_data.extend_from_slice(&16_u32.to_le_bytes());
+        v2_data.extend_from_slice(&data[16..(data.len() - 4)]);
+        let crc = crc32(&v2_data);
+        v2_data.extend_from_slice(&crc.to_le_bytes());
+
+        let mut v3 : Vol<u8> = Vol::new(1);
+        v3.deserialize(&v2_data).unwrap();
+        assert_eq!(v3, v);
+    }
+
+    #[test]
+    fn check_vol_serialize_256() {
+        let mut v : Vol<u8> = Vol::new(256);
+        v.fill(0, 0, 0, 256, 10, 256, 1.into());
+        v.set(255, 255, 255, 3.into());
+
+        let data = v.serialize();
+        let mut v2 : Vol<u8> = Vol::new(1);
+        v2.deserialize(&data).unwrap();
+        assert_eq!((v2.w, v2.h, v2.d), (256, 256, 256));
+        assert_eq!(*v2.color_at(Pos::new(255, 255, 255)), 3);
+        assert_eq!(*v2.color_at(Pos::new(255, 9, 255)), 1);
+        assert_eq!(*v2.color_at(Pos::new(255, 10, 255)), 0);
+    }
+
+    #[test]
+    fn check_vol_deserialize_v1() {
+        let mut data = vec![b'v', b'o', b'x', 1, 2];
+        data.extend_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+
+        let mut v : Vol<u8> = Vol::new(1);
+        v.deserialize(&data).unwrap();
+        assert_eq!((v.w, v.h, v.d), (2, 2, 2));
+        assert_eq!(*v.color_at(Pos::new(1, 0, 0)), 1);
+        assert_eq!(*v.color_at(Pos::new(1, 1, 1)), 7);
+
+        assert_eq!(
+            v.deserialize(&data[0..8]),
+            Err(VolError::Truncated { expected: 13, got: 8 }));
+    }
+
+    #[test]
+    fn check_vol_deserialize_errors() {
+        let mut v : Vol<u8> = Vol::new(4);
+        v.fill(0, 0, 0, 4, 2, 4, 5.into());
+        let data = v.serialize();
+
+        let mut v2 : Vol<u8> = Vol::new(1);
+        assert_eq!(v2.deserialize(&[]),
+                   Err(VolError::Truncated { expected: 4, got: 0 }));
+        assert_eq!(v2.deserialize(b"abc\x02"), Err(VolError::BadMagic));
+        assert_eq!(v2.deserialize(b"vox\x09"),
+                   Err(VolError::UnsupportedVersion(9)));
+        assert!(match v2.deserialize(&data[0..(data.len() - 1)]) {
+            Err(VolError::Truncated { .. }) => true,
+            _ => false,
+        });
+
+        let mut corrupt = data.clone();
+        corrupt[13] ^= 0x40;
+        assert!(match v2.deserialize(&corrupt) {
+            Err(VolError::ChecksumMismatch { .. }) => true,
+            _ => false,
+        });
+        assert_eq!((v2.w, v2.h, v2.d), (1, 1, 1));
+
+        // Valid checksum, but the runs don't cover the volume:
+        let mut short = b"vox\x02".to_vec();
+        short.extend_from_slice(&4_u32.to_le_bytes());
+        short.extend_from_slice(&2_u32.to_le_bytes());
+        short.extend_from_slice(&[10, 1]);
+        let crc = crc32(&short);
+        short.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(v2.deserialize(&short),
+                   Err(VolError::VoxelCountMismatch { expected: 64, got: 10 }));
+    }
+
+    #[test]
+    fn check_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn check_raycast_faces() {
+        let mut ot : Octree<u8> = Octree::new_from_size(8);
+        ot.set(4, 2, 3, 7.into());
+        ot.recompute();
+
+        let hit = ot.raycast([0.5, 2.5, 3.5], [1.0, 0.0, 0.0], 100.0).unwrap();
+        assert_eq!(hit.pos, Pos::new(4, 2, 3));
+        assert_eq!(hit.voxel.color, 7);
+        assert_eq!(hit.face, F_LEFT);
+        assert!((hit.dist - 3.5).abs() < 1e-5);
+
+        let hit = ot.raycast([4.5, 2.5, 10.0], [0.0, 0.0, -2.0], 100.0).unwrap();
+        assert_eq!(hit.pos, Pos::new(4, 2, 3));
+        assert_eq!(hit.face, F_BACK);
+        assert!((hit.dist - 6.0).abs() < 1e-5);
+
+        let hit = ot.raycast([4.5, -3.0, 3.5], [0.0, 1.0, 0.0], 100.0).unwrap();
+        assert_eq!(hit.face, F_TOP);
+        assert!((hit.dist - 5.0).abs() < 1e-5);
+
+        let hit = ot.raycast([4.2, 2.9, 3.1], [0.0, 1.0, 0.0], 100.0).unwrap();
+        assert_eq!(hit.face, 0);
+        assert_eq!(hit.dist, 0.0);
+
+        assert_eq!(ot.raycast([0.5, 2.5, 3.5], [1.0, 0.0, 0.0], 3.0), None);
+        assert_eq!(ot.raycast([0.5, 2.5, 3.5], [-1.0, 0.0, 0.0], 100.0), None);
+        assert_eq!(ot.raycast([0.5, 2.5, 3.5], [0.0, 0.0, 0.0], 100.0), None);
+        assert_eq!(ot.raycast([0.5, 3.5, 3.5], [1.0, 0.0, 0.0], 100.0), None);
+    }
+
+    #[test]
+    fn check_raycast_against_sampling() {
+        let mut ot : Octree<u8> = Octree::new_from_size(16);
+        ot.fill(0, 0, 0, 8, 8, 8, 1.into());
+        ot.fill(10, 3, 2, 3, 9, 4, 2.into());
+        ot.set(12, 14, 13, 3.into());
+        ot.set(8, 8, 8, 4.into());
+        ot.recompute();
+
+        let solid = |x: f64, y: f64, z: f64| {
+            if x < 0.0 || y < 0.0 || z < 0.0
+               || x >= 16.0 || y >= 16.0 || z >= 16.0 { return false; }
+            ot.get(x as u16, y as u16, z as u16).color != 0
+        };
+
+        let mut seed : u32 = 0xBEEF;
+        let mut rnd = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            ((seed >> 8) % 10_000) as f64 / 10_000.0
+        };
+
+        for _ in 0..500 {
+            let o = [rnd() * 24.0 - 4.0, rnd() * 24.0 - 4.0, rnd() * 24.0 - 4.0];
+            let target = [rnd() * 16.0, rnd() * 16.0, rnd() * 16.0];
+            let d = [target[0] - o[0], target[1] - o[1], target[2] - o[2]];
+            let len = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+            let d = [d[0] / len, d[1] / len, d[2] / len];
+
+            let hit = ot.raycast(
+                [o[0] as f32, o[1] as f32, o[2] as f32],
+                [d[0] as f32, d[1] as f32, d[2] as f32],
+                60.0);
+
+            let mut first = None;
+            let mut t = 0.0;
+            while t < 60.0 {
+                if solid(o[0] + d[0] * t, o[1] + d[1] * t, o[2] + d[2] * t) {
+                    first = Some(t);
+                    break;
+                }
+                t += 0.001;
+            }
+
+            match (hit, first) {
+                (Some(h), Some(t)) => {
+                    assert!((h.dist as f64 - t).abs() < 0.01,
+                            "o={:?} d={:?} hit={:?} t={}", o, d, h, t);
+                },
+                (None, None) => (),
+                (h, t) => {
+                    panic!("o={:?} d={:?} hit={:?} t={:?}", o, d, h, t);
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn check_non_cubic_vol() {
+        let mut v : Vol<u8> = Vol::new_dims(200, 40, 90);
+        v.fill(10, 0, 80, 190, 40, 10, 4.into());
+        v.set(199, 39, 89, 9.into());
+        assert!(v.contains(Pos::new(199, 39, 89)));
+        assert!(!v.contains(Pos::new(199, 40, 89)));
+
+        let data = v.serialize();
+        let mut v2 : Vol<u8> = Vol::new(1);
+        v2.deserialize(&data).unwrap();
+        assert_eq!((v2.w, v2.h, v2.d), (200, 40, 90));
+        assert_eq!(v2, v);
+    }
+
+    #[test]
+    fn check_non_cubic_octree() {
+        let mut ot : Octree<u8> = Octree::new_from_dims(5, 3, 7);
+        assert_eq!(ot.size, 8);
+        ot.fill(0, 0, 0, 5, 3, 7, 2.into());
+        ot.recompute();
+
+        let mut log = vec![];
+        let mut count = 0;
+        ot.draw(&mut |size, pos, v| {
+            assert!(ot.vol.contains(*pos));
+            assert!(ot.vol.contains(pos.offs(
+                size as u16 - 1, size as u16 - 1, size as u16 - 1)));
+            count += size * size * size;
+            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
+        });
+        assert_eq!(count, 5 * 3 * 7);
+        assert_eq!(log[0], (2, (0, 0, 0), 2, F_LEFT | F_TOP | F_FRONT));
+        assert!(log.contains(&(1, (4, 2, 6), 2, F_RIGHT | F_BOTTOM | F_BACK)));
+
+        // Voxels at the border of the volume got their outer faces:
+        assert_eq!(ot.get(4, 1, 3).faces, F_RIGHT);
+        assert_eq!(ot.get(2, 2, 3).faces, F_BOTTOM);
+        assert_eq!(ot.get_inv_y(2, 0, 3).faces, F_BOTTOM);
+
+        ot.set(4, 2, 6, 0.into());
+        ot.update_dirty();
+        let mut full = ot.clone();
+        full.recompute();
+        assert_eq!(ot.nodes, full.nodes);
+
+        let hit = ot.raycast([10.0, 1.5, 3.5], [-1.0, 0.0, 0.0], 20.0).unwrap();
+        assert_eq!(hit.pos, Pos::new(4, 1, 3));
+        assert_eq!(hit.face, F_RIGHT);
+        assert!((hit.dist - 5.0).abs() < 1e-5);
+        assert_eq!(ot.raycast([2.5, 5.0, 3.5], [0.0, 1.0, 0.0], 20.0), None);
+    }
+
+    #[test]
+    fn check_octree_queries() {
+        let mut ot : Octree<u8> = Octree::new_from_dims(12, 9, 16);
+        ot.fill(2, 0, 0, 8, 8, 8, 3.into());
+        ot.fill(0, 4, 8, 12, 2, 8, 5.into());
+        ot.set(11, 8, 15, 7.into());
+        ot.set(4, 4, 4, 0.into());
+        ot.recompute();
+
+        let mut drawn = vec![];
+        ot.draw(&mut |size, pos, v| drawn.push((size, *pos, v)));
+        let leaves : Vec<(usize, Pos, Voxel<u8>)> = ot.leaves().collect();
+        assert_eq!(leaves, drawn);
+        assert!(leaves.iter().any(|(size, _, _)| *size > 1));
+
+        let mut hist  = vec![0; 256];
+        let mut faces = 0;
+        let mut min   = Pos::new(1000, 1000, 1000);
+        let mut max   = Pos::new(0, 0, 0);
+        for z in 0..16 {
+            for y in 0..9 {
+                for x in 0..12 {
+                    let v = ot.get(x, y, z);
+                    hist[v.color as usize] += 1;
+                    if v.color == 0 { continue; }
+                    faces += v.faces.count_ones() as usize;
+                    min = Pos::new(min.x.min(x), min.y.min(y), min.z.min(z));
+                    max = Pos::new(max.x.max(x), max.y.max(y), max.z.max(z));
+                }
+            }
+        }
+
+        assert_eq!(ot.color_histogram(), hist);
+        assert_eq!(ot.color_histogram()[7], 1);
+        assert_eq!(ot.exposed_face_count(), faces);
+        assert_eq!(ot.solid_bounds(), Some((min, max)));
+        assert_eq!(ot.solid_bounds(), Some((Pos::new(0, 0, 0), Pos::new(11, 8, 15))));
+
+        let mut empty : Octree<u8> = Octree::new_from_size(4);
+        empty.recompute();
+        assert_eq!(empty.leaves().count(), 0);
+        assert_eq!(empty.solid_bounds(), None);
+        assert_eq!(empty.color_histogram()[0], 64);
+        assert_eq!(empty.exposed_face_count(), 0);
+    }
+
+    #[test]
+    fn check_draw_lod() {
+        let mut ot : Octree<u8> = Octree::new_from_size(8);
+        ot.fill(0, 0, 0, 4, 4, 4, 1.into());
+        ot.fill(4, 0, 0, 4, 4, 4, 2.into());
+        ot.set(5, 1, 1, 3.into());
+        ot.set(6, 2, 2, 0.into());
+        ot.set(1, 5, 1, 4.into());
+        ot.recompute();
+
+        let mut full = vec![];
+        ot.draw(&mut |size, pos, v| full.push((size, *pos, v)));
+        let mut lod = vec![];
+        ot.draw_lod(10, &mut |size, pos, v| lod.push((size, *pos, v)));
+        assert_eq!(lod, full);
+
+        let mut lod = vec![];
+        ot.draw_lod(1, &mut |size, pos, v| {
+            lod.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
+        });
+        // The node with the single voxel of color 4 is mostly empty:
+        assert_eq!(lod, vec![
+            (4, (0, 0, 0), 1, F_FRONT | F_LEFT | F_TOP | F_BOTTOM | F_BACK),
+            (4, (4, 0, 0), 2, F_FRONT | F_RIGHT | F_TOP | F_BOTTOM | F_BACK),
+        ]);
+
+        let mut lod = vec![];
+        ot.draw_lod(0, &mut |size, _pos, _v| lod.push(size));
+        assert!(lod.is_empty());
+
+        ot.fill(0, 0, 4, 8, 8, 4, 2.into());
+        ot.recompute();
+        let mut lod = vec![];
+        ot.draw_lod(0, &mut |size, pos, v| {
+            lod.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
+        });
+        assert_eq!(lod, vec![(8, (0, 0, 0), 2, 0x3F)]);
+    }
+
+    #[test]
+    fn check_draw_lod_padding() {
+        let mut v : Vol<u8> = Vol::new_dims(20, 12, 20);
+        v.fill(0, 0, 0, 20, 12, 20, 1.into());
+        let mut ot = Octree::new(0, v);
+        ot.recompute();
+
+        for depth in 0..5 {
+            let mut voxels = 0;
+            ot.draw_lod(depth, &mut |size, pos, _v| {
+                assert!(pos.x as usize + size <= 20);
+                assert!(pos.y as usize + size <= 12);
+                assert!(pos.z as usize + size <= 20);
+                voxels += size * size * size;
+            });
+            assert_eq!(voxels, 20 * 12 * 20, "depth {}", depth);
+
+            let mut opts = MeshOptions::default();
+            opts.lod_depth = Some(depth);
+            let md = mesh_octree(&ot, opts, |_| [1.0; 4]);
+            for p in md.positions.iter() {
+                assert!(p[0] >= 0.0 && p[0] <= 20.0);
+                assert!(p[1] >= 0.0 && p[1] <= 12.0);
+                assert!(p[2] >= 0.0 && p[2] <= 20.0);
+            }
+        }
+    }
+
+    #[test]
+    fn check_islands() {
+        let mut v : Vol<u8> = Vol::new_dims(10, 6, 8);
+        v.fill(0, 0, 0, 10, 2, 8, 1.into());
+        // A pillar on top of the base, with a cap that is only
+        // connected diagonally:
+        v.fill(4, 2, 4, 1, 2, 1, 2.into());
+        v.set(5, 4, 4, 3.into());
+        v.set(5, 4, 5, 3.into());
+        // Detached block:
+        v.fill(8, 4, 6, 2, 2, 2, 4.into());
+
+        let islands = v.islands();
+        assert_eq!(islands.len(), 2);
+        let cap = islands.iter().find(|i| i.voxel_count == 2).unwrap();
+        assert_eq!(cap.offset, Pos::new(5, 4, 4));
+        assert_eq!((cap.vol.w, cap.vol.h, cap.vol.d), (1, 1, 2));
+        assert_eq!(cap.vol.at(Pos::new(0, 0, 1)).color, 3);
+        let block = islands.iter().find(|i| i.voxel_count == 8).unwrap();
+        assert_eq!(block.offset, Pos::new(8, 4, 6));
+        assert_eq!(*block.vol.color_at(Pos::new(1, 1, 1)), 4);
+
+        // Mining the pillar's base detaches the top of the pillar:
+        v.set(4, 2, 4, 0.into());
+        let islands = v.islands_near(Pos::new(4, 2, 4), 20);
+        assert_eq!(islands.len(), 1);
+        assert_eq!(islands[0].offset, Pos::new(4, 3, 4));
+        assert_eq!(islands[0].voxel_count, 1);
+
+        // Nothing is detached by mining a voxel of the base:
+        v.set(0, 0, 0, 0.into());
+        assert!(v.islands_near(Pos::new(0, 0, 0), 20).is_empty());
+
+        // Without a bound hit, the largest component stays:
+        let mut small : Vol<u8> = Vol::new(4);
+        small.fill(0, 0, 0, 3, 1, 1, 1.into());
+        small.set(3, 3, 3, 2.into());
+        small.set(1, 0, 0, 0.into());
+        let islands = small.islands_near(Pos::new(1, 0, 0), 100);
+        assert_eq!(islands.len(), 1);
+        assert_eq!(islands[0].voxel_count, 1);
+
+        // Mining a voxel of a ring leaves two seeds that are still
+        // connected. The second fill runs around the other way into the
+        // voxels of the first one, which got too big:
+        let mut ring : Vol<u8> = Vol::new_dims(12, 1, 12);
+        ring.fill(0, 0, 0, 12, 1, 12, 1.into());
+        ring.fill(1, 0, 1, 10, 1, 10, 0.into());
+        ring.set(0, 0, 6, 0.into());
+        let seeds = vec![Pos::new(0, 0, 5), Pos::new(0, 0, 7)];
+        for max in 5..50 {
+            let islands =
+                find_islands((12, 1, 12), seeds.clone(), Some(max), |p| {
+                    ring.at(p).color != 0
+                });
+            assert!(islands.is_empty(), "max {}", max);
+        }
+    }
+
+    #[test]
+    fn check_vol_csg() {
+        let mut a : Vol<u8> = Vol::new(4);
+        a.fill(0, 0, 0, 4, 2, 4, 1.into());
+
+        let mut b : Vol<u8> = Vol::new(2);
+        b.fill(0, 0, 0, 2, 2, 2, 2.into());
+        b.set(0, 0, 0, 0.into());
+
+        let mut u = a.clone();
+        u.union(&b, Pos::new(3, 1, 3));
+        assert_eq!(*u.color_at(Pos::new(3, 1, 3)), 1);
+        assert_eq!(*u.color_at(Pos::new(3, 2, 3)), 2);
+        assert_eq!(*u.color_at(Pos::new(2, 2, 2)), 0);
+
+        let mut sub = a.clone();
+        sub.subtract(&b, Pos::new(1, 1, 1));
+        assert_eq!(*sub.color_at(Pos::new(1, 1, 1)), 1);
+        assert_eq!(*sub.color_at(Pos::new(2, 1, 1)), 0);
+        assert_eq!(*sub.color_at(Pos::new(2, 1, 2)), 0);
+        assert_eq!(*sub.color_at(Pos::new(2, 0, 2)), 1);
+
+        let mut int = a.clone();
+        int.intersect(&b, Pos::new(1, 1, 1));
+        let solid : Vec<Pos> =
+            (0..64).map(|i| Pos::new(i % 4, (i / 4) % 4, i / 16))
+                   .filter(|p| *int.color_at(*p) != 0)
+                   .collect();
+        assert_eq!(solid, vec![
+            Pos::new(2, 1, 1), Pos::new(1, 1, 2), Pos::new(2, 1, 2)]);
+        assert_eq!(*int.color_at(Pos::new(2, 1, 1)), 1);
+
+        let mut p = a.clone();
+        p.paste(&b, Pos::new(0, 0, 0));
+        assert_eq!(*p.color_at(Pos::new(0, 0, 0)), 0);
+        assert_eq!(*p.color_at(Pos::new(1, 0, 0)), 2);
+
+        let c = a.copy_region(Pos::new(2, 1, 3), 5, 5, 5);
+        assert_eq!((c.w, c.h, c.d), (2, 3, 1));
+        assert_eq!(*c.color_at(Pos::new(1, 0, 0)), 1);
+        assert_eq!(*c.color_at(Pos::new(1, 1, 0)), 0);
+    }
+
+    #[test]
+    fn check_vol_transform() {
+        let mut v : Vol<u8> = Vol::new_dims(3, 2, 5);
+        v.set(2, 1, 4, 7.into());
+        v.set(0, 0, 1, 3.into());
+
+        for axis in [Axis::X, Axis::Y, Axis::Z].iter() {
+            assert_eq!(v.rotated(*axis, 4), v);
+            assert_eq!(v.rotated(*axis, 1).rotated(*axis, 3), v);
+            assert_eq!(v.mirrored(*axis).mirrored(*axis), v);
+        }
+
+        let r = v.rotated(Axis::Y, 1);
+        assert_eq!((r.w, r.h, r.d), (5, 2, 3));
+        assert_eq!(*r.color_at(Pos::new(0, 1, 2)), 7);
+        assert_eq!(*r.color_at(Pos::new(3, 0, 0)), 3);
+
+        let r = v.rotated(Axis::X, 1);
+        assert_eq!((r.w, r.h, r.d), (3, 5, 2));
+        assert_eq!(*r.color_at(Pos::new(2, 0, 1)), 7);
+
+        let r = v.rotated(Axis::Z, 1);
+        assert_eq!((r.w, r.h, r.d), (2, 3, 5));
+        assert_eq!(*r.color_at(Pos::new(0, 2, 4)), 7);
+
+        let m = v.mirrored(Axis::X);
+        assert_eq!(*m.color_at(Pos::new(0, 1, 4)), 7);
+        assert_eq!(*m.color_at(Pos::new(2, 0, 1)), 3);
+    }
+
+    fn count_solid(v: &Vol<u8>) -> usize {
+        v.data.iter().filter(|v| v.color != 0).count()
+    }
+
+    #[test]
+    fn check_shape_rasterize() {
+        let mut v : Vol<u8> = Vol::new(32);
+        let n = v.draw_shape(
+            &Shape::Sphere { center: [16.0, 16.0, 16.0], radius: 10.0 }, 1.into());
+        let expected = 4.0 / 3.0 * std::f32::consts::PI * 1000.0;
+        assert_eq!(n, count_solid(&v));
+        assert!((n as f32 - expected).abs() < expected * 0.05, "{}", n);
+        assert_eq!(*v.color_at(Pos::new(16, 16, 16)), 1);
+        assert_eq!(*v.color_at(Pos::new(16, 16, 27)), 0);
+
+        // An ellipsoid with equal radii is the sphere:
+        let mut e : Vol<u8> = Vol::new(32);
+        e.draw_shape(
+            &Shape::Ellipsoid { center: [16.0, 16.0, 16.0], radii: [10.0; 3] },
+            1.into());
+        assert_eq!(e, v);
+
+        // A convex polyhedron made of 6 planes is a box:
+        let mut p : Vol<u8> = Vol::new(8);
+        p.draw_shape(&Shape::Polyhedron { planes: vec![
+            ([ 1.0,  0.0,  0.0],  5.0), ([-1.0,  0.0,  0.0], -1.0),
+            ([ 0.0,  1.0,  0.0],  4.0), ([ 0.0, -1.0,  0.0], -2.0),
+            ([ 0.0,  0.0,  1.0],  8.0), ([ 0.0,  0.0, -1.0],  0.0),
+        ]}, 1.into());
+        let mut b : Vol<u8> = Vol::new(8);
+        b.fill(1, 2, 0, 4, 2, 8, 1.into());
+        assert_eq!(p, b);
+
+        let mut c : Vol<u8> = Vol::new(16);
+        let n = c.draw_shape(&Shape::Cylinder {
+            a: [8.0, 2.0, 8.0], b: [8.0, 12.0, 8.0], radius: 3.0 }, 1.into());
+        // 10 layers of a disc with radius 3:
+        assert_eq!(n, 10 * 32);
+        assert_eq!(*c.color_at(Pos::new(8, 1, 8)), 0);
+        assert_eq!(*c.color_at(Pos::new(8, 2, 8)), 1);
+
+        let mut cap : Vol<u8> = Vol::new(16);
+        let n_cap = cap.draw_shape(&Shape::Capsule {
+            a: [8.0, 2.0, 8.0], b: [8.0, 12.0, 8.0], radius: 3.0 }, 1.into());
+        assert!(n_cap > n);
+        assert_eq!(*cap.color_at(Pos::new(7, 0, 7)), 1);
+
+        let mut cone : Vol<u8> = Vol::new(16);
+        let n_cone = cone.draw_shape(&Shape::Cone {
+            base: [8.0, 2.0, 8.0], apex: [8.0, 12.0, 8.0], radius: 3.0 }, 1.into());
+        assert!(n_cone < n / 2 && n_cone > n / 4, "{}", n_cone);
+        assert_eq!(*cone.color_at(Pos::new(7, 2, 7)), 1);
+        assert_eq!(*cone.color_at(Pos::new(7, 10, 5)), 0);
+
+        // A thin diagonal line is 26-connected and hits both ends:
+        let mut l : Vol<u8> = Vol::new(16);
+        l.draw_shape(&Shape::Line {
+            a: [0.5, 0.5, 0.5], b: [15.5, 7.5, 3.5], thickness: 1.0 }, 1.into());
+        assert_eq!(*l.color_at(Pos::new(0, 0, 0)), 1);
+        assert_eq!(*l.color_at(Pos::new(15, 7, 3)), 1);
+        for x in 0..16 {
+            let col : usize =
+                (0..16).map(|z| (0..16).filter(|y|
+                    *l.color_at(Pos::new(x, *y, z)) != 0).count()).sum();
+            assert!(col >= 1, "gap at x={}", x);
+        }
+
+        // The blend function sees the old voxel:
+        let mut r : Vol<u8> = Vol::new(4);
+        r.fill(0, 0, 0, 4, 4, 4, 3.into());
+        r.rasterize(
+            &Shape::Sphere { center: [2.0, 2.0, 2.0], radius: 1.0 },
+            |dst| (dst.color + 1).into());
+        assert_eq!(*r.color_at(Pos::new(1, 1, 1)), 4);
+        assert_eq!(*r.color_at(Pos::new(0, 0, 0)), 3);
+    }
+
+    #[test]
+    fn check_magica_vox() {
+        let mut v : Vol<u8> = Vol::new_dims(3, 4, 5);
+        v.set(0, 0, 0, 7.into());
+        v.set(2, 3, 4, 1.into());
+        v.set(1, 2, 3, 255.into());
+
+        let mut palette = [[0; 4]; 256];
+        palette[7]   = [10, 20, 30, 255];
+        palette[255] = [1, 2, 3, 4];
+
+        let data = v.to_magica_vox(Some(&palette)).unwrap();
+        assert_eq!(&data[0..4], b"VOX ");
+
+        let m = MagicaVoxModel::parse(&data).unwrap();
+        assert_eq!((m.w, m.h, m.d), (3, 4, 5));
+        assert_eq!(m.voxels.len(), 3);
+        assert_eq!(m.palette.unwrap()[7],   [10, 20, 30, 255]);
+        assert_eq!(m.palette.unwrap()[255], [1, 2, 3, 4]);
+        assert_eq!(m.to_vol::<u8>(), v);
+
+        // MagicaVoxel is z-up, voxel (0, 0, 0) is at the top of the model:
+        let xyzi = data.windows(4).position(|w| w == b"XYZI").unwrap();
+        assert_eq!(&data[(xyzi + 16)..(xyzi + 20)], &[0, 0, 3, 7]);
+
+        // Clipping writes what fits and reports the sizes:
+        let mut small : Vol<u8> = Vol::new(3);
+        assert_eq!(m.write_into(&mut small), Err(VolError::ModelClipped {
+            model: (3, 4, 5), vol: (3, 3, 3) }));
+        assert_eq!(*small.color_at(Pos::new(0, 0, 0)), 7);
+        assert_eq!(count_solid(&small), 1);
+
+        let nopal = MagicaVoxModel::parse(&v.to_magica_vox(None).unwrap()).unwrap();
+        assert!(nopal.palette.is_none());
+
+        assert_eq!(MagicaVoxModel::parse(b"VOX").unwrap_err(),
+                   VolError::Truncated { expected: 8, got: 3 });
+        assert_eq!(MagicaVoxModel::parse(b"vox\x03....").unwrap_err(),
+                   VolError::NotMagicaVox);
+        assert!(MagicaVoxModel::parse(&data[0..(data.len() - 10)]).is_err());
+
+        let mut no_xyzi = b"VOX \x96\0\0\0MAIN\0\0\0\0\x18\0\0\0".to_vec();
+        push_vox_chunk(&mut no_xyzi, b"SIZE", &[1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(MagicaVoxModel::parse(&no_xyzi).unwrap_err(),
+                   VolError::MissingVoxChunk("XYZI"));
+
+        let big : Vol<u8> = Vol::new_dims(300, 1, 1);
+        assert_eq!(big.to_magica_vox(None).unwrap_err(), VolError::SizeTooBig(300));
+    }
+
+    #[test]
+    fn check_edit_journal() {
+        let mut base : Vol<u8> = Vol::new_dims(5, 4, 3);
+        base.fill(0, 0, 0, 5, 2, 3, 1.into());
+
+        let mut v = base.clone();
+        let mut j = EditJournal::new();
+        assert!(!j.can_undo());
+
+        v.set_journaled(&mut j, 1, 1, 1, 0.into());
+        v.set_journaled(&mut j, 2, 1, 1, 0.into());
+        j.commit();
+        let after_first = v.clone();
+
+        v.set_journaled(&mut j, 2, 1, 1, 3.into());
+        v.set_journaled(&mut j, 4, 3, 2, 5.into());
+        // Writing the same color is no change:
+        v.set_journaled(&mut j, 0, 0, 0, 1.into());
+        j.commit();
+        let after_second = v.clone();
+
+        let edits = j.undo().unwrap();
+        assert_eq!(edits.len(), 2);
+        v.apply_edits(&edits);
+        assert_eq!(v, after_first);
+        assert!(j.can_redo());
+
+        v.apply_edits(&j.undo().unwrap());
+        assert_eq!(v, base);
+        assert!(j.undo().is_none());
+
+        v.apply_edits(&j.redo().unwrap());
+        v.apply_edits(&j.redo().unwrap());
+        assert_eq!(v, after_second);
+        assert!(j.redo().is_none());
+
+        assert_eq!(j.delta(), vec![
+            (Pos::new(1, 1, 1), 0),
+            (Pos::new(2, 1, 1), 3),
+            (Pos::new(4, 3, 2), 5),
+        ]);
+
+        let data = j.serialize_delta((5, 4, 3));
+        assert_eq!(data.len(), 20 + 3 * 2 + 4);
+        let delta = EditJournal::<u8>::deserialize_delta(&data, (5, 4, 3)).unwrap();
+        let mut restored = base.clone();
+        restored.apply_edits(&delta);
+        assert_eq!(restored, after_second);
+
+        // Reverting a change removes it from the delta:
+        v.set_journaled(&mut j, 1, 1, 1, 1.into());
+        assert_eq!(j.delta().len(), 2);
+
+        // A new change after undo drops the redo steps:
+        v.apply_edits(&j.undo().unwrap());
+        v.set_journaled(&mut j, 0, 3, 0, 9.into());
+        assert!(!j.can_redo());
+
+        assert_eq!(EditJournal::<u8>::deserialize_delta(&data, (5, 4, 4)).unwrap_err(),
+                   VolError::DeltaSizeMismatch { expected: (5, 4, 4), got: (5, 4, 3) });
+        let mut bad = data.clone();
+        bad[21] ^= 0xFF;
+        match EditJournal::<u8>::deserialize_delta(&bad, (5, 4, 3)).unwrap_err() {
+            VolError::ChecksumMismatch { .. } => (),
+            e => panic!("unexpected error {}", e),
+        }
+        assert_eq!(EditJournal::<u8>::deserialize_delta(&data[1..], (5, 4, 3)).unwrap_err(),
+                   VolError::NotADelta);
+
+        let mut o : Octree<u8> = Octree::new(0, base.clone());
+        let mut oj = EditJournal::new();
+        o.set_journaled(&mut oj, 3, 0, 0, 0.into());
+        o.apply_edits(&oj.undo().unwrap());
+        assert_eq!(o.vol, base);
+    }
+
+    #[test]
+    fn check_chunked_volume() {
+        let mut cv : ChunkedVolume<u8> = ChunkedVolume::new(20, 10, 8, 8);
+        assert_eq!(cv.chunk_dims(), (3, 2, 1));
+        assert_eq!(cv.chunk_count(), 6);
+        assert_eq!(cv.chunk_extent(5), (4, 2, 8));
+        assert_eq!(cv.chunk_origin(5), Pos::new(16, 8, 0));
+        assert_eq!(cv.chunk_origin_inv_y(5), Pos::new(16, 0, 0));
+        assert_eq!(cv.chunk_origin_inv_y(0), Pos::new(0, 2, 0));
+
+        // Writing empty voxels doesn't allocate:
+        cv.set(Pos::new(1, 1, 1), 0.into());
+        assert_eq!(cv.allocated_chunk_count(), 0);
+        assert_eq!(cv.dirty_chunks().count(), 0);
+
+        cv.set(Pos::new(7, 7, 7), 1.into());
+        cv.set(Pos::new(8, 7, 7), 2.into());
+        cv.set(Pos::new(19, 9, 7), 3.into());
+        assert_eq!(cv.allocated_chunk_count(), 3);
+        assert_eq!(cv.get(Pos::new(7, 7, 7)).color, 1);
+        assert_eq!(cv.get(Pos::new(8, 7, 7)).color, 2);
+        assert_eq!(cv.get(Pos::new(19, 9, 7)).color, 3);
+        assert_eq!(cv.get(Pos::new(18, 9, 7)).color, 0);
+        assert_eq!(cv.get(Pos::new(0, 9, 0)).color, 0);
+
+        assert_eq!(cv.dirty_chunks().collect::<Vec<usize>>(), vec![0, 1, 5]);
+        assert_eq!(cv.take_dirty(), vec![0, 1, 5]);
+        assert_eq!(cv.dirty_chunks().count(), 0);
+
+        // The inverted y axis points up:
+        assert_eq!(cv.get_inv_y(19, 0, 7).color, 3);
+        cv.set_inv_y(0, 9, 0, 4.into());
+        assert_eq!(cv.get(Pos::new(0, 0, 0)).color, 4);
+        assert_eq!(cv.take_dirty(), vec![0]);
+
+        let (idx, lpos) = cv.locate(Pos::new(19, 9, 7));
+        assert_eq!((idx, lpos), (5, Pos::new(3, 1, 7)));
+        assert_eq!(cv.chunk(idx).unwrap().read().unwrap().vol.h, 2);
+        assert!(cv.chunk(3).is_none());
+
+        let hist = cv.color_histogram();
+        assert_eq!(&hist[1..5], &[1, 1, 1, 1]);
+        assert_eq!(hist.iter().sum::<usize>(), 20 * 10 * 8);
+
+        let vol = cv.to_vol();
+        assert_eq!((vol.w, vol.h, vol.d), (20, 10, 8));
+        assert_eq!(*vol.color_at(Pos::new(19, 9, 7)), 3);
+
+        let mut cv2 : ChunkedVolume<u8> = ChunkedVolume::new(20, 10, 8, 8);
+        cv2.write_vol(&vol);
+        assert_eq!(cv2.allocated_chunk_count(), 3);
+        assert_eq!(cv2.dirty_chunks().count(), 6);
+        assert_eq!(cv2.to_vol(), vol);
+
+        // The chunk octrees can be computed and meshed as usual:
+        let ot = cv2.chunk(5).unwrap();
+        ot.write().unwrap().update_dirty();
+        assert!(!ot.read().unwrap().root().empty);
+
+        // Chunks loaded by `write_vol` are meshed after `update_dirty`
+        // alone, like the render jobs do it:
+        let mut cube : Vol<u8> = Vol::new(16);
+        cube.set(3, 3, 3, 5.into());
+        let mut cv3 : ChunkedVolume<u8> = ChunkedVolume::new(16, 16, 16, 8);
+        cv3.write_vol(&cube);
+        assert_eq!(cv3.allocated_chunk_count(), 1);
+        let ot = cv3.chunk(0).unwrap();
+        ot.write().unwrap().update_dirty();
+        let ot = ot.read().unwrap();
+        assert!(!ot.root().empty);
+        let md = mesh_octree(&*ot, MeshOptions::default(), |_| [1.0; 4]);
+        assert_eq!(md.triangle_count(), 12);
+    }
 
     #[test]
-    fn check_octree_n1_filled() {
-        let mut t : Octree<u8> = Octree::new_from_size(2);
-        t.fill(0, 0, 0, 2, 2, 2, 10.into());
+    fn check_mesh_chunked_volume() {
+        let opts  = MeshOptions { backend: MeshBackend::Greedy, ..MeshOptions::default() };
+        let color = |c: u8| [c as f32, 0.0, 0.0, 1.0];
+        let sorted = |md: &MeshData| {
+            let mut p : Vec<(i32, i32, i32)> =
+                md.positions.iter().map(|p| (p[0] as i32, p[1] as i32, p[2] as i32)).collect();
+            p.sort();
+            p
+        };
+
+        let mut cv : ChunkedVolume<u8> = ChunkedVolume::new(20, 12, 20, 8);
+        assert!(mesh_chunked_volume(&cv, opts, color).is_empty());
+
+        // Voxels inside of one chunk are placed like in the whole volume:
+        cv.set(Pos::new(9, 1, 9), 2.into());
+        cv.set(Pos::new(10, 2, 10), 2.into());
+        let md = mesh_chunked_volume(&cv, opts, color);
+        let mut whole = Octree::new(0, cv.to_vol());
+        whole.recompute();
+        assert_eq!(sorted(&md), sorted(&mesh_octree(&whole, opts, color)));
+        assert_eq!(md.indices.iter().max(), Some(&(md.vertex_count() as u32 - 1)));
+
+        // The voxel in the upper far corner of the last chunk:
+        cv.set(Pos::new(9, 1, 9), 0.into());
+        cv.set(Pos::new(10, 2, 10), 0.into());
+        cv.set(Pos::new(19, 0, 19), 3.into());
+        let md = mesh_chunked_volume(&cv, opts, color);
+        assert_eq!(md.triangle_count(), 12);
+        let p = sorted(&md);
+        assert_eq!((p[0], p[p.len() - 1]), ((19, 11, 19), (20, 12, 20)));
+    }
+
+    #[test]
+    fn check_chunked_volume_waits_for_render() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let mut cv : ChunkedVolume<u8> = ChunkedVolume::new(16, 16, 16, 8);
+        cv.set(Pos::new(1, 1, 1), 1.into());
+
+        // A render job meshing chunk 0 until it gets `go`:
+        let ot       = cv.chunk(0).unwrap().clone();
+        let released = std::sync::Arc::new(AtomicBool::new(false));
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (go_tx, go_rx)         = std::sync::mpsc::channel::<()>();
+        let job = {
+            let ot       = ot.clone();
+            let released = released.clone();
+            std::thread::spawn(move || {
+                let guard = ot.read().unwrap();
+                locked_tx.send(()).unwrap();
+                go_rx.recv().unwrap();
+                released.store(true, Ordering::SeqCst);
+                drop(guard);
+            })
+        };
+        locked_rx.recv().unwrap();
+
+        // Other chunks are written while the job holds chunk 0:
+        cv.set(Pos::new(9, 9, 9), 2.into());
+        assert_eq!(cv.get(Pos::new(9, 9, 9)).color, 2);
+        assert!(ot.try_write().is_err());
+
+        // Writing chunk 0 returns only after the job let go of it:
+        go_tx.send(()).unwrap();
+        cv.set(Pos::new(2, 1, 1), 3.into());
+        assert!(released.load(Ordering::SeqCst));
+        assert_eq!(cv.get(Pos::new(2, 1, 1)).color, 3);
+        job.join().unwrap();
+    }
+
+    #[test]
+    fn check_vertex_ao() {
+        let corners = [[0, 0, 0], [1, 0, 0], [1, 0, 1], [0, 0, 1]];
+
+        // A lone voxel is not occluded:
+        let mut v : Vol<u8> = Vol::new(8);
+        v.set(3, 3, 3, 1.into());
+        let t : Octree<u8> = Octree::new(0, v.clone());
+        assert_eq!(t.face_ao(Pos::new(3, 3, 3), [1; 3], F_TOP, &corners), [3, 3, 3, 3]);
+
+        // A voxel above the left neighbour occludes the two left corners
+        // of the top face of (3, 3, 3), a diagonal one only the corner:
+        v.set(2, 2, 3, 1.into());
+        v.set(4, 2, 4, 1.into());
+        let t : Octree<u8> = Octree::new(0, v.clone());
+        assert_eq!(t.face_ao(Pos::new(3, 3, 3), [1; 3], F_TOP, &corners), [2, 3, 2, 2]);
+
+        // Both sides of a corner occlude it fully:
+        v.set(3, 2, 2, 1.into());
+        let t : Octree<u8> = Octree::new(0, v.clone());
+        assert_eq!(t.face_ao(Pos::new(3, 3, 3), [1; 3], F_TOP, &corners)[0], 0);
+        // The bottom face is not affected by anything above:
+        assert_eq!(t.face_ao(Pos::new(3, 3, 3), [1; 3], F_BOTTOM, &corners), [3, 3, 3, 3]);
+
+        // Merged cubes sample the cells next to their corners:
+        let mut v : Vol<u8> = Vol::new(8);
+        v.fill(0, 4, 0, 4, 4, 4, 1.into());
+        v.set(4, 3, 0, 1.into());
+        v.set(0, 3, 4, 1.into());
+        let t : Octree<u8> = Octree::new(0, v);
+        let corners = [[0, 0, 0], [1, 0, 0], [1, 0, 1], [0, 0, 1]];
+        assert_eq!(t.face_ao(Pos::new(0, 4, 0), [4; 3], F_TOP, &corners), [3, 2, 3, 2]);
+
+        // The same for the right face of the merged cube:
+        let corners = [[1, 0, 0], [1, 1, 0], [1, 1, 1], [1, 0, 1]];
+        assert_eq!(t.face_ao(Pos::new(0, 4, 0), [4; 3], F_RIGHT, &corners), [2, 3, 3, 3]);
+
+        assert!(ao_split_13([0, 3, 3, 3]));
+        assert!(!ao_split_13([3, 0, 3, 3]));
+        assert!(!ao_split_13([3, 3, 3, 3]));
+    }
+
+    fn exposed_unit_faces(v: &Vol<u8>) -> Vec<(u8, Pos, u8)> {
+        let mut faces = vec![];
+        for z in 0..v.d {
+            for y in 0..v.h {
+                for x in 0..v.w {
+                    let p = Pos::new(x as PInt, y as PInt, z as PInt);
+                    let c = *v.color_at(p);
+                    if c == 0 { continue; }
+                    for (face, n) in [
+                        (F_LEFT,   [-1, 0, 0]), (F_RIGHT,  [1, 0, 0]),
+                        (F_TOP,    [0, -1, 0]), (F_BOTTOM, [0, 1, 0]),
+                        (F_FRONT,  [0, 0, -1]), (F_BACK,   [0, 0, 1]),
+                    ].iter() {
+                        let np = [x as i32 + n[0], y as i32 + n[1], z as i32 + n[2]];
+                        let outside =
+                               np[0] < 0 || np[1] < 0 || np[2] < 0
+                            || np[0] >= v.w as i32 || np[1] >= v.h as i32
+                            || np[2] >= v.d as i32;
+                        if outside || *v.color_at(Pos::new(
+                                np[0] as PInt, np[1] as PInt, np[2] as PInt)) == 0
+                        {
+                            faces.push((*face, p, c));
+                        }
+                    }
+                }
+            }
+        }
+        faces.sort_by_key(|(f, p, _)| (*f, p.z, p.y, p.x));
+        faces
+    }
+
+    fn quad_unit_faces(quads: &[FaceQuad<u8>]) -> Vec<(u8, Pos, u8)> {
+        let mut faces = vec![];
+        for q in quads {
+            q.for_each_unit_face(|p| faces.push((q.face, p, q.color)));
+        }
+        faces.sort_by_key(|(f, p, _)| (*f, p.z, p.y, p.x));
+        faces
+    }
+
+    #[test]
+    fn check_greedy_quads() {
+        // A wall made of an 8x8 block, smaller blocks and single voxels,
+        // with a second color on one part:
+        let mut v : Vol<u8> = Vol::new(16);
+        v.fill(0, 0, 4, 8, 8, 4, 1.into());
+        v.fill(8, 0, 4, 4, 4, 4, 1.into());
+        v.fill(8, 4, 4, 2, 2, 4, 1.into());
+        v.fill(10, 4, 4, 1, 1, 4, 1.into());
+        v.fill(12, 0, 4, 4, 8, 4, 2.into());
+        v.set(15, 15, 15, 3.into());
+
+        let greedy = v.greedy_quads();
+        assert_eq!(quad_unit_faces(&greedy), exposed_unit_faces(&v));
+
+        let mut t : Octree<u8> = Octree::new(0, v.clone());
         t.recompute();
+        let cubes = t.face_quads();
 
-        let mut log = vec![];
-        t.draw(&mut |size, pos, v| {
-            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
-        });
+        // Two triangles per quad:
+        assert_eq!(greedy.len() * 2, 58);
+        assert_eq!(cubes.len()  * 2, 100);
+        // The stepped front of the wall is one quad per row of equal
+        // width, and one for the second color:
+        assert_eq!(greedy.iter().filter(|q| q.face == F_FRONT && q.pos.z == 4).count(), 5);
 
-        assert_eq!(log[0], (2, (0, 0, 0), 10, 63));
+        // The cube mesher draws whole faces of merged nodes that are only
+        // partially exposed, so it covers at least the visible surface:
+        let cube_faces = quad_unit_faces(&cubes);
+        for f in exposed_unit_faces(&v) {
+            assert!(cube_faces.contains(&f), "missing {:?}", f);
+        }
+
+        let mut e : Vol<u8> = Vol::new(4);
+        assert!(e.greedy_quads().is_empty());
+        e.set(1, 2, 3, 1.into());
+        assert_eq!(e.greedy_quads().len(), 6);
+    }
+
+    fn mesh_area(md: &MeshData) -> f32 {
+        md.collision_tris.chunks(3).map(|t| {
+            let a = v3_sub(t[1], t[0]);
+            let b = v3_sub(t[2], t[0]);
+            let c = [a[1] * b[2] - a[2] * b[1],
+                     a[2] * b[0] - a[0] * b[2],
+                     a[0] * b[1] - a[1] * b[0]];
+            v3_dot(c, c).sqrt() * 0.5
+        }).sum()
     }
 
     #[test]
-    fn check_octree_n1_partial_minus_1() {
-        let mut t : Octree<u8> = Octree::new_from_size(4);
-        t.fill(0, 0, 0, 4, 4, 4, 10.into());
-        t.set(0, 0, 0, 12.into());
+    fn check_mesh_data() {
+        let mut v : Vol<u8> = Vol::new(4);
+        v.set(1, 0, 2, 1.into());
+        let mut t : Octree<u8> = Octree::new(0, v);
         t.recompute();
 
-        let mut log = vec![];
-        t.draw(&mut |size, pos, v| {
-            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
-        });
-        for l in log.iter() { println!("E {:?} {:x}", l, l.3); }
-        for (i, n) in t.nodes.iter().enumerate() {
-            println!("AT {}: {:?}", i, n);
+        let color = |c: u8| [c as f32 / 4.0, 0.0, 0.0, 1.0];
+        for backend in [MeshBackend::OctreeCubes, MeshBackend::Greedy].iter() {
+            let opts = MeshOptions { backend: *backend, ambient_occlusion: true, ..MeshOptions::default() };
+            let md = mesh_octree(&t, opts, color);
+            assert_eq!(md.vertex_count(),         24);
+            assert_eq!(md.normals.len(),          24);
+            assert_eq!(md.uvs2.len(),             24);
+            assert_eq!(md.triangle_count(),       12);
+            assert_eq!(md.collision_tris.len(),   36);
+
+            // The volume y axis is flipped:
+            for p in md.positions.iter() {
+                assert!(p[0] == 1.0 || p[0] == 2.0);
+                assert!(p[1] == 3.0 || p[1] == 4.0);
+                assert!(p[2] == 2.0 || p[2] == 3.0);
+            }
+            // A single voxel is not occluded:
+            assert!(md.colors.iter().all(|c| *c == [0.25, 0.0, 0.0, 1.0]));
+            for (i, idx) in md.indices.iter().enumerate() {
+                assert_eq!(md.collision_tris[i], md.positions[*idx as usize]);
+            }
+            // Godot culls counter clockwise triangles, so they wind
+            // clockwise seen from outside:
+            for tri in md.indices.chunks(3) {
+                let p : std::vec::Vec<[f32; 3]> =
+                    tri.iter().map(|i| md.positions[*i as usize]).collect();
+                let a = v3_sub(p[1], p[0]);
+                let b = v3_sub(p[2], p[0]);
+                let n = [a[1] * b[2] - a[2] * b[1],
+                         a[2] * b[0] - a[0] * b[2],
+                         a[0] * b[1] - a[1] * b[0]];
+                let normal = md.normals[tri[0] as usize];
+                assert!(v3_dot(n, normal) < 0.0);
+            }
+            assert_eq!(mesh_area(&md), 6.0);
         }
 
-        assert_eq!(log[0], (1, (0, 0, 0), 12, F_FRONT | F_LEFT | F_TOP));
-        assert_eq!(log[1], (1, (1, 0, 0), 10, F_FRONT | F_TOP));
-        assert_eq!(log[2], (1, (0, 1, 0), 10, F_FRONT | F_LEFT));
-        assert_eq!(log[3], (1, (1, 1, 0), 10, F_FRONT));
-        assert_eq!(log[4], (1, (0, 0, 1), 10, F_LEFT | F_TOP));
-        assert_eq!(log[5], (1, (1, 0, 1), 10, F_TOP));
-        assert_eq!(log[6], (1, (0, 1, 1), 10, F_LEFT));
-        assert_eq!(log[7], (1, (1, 1, 1), 10, 0x00));
-        assert_eq!(log[8], (2, (2, 0, 0), 10, F_RIGHT | F_FRONT | F_TOP));
+        // The wall of check_greedy_quads:
+        let mut v : Vol<u8> = Vol::new(16);
+        v.fill(0, 0, 4, 8, 8, 4, 1.into());
+        v.fill(8, 0, 4, 4, 4, 4, 1.into());
+        v.fill(8, 4, 4, 2, 2, 4, 1.into());
+        v.fill(10, 4, 4, 1, 1, 4, 1.into());
+        v.fill(12, 0, 4, 4, 8, 4, 2.into());
+        let exposed = exposed_unit_faces(&v).len() as f32;
+        let mut t : Octree<u8> = Octree::new(0, v);
+        t.recompute();
+
+        let cubes  = mesh_octree(&t, MeshOptions::default(), color);
+        let greedy = mesh_octree(&t, MeshOptions {
+            backend: MeshBackend::Greedy, ..MeshOptions::default() }, color);
+        assert_eq!(greedy.triangle_count(), 46);
+        assert_eq!(cubes.triangle_count(),  88);
+        assert_eq!(mesh_area(&greedy), exposed);
+        assert!(mesh_area(&cubes) >= exposed);
+
+        // Ambient occlusion darkens the inner corner of the step:
+        let ao = mesh_octree(&t, MeshOptions {
+            backend: MeshBackend::Greedy, ambient_occlusion: true,
+            ..MeshOptions::default() }, color);
+        assert_eq!(ao.positions, greedy.positions);
+        assert!(ao.colors.iter().any(|c| c[0] < 0.25));
+        assert!(greedy.colors.iter().all(|c| c[0] == 0.25 || c[0] == 0.5));
     }
 
     #[test]
-    fn check_octree_n2_filled() {
-        let v : Vol<u8> = Vol::new(4);
-        let mut t : Octree<u8> = Octree::new(64, v);
-        t.fill(0, 0, 0, 4, 4, 4, 10.into());
-        let n = t.recompute();
-//        println!("NODES: {:?}", t.nodes);
+    fn check_surface_nets() {
+        // A ball of radius 5 in a 16^3 field, denser towards the center:
+        let mut df = DensityField::new(16, 16, 16);
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..16 {
+                    let d = [x as f32 + 0.5 - 8.0, y as f32 + 0.5 - 8.0, z as f32 + 0.5 - 8.0];
+                    df.set(Pos::new(x, y, z), 5.0 - v3_dot(d, d).sqrt());
+                }
+            }
+        }
+        let red = |_p: Pos| [1.0, 0.0, 0.0, 1.0];
+        let md = mesh_surface_nets(
+            [16, 16, 16], Pos::new(0, 0, 0), [16, 16, 16], 0.0, |p| df.at(p), red);
 
-        assert_eq!(n.voxel.unwrap().color, 10);
-        assert_eq!(n.voxel.unwrap().faces, 0x3F);
-        assert_eq!(t.nodes[0].voxel.unwrap().color, 10);
-        assert_eq!(t.nodes[0].empty, false);
+        assert!(md.triangle_count() > 100);
+        assert_eq!(md.normals.len(), md.vertex_count());
+        assert_eq!(md.collision_tris.len(), md.indices.len());
+        for p in md.positions.iter() {
+            let r = { let d = v3_sub(*p, [8.0; 3]); v3_dot(d, d).sqrt() };
+            assert!(r > 4.5 && r < 5.5, "r={}", r);
+        }
+        // Normals point outwards and the triangles are wound like the
+        // ones of the cube mesher:
+        for (p, n) in md.positions.iter().zip(md.normals.iter()) {
+            assert!(v3_dot(v3_sub(*p, [8.0; 3]), *n) > 0.0);
+        }
+        for tri in md.indices.chunks(3) {
+            let p : std::vec::Vec<[f32; 3]> =
+                tri.iter().map(|i| md.positions[*i as usize]).collect();
+            let a = v3_sub(p[1], p[0]);
+            let b = v3_sub(p[2], p[0]);
+            let n = [a[1] * b[2] - a[2] * b[1],
+                     a[2] * b[0] - a[0] * b[2],
+                     a[0] * b[1] - a[1] * b[0]];
+            assert!(v3_dot(n, v3_sub(p[0], [8.0; 3])) < 0.0);
+        }
+        // The surface is closed, every edge has two triangles:
+        let mut edges = std::collections::HashMap::new();
+        for tri in md.indices.chunks(3) {
+            for i in 0..3 {
+                let (a, b) = (tri[i], tri[(i + 1) % 3]);
+                *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+        assert!(edges.values().all(|c| *c == 2));
+        assert!(md.colors.iter().all(|c| *c == [1.0, 0.0, 0.0, 1.0]));
 
-        let mut first : Option<(usize, Pos, Voxel<u8>)> = None;
-        let mut last : Option<(usize, Pos, Voxel<u8>)> = None;
-        t.draw(&mut |size, pos, v| {
-            if first.is_none() { first = Some((size, *pos, v)); }
-            last = Some((size, *pos, v));
-        });
+        // Eight regions give the same triangles:
+        let mut tiled = 0;
+        let mut tiled_verts = vec![];
+        for i in 0..8 {
+            let o = Pos::new((i & 1) * 8, (i >> 1 & 1) * 8, (i >> 2) * 8);
+            let part = mesh_surface_nets(
+                [16, 16, 16], o, [8, 8, 8], 0.0, |p| df.at(p), red);
+            tiled += part.triangle_count();
+            for p in part.collision_tris.iter() {
+                // Back to the coordinates of the whole volume:
+                tiled_verts.push([p[0] + o.x as f32, p[1] + (8 - o.y) as f32, p[2] + o.z as f32]);
+            }
+        }
+        assert_eq!(tiled, md.triangle_count());
+        for p in tiled_verts.iter() {
+            assert!(md.positions.contains(p));
+        }
 
-        let first = first.unwrap();
-        assert_eq!((first.0, first.1.x, first.1.y, first.1.z), (4, 0, 0, 0));
-        assert_eq!(first.2.color, 10);
-        assert_eq!(first.2.faces, 0x3f); // all faces
+        // Empty samples carve into the surface, and the color of the
+        // inner samples is used:
+        let hole = Pos::new(12, 8, 8);
+        let carved = mesh_surface_nets(
+            [16, 16, 16], Pos::new(0, 0, 0), [16, 16, 16], 0.0,
+            |p| if p == hole { df.at(p).min(0.0) } else { df.at(p) },
+            |p| if p.x > 8 { [0.0, 1.0, 0.0, 1.0] } else { [1.0, 0.0, 0.0, 1.0] });
+        assert!(carved.triangle_count() > md.triangle_count());
+        assert!(carved.colors.iter().any(|c| c[1] == 1.0));
+        assert!(carved.colors.iter().any(|c| c[0] == 1.0));
 
-        let last = last.unwrap();
-        assert_eq!((last.0, last.1.x, last.1.y, last.1.z), (4, 0, 0, 0));
-        assert_eq!(last.2.color, 10);
-        assert_eq!(last.2.faces, 0x3f); // all faces
+        let none = mesh_surface_nets(
+            [4, 4, 4], Pos::new(0, 0, 0), [4, 4, 4], 0.5, |_p| 0.0, red);
+        assert!(none.is_empty());
+        // Samples at the border are closed off:
+        let full = mesh_surface_nets(
+            [2, 2, 2], Pos::new(0, 0, 0), [2, 2, 2], 0.5, |_p| 1.0, red);
+        assert_eq!(full.triangle_count(), 6 * 4 * 2);
     }
 
     #[test]
-    fn check_octree_n2_broken() {
-        let v : Vol<u8> = Vol::new(4);
-        let mut t : Octree<u8> = Octree::new(64, v);
-        t.fill(0, 0, 0, 4, 4, 4, 10.into());
-        t.set(3, 3, 3, 20.into());
-        let n = t.recompute();
-//        println!("NODES: {:?}", t.nodes);
+    fn check_texture_atlas() {
+        let mut atlas = TextureAtlas::new(4, 2);
+        atlas.set(1, AtlasTiles { top: 1, side: 2, bottom: 5 });
+        atlas.set(2, AtlasTiles::all(7));
+        assert_eq!(atlas.tile_size(),      [0.25, 0.5]);
+        assert_eq!(atlas.tile_origin(0),   [0.0,  0.0]);
+        assert_eq!(atlas.tile_origin(5),   [0.25, 0.5]);
+        assert_eq!(atlas.tile_origin(7),   [0.75, 0.5]);
+        assert_eq!(atlas.tile_origin(9),   [0.25, 0.0]);
 
-        assert_eq!(n.voxel, None);
-        // first layer:
-        assert_eq!(t.nodes[1].voxel.unwrap().color, 10);
-        assert_eq!(t.nodes[1].empty, false);
-        assert_eq!(t.nodes[2].voxel.unwrap().color, 10);
-        assert_eq!(t.nodes[2].empty, false);
-        assert_eq!(t.nodes[3].voxel.unwrap().color, 10);
-        assert_eq!(t.nodes[3].empty, false);
-        assert_eq!(t.nodes[4].voxel.unwrap().color, 10);
-        assert_eq!(t.nodes[4].empty, false);
+        // The tile count doesn't fit into the tile numbers:
+        let big = TextureAtlas::new(256, 256);
+        assert_eq!(big.tile_origin(257),        [1.0 / 256.0, 1.0 / 256.0]);
+        assert_eq!(big.tile_origin(u16::MAX),   [255.0 / 256.0, 255.0 / 256.0]);
+        let wide = TextureAtlas::new(u16::MAX, 2);
+        assert_eq!(wide.tile_origin(u16::MAX),  [0.0, 0.5]);
+        assert_eq!(atlas.tile_for(1, Face::Top),    1);
+        assert_eq!(atlas.tile_for(1, Face::Left),   2);
+        assert_eq!(atlas.tile_for(1, Face::Bottom), 5);
+        assert_eq!(atlas.tile_for(3, Face::Front),  0);
 
-        // second layer:
-        assert_eq!(t.nodes[5].voxel.unwrap().color, 10);
-        assert_eq!(t.nodes[5].empty, false);
-        assert_eq!(t.nodes[6].voxel.unwrap().color, 10);
-        assert_eq!(t.nodes[6].empty, false);
-        assert_eq!(t.nodes[7].voxel.unwrap().color, 10);
-        assert_eq!(t.nodes[7].empty, false);
-        assert_eq!(t.nodes[8].voxel, None);
-        assert_eq!(t.nodes[8].empty, false);
+        // A 4x4x4 block merged into one octree node:
+        let mut v : Vol<u8> = Vol::new(8);
+        v.fill(0, 4, 0, 4, 4, 4, 1.into());
+        v.set(6, 7, 6, 2.into());
+        let mut t : Octree<u8> = Octree::new(0, v);
+        t.recompute();
 
-        let mut first : Option<(usize, Pos, Voxel<u8>)> = None;
-        let mut last : Option<(usize, Pos, Voxel<u8>)> = None;
-        t.draw(&mut |size, pos, v| {
-//            println!("DRAWING: size={}, pos={:?} v={:?}", size, pos, v);
-            if first.is_none() { first = Some((size, *pos, v)); }
-            last = Some((size, *pos, v));
-        });
+        for backend in [MeshBackend::OctreeCubes, MeshBackend::Greedy].iter() {
+            let opts = MeshOptions { backend: *backend, ..MeshOptions::default() };
+            let md = mesh_octree_atlas(&t, opts, &atlas);
+            let plain = mesh_octree(&t, opts, |_| [1.0; 4]);
+            assert_eq!(md.positions, plain.positions);
+            assert_eq!(md.indices,   plain.indices);
+            assert!(md.colors.iter().all(|c| *c == [1.0; 4]));
 
-        let first = first.unwrap();
-        assert_eq!((first.0, first.1.x, first.1.y, first.1.z), (2, 0, 0, 0));
-        assert_eq!(first.2.color, 10);
-        assert_eq!(first.2.faces, F_FRONT | F_LEFT | F_TOP);
+            for i in 0..md.vertex_count() {
+                let (p, n, uv) = (md.positions[i], md.normals[i], md.uvs[i]);
+                if p[0] > 4.0 {
+                    assert_eq!(md.uvs2[i], atlas.tile_origin(7));
+                    assert!(uv[0] == 0.0 || uv[0] == 1.0);
+                    assert!(uv[1] == 0.0 || uv[1] == 1.0);
+                    continue;
+                }
 
-        let last = last.unwrap();
-        assert_eq!((last.0, last.1.x, last.1.y, last.1.z), (1, 3, 3, 3));
-        assert_eq!(last.2.color, 20);
-        assert_eq!(last.2.faces, F_BACK | F_RIGHT | F_BOTTOM);
+                let tile =
+                    if      n[1] > 0.0 { 1 }
+                    else if n[1] < 0.0 { 5 }
+                    else               { 2 };
+                assert_eq!(md.uvs2[i], atlas.tile_origin(tile));
+                // The UVs count the voxels, so the tile repeats on
+                // the merged faces:
+                assert!(uv[0] == 0.0 || uv[0] == 4.0, "{:?}", uv);
+                assert!(uv[1] == 0.0 || uv[1] == 4.0, "{:?}", uv);
+                // Side faces are upright, with the top edge at v = 0:
+                if n[1] == 0.0 {
+                    assert_eq!(uv[1], 4.0 - p[1]);
+                }
+            }
+        }
+
+        // The front face seen from outside has u growing to the right,
+        // that is towards -x:
+        let mut md = MeshData::new();
+        md.add_face(Face::Front, [1.0; 4], None, Some([0.5, 0.5]),
+                    [0.0; 3], [3.0, 2.0, 1.0], 1.0);
+        for (p, uv) in md.positions.iter().zip(md.uvs.iter()) {
+            assert_eq!(uv[0], 3.0 - p[0]);
+            assert_eq!(uv[1], 2.0 - p[1]);
+        }
+        assert!(md.uvs2.iter().all(|t| *t == [0.5, 0.5]));
     }
 
     #[test]
-    fn check_n3_filled() {
-        let v : Vol<u8> = Vol::new(8);
-        let mut t : Octree<u8> = Octree::new(8 * 8 * 8, v);
-        t.fill(0, 0, 0, 8, 8, 8, 11.into());
-        let n = t.recompute();
+    fn check_collision_boxes() {
+        // The wall of check_greedy_quads and a 16^3 chunk of noise-like
+        // rubble with a solid core:
+        let mut wall : Vol<u8> = Vol::new(16);
+        wall.fill(0, 0, 4, 8, 8, 4, 1.into());
+        wall.fill(8, 0, 4, 4, 4, 4, 1.into());
+        wall.fill(8, 4, 4, 2, 2, 4, 1.into());
+        wall.fill(10, 4, 4, 1, 1, 4, 1.into());
+        wall.fill(12, 0, 4, 4, 8, 4, 2.into());
 
-        assert_eq!(n.voxel.unwrap().color, 11);
-        assert_eq!(n.voxel.unwrap().faces, 0x3F);
-        assert_eq!(t.nodes[0].voxel.unwrap().color, 11);
-        assert_eq!(t.nodes[0].empty, false);
+        let mut rubble : Vol<u8> = Vol::new(16);
+        rubble.fill(2, 2, 2, 12, 12, 12, 1.into());
+        for i in 0..4096_usize {
+            let (x, y, z) = (i % 16, (i / 16) % 16, i / 256);
+            if (x * 7 + y * 13 + z * 5) % 11 == 0 {
+                rubble.set(x as PInt, y as PInt, z as PInt, ((i % 3) as u8).into());
+            }
+        }
 
-        let mut first : Option<(usize, Pos, Voxel<u8>)> = None;
-        let mut last : Option<(usize, Pos, Voxel<u8>)> = None;
-        t.draw(&mut |size, pos, v| {
-            if first.is_none() { first = Some((size, *pos, v)); }
-            last = Some((size, *pos, v));
-        });
+        for v in [wall, rubble].iter() {
+            let solid : usize =
+                v.data.iter().filter(|vx| vx.color != 0).count();
+            let mut t : Octree<u8> = Octree::new(0, v.clone());
+            t.recompute();
 
-        let first = first.unwrap();
-        assert_eq!((first.0, first.1.x, first.1.y, first.1.z), (8, 0, 0, 0));
-        assert_eq!(first.2.color, 11);
-        assert_eq!(first.2.faces, 0x3f); // all faces
+            let greedy = v.greedy_boxes();
+            let nodes  = t.node_boxes();
+            for boxes in [&greedy, &nodes].iter() {
+                // The boxes cover exactly the solid voxels, once:
+                assert_eq!(boxes.iter().map(|b| b.voxel_count()).sum::<usize>(), solid);
+                for b in boxes.iter() {
+                    for z in 0..b.size[2] {
+                        for y in 0..b.size[1] {
+                            for x in 0..b.size[0] {
+                                let p = b.pos.offs(x as PInt, y as PInt, z as PInt);
+                                assert!(v.at(p).color != 0);
+                            }
+                        }
+                    }
+                }
+            }
+            assert!(greedy.len() <= nodes.len());
 
-        let last = last.unwrap();
-        assert_eq!((last.0, last.1.x, last.1.y, last.1.z), (8, 0, 0, 0));
-        assert_eq!(last.2.color, 11);
-        assert_eq!(last.2.faces, 0x3f); // all faces
+            let tris = mesh_octree(&t, MeshOptions::default(), |_| [1.0; 4]);
+            let boxed = mesh_octree(&t, MeshOptions {
+                collision: CollisionMode::GreedyBoxes, ..MeshOptions::default() },
+                |_| [1.0; 4]);
+            assert_eq!(boxed.indices, tris.indices);
+            assert!(boxed.collision_tris.is_empty());
+            assert_eq!(boxed.collision_boxes.len(), greedy.len());
+            assert!(greedy.len() * 4 < tris.collision_tris.len() / 3);
+        }
+
+        // Boxes in mesh coordinates have the y axis flipped:
+        let mut v : Vol<u8> = Vol::new(8);
+        v.fill(1, 0, 2, 2, 3, 4, 1.into());
+        let boxes = v.greedy_boxes();
+        assert_eq!(boxes, vec![VoxelBox { pos: Pos::new(1, 0, 2), size: [2, 3, 4] }]);
+        assert!(boxes[0].contains(Pos::new(2, 2, 5)));
+        assert!(!boxes[0].contains(Pos::new(3, 2, 5)));
+        assert_eq!(CollisionBox::from_voxel_box(&boxes[0], 8), CollisionBox {
+            center:       [2.0, 6.5, 4.0],
+            half_extents: [1.0, 1.5, 2.0],
+        });
+        assert!(Vol::<u8>::new(4).greedy_boxes().is_empty());
     }
 
     #[test]
-    fn check_n256_filled() {
-        let v : Vol<u8> = Vol::new(256);
-        let mut t : Octree<u8> = Octree::new(256 * 256 * 256, v);
-        let d = std::time::Instant::now();
-        t.fill(0, 0, 0, 256, 256, 256, 11.into());
-        eprintln!("TF={}",d.elapsed().as_millis());
-        let n = t.recompute();
+    fn check_edit_store() {
+        let mut v : Vol<u8> = Vol::new_dims_default(5, 4, 3, 2.into());
 
-        eprintln!("T={}",d.elapsed().as_millis());
+        // First session mines two voxels:
+        let mut j = EditJournal::new();
+        v.set_journaled(&mut j, 1, 1, 1, 0.into());
+        v.set_journaled(&mut j, 4, 3, 2, 0.into());
+        j.commit();
 
-        assert_eq!(n.voxel.unwrap().color, 11);
-        assert_eq!(n.voxel.unwrap().faces, 0x3F);
-        assert_eq!(t.nodes[0].voxel.unwrap().color, 11);
-        assert_eq!(t.nodes[0].empty, false);
+        let mut store = VoxelEditStore::new();
+        store.store((3, 7), &j, (5, 4, 3));
+        store.store((3, 8), &EditJournal::<u8>::new(), (5, 4, 3));
+        assert_eq!(store.len(), 1);
 
-        let mut first : Option<(usize, Pos, Voxel<u8>)> = None;
-        let mut last : Option<(usize, Pos, Voxel<u8>)> = None;
-        t.draw(&mut |size, pos, v| {
-            if first.is_none() { first = Some((size, *pos, v)); }
-            last = Some((size, *pos, v));
-        });
+        let entries = store.to_entries();
+        assert_eq!(entries[0].0, 3);
+        assert_eq!(entries[0].1, 7);
+        let store = VoxelEditStore::from_entries(&entries).unwrap();
+        assert!(VoxelEditStore::from_entries(&[(1, 2, "A".to_string())]).is_err());
 
-        let first = first.unwrap();
-        assert_eq!((first.0, first.1.x, first.1.y, first.1.z), (256, 0, 0, 0));
-        assert_eq!(first.2.color, 11);
-        assert_eq!(first.2.faces, 0x3f); // all faces
+        // Second session regenerates the volume and restores the edits:
+        let mut v2 : Vol<u8> = Vol::new_dims_default(5, 4, 3, 2.into());
+        let saved : Vec<(Pos, u8)> = store.restore((3, 7), (5, 4, 3)).unwrap();
+        assert_eq!(saved, vec![(Pos::new(1, 1, 1), 0), (Pos::new(4, 3, 2), 0)]);
+        assert!(store.restore::<u8>((3, 8), (5, 4, 3)).unwrap().is_empty());
+        assert!(store.restore::<u8>((3, 7), (5, 4, 4)).is_err());
 
-        let last = last.unwrap();
-        assert_eq!((last.0, last.1.x, last.1.y, last.1.z), (256, 0, 0, 0));
-        assert_eq!(last.2.color, 11);
-        assert_eq!(last.2.faces, 0x3f); // all faces
+        let base : Vec<(Pos, u8, u8)> =
+            saved.iter().map(|(p, c)| (*p, v2.at(*p).color, *c)).collect();
+        let mut j2 = EditJournal::with_base(&base);
+        v2.apply_edits(&saved);
+        assert_eq!(v2.serialize(), v.serialize());
+        assert!(!j2.can_undo());
+        assert_eq!(j2.delta(), j.delta());
+
+        // The restored edits stay in the delta, but can't be undone:
+        v2.set_journaled(&mut j2, 1, 1, 1, 5.into());
+        v2.set_journaled(&mut j2, 0, 0, 0, 0.into());
+        j2.commit();
+        assert_eq!(j2.delta(),
+                   vec![(Pos::new(0, 0, 0), 0), (Pos::new(1, 1, 1), 5), (Pos::new(4, 3, 2), 0)]);
+        v2.apply_edits(&j2.undo().unwrap());
+        assert_eq!(j2.undo(), None);
+        assert_eq!(j2.delta(), j.delta());
+        assert_eq!(v2.serialize(), v.serialize());
+
+        // Putting a mined voxel back drops it from the delta:
+        v2.set_journaled(&mut j2, 4, 3, 2, 2.into());
+        assert_eq!(j2.delta(), vec![(Pos::new(1, 1, 1), 0)]);
+        store.clone().store((3, 7), &j2, (5, 4, 3));
     }
 
     #[test]
-    fn check_size8_1() {
-        let mut ot : Octree<u8> = Octree::new_from_size(8);
-        ot.fill(0, 0, 0, 4, 4, 4, 1.into());
-        ot.fill(4, 4, 4, 4, 4, 4, 2.into());
-        ot.recompute();
+    fn check_mining_tools() {
+        let dims = (16, 16, 16);
+        let c = Pos::new(8, 8, 8);
 
-        let mut log = vec![];
-        ot.draw(&mut |size, pos, v| {
-            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
-        });
-        for l in log.iter() { println!("E {:?} {:x}", l, l.3); }
+        assert_eq!(MiningTool::Single.positions(c, dims), vec![c]);
+        assert_eq!(MiningTool::Sphere { radius: 0 }.positions(c, dims), vec![c]);
+        assert_eq!(MiningTool::Sphere { radius: 1 }.positions(c, dims).len(), 7);
+        assert_eq!(MiningTool::Sphere { radius: 2 }.positions(c, dims).len(), 33);
+        // Clipped at the corner of the volume:
+        assert_eq!(MiningTool::Sphere { radius: 1 }.positions(Pos::new(0, 0, 0), dims).len(), 4);
 
-        assert_eq!(log[0], (4, (0, 0, 0), 1, 63));
-        assert_eq!(log[1], (4, (4, 4, 4), 2, 63));
-        assert_eq!(log.len(), 2);
+        assert_eq!(Face::from_normal([0.1, 0.9, -0.2]), Face::Top);
+        assert_eq!(Face::from_normal([0.0, 0.0, -1.0]), Face::Front);
+        assert_eq!(Face::from_normal([-0.7, 0.5, 0.5]), Face::Left);
+        for f in FACES.iter() {
+            let o = f.offset();
+            assert_eq!(Face::from_normal([o[0] as f32, o[1] as f32, o[2] as f32]), *f);
+            assert_eq!(f.opposite().opposite(), *f);
+        }
+
+        // A hit from above drills downwards:
+        let drill = MiningTool::from_name("drill", 3, Face::Top).unwrap();
+        assert_eq!(drill, MiningTool::Drill { depth: 3, dir: Face::Bottom });
+        assert_eq!(drill.positions(c, dims),
+                   vec![c, Pos::new(8, 7, 8), Pos::new(8, 6, 8)]);
+        assert_eq!(drill.positions(Pos::new(3, 1, 3), dims).len(), 2);
+        assert_eq!(MiningTool::from_name("drill", 0, Face::Top).unwrap()
+                   .positions(c, dims), vec![c]);
+
+        let plane = MiningTool::from_name("plane", 1, Face::Right).unwrap();
+        let ps = plane.positions(c, dims);
+        assert_eq!(ps.len(), 9);
+        assert!(ps.iter().all(|p| p.x == 8));
+        assert!(ps.contains(&Pos::new(8, 9, 7)));
+
+        assert_eq!(MiningTool::from_name("laser", 1, Face::Top), None);
+
+        // Mining the top layer of a block with a plane:
+        let mut v : Vol<u8> = Vol::new_dims(16, 16, 16);
+        v.fill(4, 4, 4, 8, 8, 8, 3.into());
+        v.set(8, 4, 8, 5.into());
+        let plane = MiningTool::from_name("plane", 2, Face::Top).unwrap();
+        let mut y = MiningYield::new();
+        for p in plane.positions(Pos::new(8, 11, 8), dims) {
+            let c = v.at(Pos::new(p.x, 15 - p.y, p.z)).color;
+            if c != 0 { y.add(c); }
+        }
+        assert_eq!(y.total(), 25);
+        assert_eq!(y.materials(), vec![(3, 24), (5, 1)]);
     }
 
     #[test]
-    fn check_smal() {
-        let mut ot : Octree<u8> = Octree::new_from_size(4);
-        ot.set(2, 2, 2, 1.into());
-        ot.recompute();
-        let mut log = vec![];
-        ot.draw(&mut |size, pos, v| {
-            log.push((size, (pos.x, pos.y, pos.z), v.color, v.faces));
-        });
-//
-//        for (i, n) in ot.nodes.iter().enumerate() {
-//            println!("AT {}: {:?}", i, n);
-//        }
+    fn check_structure_layout() {
+        let l = StructureLayout::default();
+        assert_eq!(l.dims(), (128, 128, 128));
+        assert_eq!(l.new_chunks::<u8>().chunk_count(), 512);
 
+        let l = StructureLayout::new(20, 8, 33, 8).unwrap();
+        let c : ChunkedVolume<u8> = l.new_chunks();
+        assert_eq!(c.chunk_dims(), (3, 1, 5));
+        assert_eq!(c.chunk_extent(c.chunk_count() - 1), (4, 8, 1));
+        let v : Vol<u8> = l.new_vol();
+        assert_eq!((v.w, v.h, v.d), (20, 8, 33));
 
-        assert_eq!(log[0], (1, (2, 2, 2), 1, 63));
+        assert_eq!(StructureLayout::new(16, 16, 16, 3),  Err(VolError::BadChunkSize(3)));
+        assert_eq!(StructureLayout::new(16, 16, 16, 65), Err(VolError::BadChunkSize(65)));
+        assert_eq!(StructureLayout::new(16, 0, 16, 16),  Err(VolError::SizeTooBig(0)));
+        assert_eq!(StructureLayout::new(5000, 1, 1, 16), Err(VolError::SizeTooBig(5000)));
+        assert_eq!(StructureLayout::new(4096, 4096, 4096, 64),
+                   Err(VolError::SizeTooBig(4096 * 4096 * 4096)));
+        assert!(StructureLayout::new(1024, 256, 1024, 32).is_ok());
+
+        // Wider than an octree, meshed by chunks:
+        let l = StructureLayout::new(STRUCTURE_MAX_SIZE, 8, 8, 64).unwrap();
+        let mut c : ChunkedVolume<u8> = l.new_chunks();
+        c.set(Pos::new(4000, 0, 0), 1.into());
+        let md = mesh_chunked_volume(&c, MeshOptions::default(), |_| [1.0; 4]);
+        assert_eq!(md.triangle_count(), 12);
+        assert!(md.positions.iter().all(|p| p[0] >= 4000.0 && p[0] <= 4001.0 && p[1] >= 7.0));
+
+        let l = StructureLayout::default();
+        assert_eq!(l.max_tool_size(), 16);
+        assert_eq!(l.tool_size(-3),       Some(0));
+        assert_eq!(l.tool_size(16),       Some(16));
+        assert_eq!(l.tool_size(17),       None);
+        assert_eq!(l.tool_size(70000),    None);
+        assert_eq!(l.tool_size(i64::MAX), None);
+        let biggest = MiningTool::Sphere { radius: l.tool_size(16).unwrap() };
+        assert!(biggest.positions(Pos::new(64, 64, 64), l.dims()).len() < 33 * 33 * 33);
     }
 }
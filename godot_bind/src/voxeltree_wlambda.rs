@@ -69,6 +69,8 @@ enum Mask {
 pub struct VoxelPainter {
     volumes: std::vec::Vec<Vol<FColor>>,
     masks:   std::vec::Vec<Mask>,
+    /// Palettes of the volumes loaded from MagicaVoxel files.
+    palettes: std::collections::HashMap<usize, VoxPalette>,
 }
 
 pub struct Rect {
@@ -91,6 +93,20 @@ impl Rect {
             d: env.arg(offs + 5).i() as u16,
         }
     }
+
+    /// Like `from_wlambda_env`, but refuses values that are negative
+    /// or too big for a voxel position, instead of wrapping them.
+    pub fn checked_from_wlambda_env(env: &Env, offs: usize) -> Result<Self, String> {
+        Ok(Self {
+            x: coord_from_env(env, offs)?,
+            y: coord_from_env(env, offs + 1)?,
+            z: coord_from_env(env, offs + 2)?,
+            w: coord_from_env(env, offs + 3)?,
+            h: coord_from_env(env, offs + 4)?,
+            d: coord_from_env(env, offs + 5)?,
+        })
+    }
+
     pub fn from_usize(x: usize, y: usize, z: usize, w: usize, h: usize, d: usize) -> Self {
         Self {
             x: x as u16,
@@ -238,29 +254,70 @@ impl DrawOp {
     }
 }
 
+fn v3_from_env(env: &Env, offs: usize) -> [f32; 3] {
+    [env.arg(offs).f()     as f32,
+     env.arg(offs + 1).f() as f32,
+     env.arg(offs + 2).f() as f32]
+}
+
+/// Reads a voxel coordinate or size argument.
+fn coord_from_env(env: &Env, offs: usize) -> Result<u16, String> {
+    let v = env.arg(offs).i();
+    if v < 0 || v > u16::MAX as i64 {
+        return Err(format!("Voxel coordinate {} out of range", v));
+    }
+    Ok(v as u16)
+}
+
+fn pos_from_env(env: &Env, offs: usize) -> Result<Pos, String> {
+    Ok(Pos::new(
+        coord_from_env(env, offs)?,
+        coord_from_env(env, offs + 1)?,
+        coord_from_env(env, offs + 2)?))
+}
+
+fn csg_op_from_vval(vv: &VVal) -> CsgOp {
+    match &vv.s_raw()[..] {
+        "subtract"  => CsgOp::Subtract,
+        "intersect" => CsgOp::Intersect,
+        "replace"   => CsgOp::Replace,
+        _           => CsgOp::Union,
+    }
+}
+
+fn axis_from_vval(vv: &VVal) -> Axis {
+    match &vv.s_raw()[..] {
+        "x" => Axis::X,
+        "z" => Axis::Z,
+        _   => Axis::Y,
+    }
+}
+
 impl VoxelPainter {
     pub fn new() -> Self {
         Self {
             volumes: vec![],
             masks: vec![],
+            palettes: std::collections::HashMap::new(),
         }
     }
 
     pub fn clear(&mut self) {
         self.volumes.clear();
         self.masks.clear();
+        self.palettes.clear();
     }
 
     pub fn write_into_u8_vol(&self, vol_id: usize, vol: &mut Vol<u8>) {
         if self.volumes.len() == 0 { return; }
 
-        for z in 0..vol.size {
-            for y in 0..vol.size {
-                for x in 0..vol.size {
+        let src = &self.volumes[vol_id];
+        for z in 0..vol.d.min(src.d) {
+            for y in 0..vol.h.min(src.h) {
+                for x in 0..vol.w.min(src.w) {
                     vol.set(
                         x as u16, y as u16, z as u16,
-                        self.volumes[vol_id]
-                        .at(Pos { x: x as u16, y: y as u16, z: z as u16 })
+                        src.at(Pos { x: x as u16, y: y as u16, z: z as u16 })
                         .color.into());
                 }
             }
@@ -345,10 +402,116 @@ impl VoxelPainter {
             rect.w, rect.h, rect.d, val.into());
     }
 
-    pub fn new_vol(&mut self, size: usize, def: f64) -> i64 {
-        self.volumes.push(Vol::new_default(size, def.into()));
+    pub fn new_vol(&mut self, w: usize, h: usize, d: usize, def: f64) -> i64 {
+        self.volumes.push(Vol::new_dims_default(w, h, d, def.into()));
         (self.volumes.len() - 1) as i64
     }
+
+    pub fn draw_shape(&mut self, vol_id: usize, mask: usize,
+                      shape: &Shape, val: f64, op: DrawOp) -> i64
+    {
+        self.volumes[vol_id].rasterize(shape, |dst| {
+            op.apply(val, (*dst).into()).into()
+        }) as i64
+    }
+
+    /// Loads a MagicaVoxel file into a new volume and returns its id.
+    pub fn load_vox(&mut self, filename: &str) -> Result<i64, String> {
+        let data =
+            std::fs::read(filename)
+            .map_err(|e| format!("Couldn't read '{}': {}", filename, e))?;
+        let model =
+            MagicaVoxModel::parse(&data)
+            .map_err(|e| format!("Couldn't load '{}': {}", filename, e))?;
+
+        self.volumes.push(model.to_vol());
+        let id = self.volumes.len() - 1;
+        if let Some(palette) = model.palette {
+            self.palettes.insert(id, palette);
+        }
+        Ok(id as i64)
+    }
+
+    pub fn save_vox(&self, vol_id: usize, filename: &str,
+                    palette: Option<&VoxPalette>) -> Result<(), String>
+    {
+        let palette = palette.or_else(|| self.palettes.get(&vol_id));
+        let data =
+            self.volumes[vol_id].to_magica_vox(palette)
+            .map_err(|e| format!("Couldn't save '{}': {}", filename, e))?;
+        std::fs::write(filename, data)
+            .map_err(|e| format!("Couldn't write '{}': {}", filename, e))
+    }
+
+    pub fn vox_palette(&self, vol_id: usize) -> Option<VoxPalette> {
+        self.palettes.get(&vol_id).copied()
+    }
+
+    /// The float volume `vol_id` as density field, see `mesh_surface_nets`.
+    pub fn density_field(&self, vol_id: usize) -> Option<DensityField> {
+        self.volumes.get(vol_id).map(DensityField::from_vol)
+    }
+
+    /// Meshes the float volume `vol_id` smoothly at the `iso` level,
+    /// with the colors of the material volume `mat_vol_id`.
+    pub fn mesh_isosurface<F>(&self, vol_id: usize, mat_vol_id: usize, iso: f32, color: F)
+        -> Option<MeshData> where F: Fn(u8) -> [f32; 4]
+    {
+        let density = self.volumes.get(vol_id)?;
+        let mat     = self.volumes.get(mat_vol_id)?;
+        Some(mesh_surface_nets(
+            [density.w, density.h, density.d],
+            Pos::new(0, 0, 0),
+            [density.w, density.h, density.d],
+            iso,
+            |p| { let d : f64 = density.at(p).color.into(); d as f32 },
+            |p| {
+                if mat.contains(p) { color(mat.at(p).color.into()) }
+                else               { color(0) }
+            }))
+    }
+
+    pub fn combine(&mut self, dst_id: usize, src_id: usize, offs: Pos, op: CsgOp) {
+        let src = self.volumes[src_id].clone();
+        self.volumes[dst_id].combine(&src, offs, op);
+    }
+
+    /// Copies a region of a volume into a new volume and returns its id.
+    pub fn copy_region(&mut self, vol_id: usize, rect: Rect) -> i64 {
+        let v = self.volumes[vol_id].copy_region(
+            rect.pos(), rect.w as usize, rect.h as usize, rect.d as usize);
+        self.volumes.push(v);
+        (self.volumes.len() - 1) as i64
+    }
+
+    pub fn rotate(&mut self, vol_id: usize, axis: Axis, quarter_turns: usize) {
+        self.volumes[vol_id] =
+            self.volumes[vol_id].rotated(axis, quarter_turns);
+    }
+
+    pub fn mirror(&mut self, vol_id: usize, axis: Axis) {
+        self.volumes[vol_id] = self.volumes[vol_id].mirrored(axis);
+    }
+}
+
+/// Converts the voxel edits to a vector of `$[system_id, entity_id, data]`
+/// entries with base64 data, for the savegame JSON.
+pub fn voxel_edits_to_vval(store: &VoxelEditStore) -> VVal {
+    let v = VVal::vec();
+    for (sys, ent, data) in store.to_entries() {
+        let e = VVal::vec();
+        e.push(VVal::Int(sys));
+        e.push(VVal::Int(ent));
+        e.push(VVal::new_str_mv(data));
+        v.push(e);
+    }
+    v
+}
+
+pub fn voxel_edits_from_vval(v: &VVal) -> Result<VoxelEditStore, String> {
+    let entries : std::vec::Vec<(i64, i64, String)> =
+        v.iter().map(|e| (e.v_i(0), e.v_i(1), e.v_s_raw(2))).collect();
+    VoxelEditStore::from_entries(&entries)
 }
 
 pub fn new_voxel_painter(id: usize) -> (Rc<RefCell<VoxelPainter>>, VVal) {
@@ -356,11 +519,21 @@ pub fn new_voxel_painter(id: usize) -> (Rc<RefCell<VoxelPainter>>, VVal) {
 
     let painter = Rc::new(RefCell::new(VoxelPainter::new()));
 
-    set_vval_method!(o, painter, new, Some(2), Some(2), env, _argc, {
+    // Either `new size default` for a cube or `new w h d default`:
+    set_vval_method!(o, painter, new, Some(2), Some(4), env, argc, {
         println!("NEW VOL!");
-        Ok(VVal::Int(painter.borrow_mut().new_vol(
-            env.arg(0).i() as usize,
-            env.arg(1).f())))
+        if argc == 4 {
+            Ok(VVal::Int(painter.borrow_mut().new_vol(
+                env.arg(0).i() as usize,
+                env.arg(1).i() as usize,
+                env.arg(2).i() as usize,
+                env.arg(3).f())))
+        } else {
+            let size = env.arg(0).i() as usize;
+            Ok(VVal::Int(painter.borrow_mut().new_vol(
+                size, size, size,
+                env.arg(1).f())))
+        }
     });
 
     set_vval_method!(o, painter, fill_noise, Some(11), Some(12), env, _argc, {
@@ -403,6 +576,172 @@ pub fn new_voxel_painter(id: usize) -> (Rc<RefCell<VoxelPainter>>, VVal) {
         Ok(VVal::Bol(true))
     });
 
+    // The shape methods take the volume and mask, the shape parameters,
+    // the value to draw and an optional DrawOp. They return the number
+    // of voxels drawn.
+    set_vval_method!(o, painter, sphere, Some(7), Some(8), env, _argc, {
+        let shape = Shape::Sphere {
+            center: v3_from_env(env, 2),
+            radius: env.arg(5).f() as f32,
+        };
+        Ok(VVal::Int(painter.borrow_mut().draw_shape(
+            env.arg(0).i() as usize,
+            env.arg(1).i() as usize,
+            &shape, env.arg(6).f(), DrawOp::from_vval(env.arg(7)))))
+    });
+
+    set_vval_method!(o, painter, ellipsoid, Some(9), Some(10), env, _argc, {
+        let shape = Shape::Ellipsoid {
+            center: v3_from_env(env, 2),
+            radii:  v3_from_env(env, 5),
+        };
+        Ok(VVal::Int(painter.borrow_mut().draw_shape(
+            env.arg(0).i() as usize,
+            env.arg(1).i() as usize,
+            &shape, env.arg(8).f(), DrawOp::from_vval(env.arg(9)))))
+    });
+
+    set_vval_method!(o, painter, cylinder, Some(10), Some(11), env, _argc, {
+        let shape = Shape::Cylinder {
+            a:      v3_from_env(env, 2),
+            b:      v3_from_env(env, 5),
+            radius: env.arg(8).f() as f32,
+        };
+        Ok(VVal::Int(painter.borrow_mut().draw_shape(
+            env.arg(0).i() as usize,
+            env.arg(1).i() as usize,
+            &shape, env.arg(9).f(), DrawOp::from_vval(env.arg(10)))))
+    });
+
+    set_vval_method!(o, painter, capsule, Some(10), Some(11), env, _argc, {
+        let shape = Shape::Capsule {
+            a:      v3_from_env(env, 2),
+            b:      v3_from_env(env, 5),
+            radius: env.arg(8).f() as f32,
+        };
+        Ok(VVal::Int(painter.borrow_mut().draw_shape(
+            env.arg(0).i() as usize,
+            env.arg(1).i() as usize,
+            &shape, env.arg(9).f(), DrawOp::from_vval(env.arg(10)))))
+    });
+
+    set_vval_method!(o, painter, cone, Some(10), Some(11), env, _argc, {
+        let shape = Shape::Cone {
+            base:   v3_from_env(env, 2),
+            apex:   v3_from_env(env, 5),
+            radius: env.arg(8).f() as f32,
+        };
+        Ok(VVal::Int(painter.borrow_mut().draw_shape(
+            env.arg(0).i() as usize,
+            env.arg(1).i() as usize,
+            &shape, env.arg(9).f(), DrawOp::from_vval(env.arg(10)))))
+    });
+
+    set_vval_method!(o, painter, line, Some(10), Some(11), env, _argc, {
+        let shape = Shape::Line {
+            a:         v3_from_env(env, 2),
+            b:         v3_from_env(env, 5),
+            thickness: env.arg(8).f() as f32,
+        };
+        Ok(VVal::Int(painter.borrow_mut().draw_shape(
+            env.arg(0).i() as usize,
+            env.arg(1).i() as usize,
+            &shape, env.arg(9).f(), DrawOp::from_vval(env.arg(10)))))
+    });
+
+    // polyhedron vol mask $[$[nx, ny, nz, dist], ...] value [op]
+    set_vval_method!(o, painter, polyhedron, Some(4), Some(5), env, _argc, {
+        let planes_vv = env.arg(2);
+        let mut planes = vec![];
+        for i in 0..planes_vv.len() {
+            let p = planes_vv.v_(i);
+            planes.push((
+                [p.v_f(0) as f32, p.v_f(1) as f32, p.v_f(2) as f32],
+                p.v_f(3) as f32));
+        }
+        let shape = Shape::Polyhedron { planes };
+        Ok(VVal::Int(painter.borrow_mut().draw_shape(
+            env.arg(0).i() as usize,
+            env.arg(1).i() as usize,
+            &shape, env.arg(3).f(), DrawOp::from_vval(env.arg(4)))))
+    });
+
+    set_vval_method!(o, painter, load_vox, Some(1), Some(1), env, _argc, {
+        match painter.borrow_mut().load_vox(&env.arg(0).s_raw()) {
+            Ok(id) => Ok(VVal::Int(id)),
+            Err(e) => Ok(VVal::err_msg(&e)),
+        }
+    });
+
+    // save_vox vol filename [palette], with the palette being a vector
+    // of hex colors indexed by the voxel color:
+    set_vval_method!(o, painter, save_vox, Some(2), Some(3), env, _argc, {
+        let palette =
+            if env.arg(2).is_none() { None }
+            else {
+                use crate::gui::wlambda_api::color_hex24tpl;
+                let mut p = [[0; 4]; 256];
+                for (i, c) in env.arg(2).iter().enumerate().take(256) {
+                    let tpl = color_hex24tpl(&c.s_raw());
+                    p[i] = [tpl.0, tpl.1, tpl.2, tpl.3];
+                }
+                Some(p)
+            };
+
+        match painter.borrow().save_vox(
+                env.arg(0).i() as usize, &env.arg(1).s_raw(), palette.as_ref())
+        {
+            Ok(()) => Ok(VVal::Bol(true)),
+            Err(e) => Ok(VVal::err_msg(&e)),
+        }
+    });
+
+    // csg dst_vol src_vol x y z op, with op being one of
+    // "union", "subtract", "intersect" or "replace". Negative offsets
+    // are an error:
+    set_vval_method!(o, painter, csg, Some(6), Some(6), env, _argc, {
+        let offs =
+            match pos_from_env(env, 2) {
+                Ok(pos) => pos,
+                Err(e)  => return Ok(VVal::err_msg(&e)),
+            };
+        painter.borrow_mut().combine(
+            env.arg(0).i() as usize,
+            env.arg(1).i() as usize,
+            offs,
+            csg_op_from_vval(&env.arg(5)));
+
+        Ok(VVal::Bol(true))
+    });
+
+    set_vval_method!(o, painter, copy, Some(7), Some(7), env, _argc, {
+        let rect =
+            match Rect::checked_from_wlambda_env(env, 1) {
+                Ok(rect) => rect,
+                Err(e)   => return Ok(VVal::err_msg(&e)),
+            };
+        Ok(VVal::Int(painter.borrow_mut().copy_region(
+            env.arg(0).i() as usize, rect)))
+    });
+
+    // rotate vol axis quarter_turns, with axis being "x", "y" or "z":
+    set_vval_method!(o, painter, rotate, Some(3), Some(3), env, _argc, {
+        painter.borrow_mut().rotate(
+            env.arg(0).i() as usize,
+            axis_from_vval(&env.arg(1)),
+            env.arg(2).i().rem_euclid(4) as usize);
+
+        Ok(VVal::Bol(true))
+    });
+
+    set_vval_method!(o, painter, mirror, Some(2), Some(2), env, _argc, {
+        painter.borrow_mut().mirror(
+            env.arg(0).i() as usize,
+            axis_from_vval(&env.arg(1)));
+
+        Ok(VVal::Bol(true))
+    });
+
     set_vval_method!(o, painter, clear, Some(0), Some(0), env, _argc, {
         painter.borrow_mut().clear();
         Ok(VVal::Bol(true))
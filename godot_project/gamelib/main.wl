@@ -380,6 +380,19 @@ STATE.code.build_color_to_element_index = {||
     STATE.vol_color_goods = vol_color_goods;
 };
 
+STATE.code.voxel_materials = {||
+    !materials = $[];
+    STATE.good_types {!(v, k) = @;
+        (not ~ is_none v.vol_color) {
+            materials.(v.vol_color) = ${
+                minable = bool[v.mineable],
+                density = float[v.kg_p_m3] / 1000.0,
+            };
+        };
+    };
+    materials
+};
+
 STATE.code.enumerate_entities = {||
     !i = $&0;
     STATE.systems {!(sys) = @;
@@ -550,6 +563,24 @@ STATE.callbacks.on_mined_voxel = {
     $t
 };
 
+STATE.callbacks.on_mined_area = {!(sys_id, ent_id, tool, x, y, z, report, voxel_count) = @;
+    std:displayln "MINED AREA:" tool voxel_count report;
+    report {!(m, color) = @;
+        !(k, v) = STATE.code.get_good_by_color[int color];
+        not[is_none[k]] {
+            STATE.ship.cargo.goods.(k) =
+                STATE.ship.cargo.goods.(k) + m.count;
+        };
+    };
+    STATE.code.recalc_ship_cargo[];
+    $t
+};
+
+STATE.callbacks.on_voxel_island_detached = {!(sys_id, ent_id, offs, dims, counts, voxel_count) = @;
+    std:displayln "ISLAND DETACHED:" sys_id ent_id offs dims counts voxel_count;
+    $t
+};
+
 !vp = $&&$n;
 
 STATE.callbacks.on_texture_description = {|| std:displayln "Describing textures ..."; $[
@@ -575,7 +606,7 @@ STATE.callbacks.on_draw_voxel_structure = {!(sys_id, ent_id) = @;
         };
 
 #    std:displayln "DONE!" $[vp.id[], main_vol, cm];
-    $[vp.id[], main_vol, cm]
+    $[vp.id[], main_vol, cm, STATE.code.voxel_materials[]]
 };
 
 STATE.callbacks.on_saved_godot_state = {!(state) = @;
@@ -0,0 +1,19 @@
+shader_type spatial;
+
+render_mode diffuse_burley;
+
+// The texture atlas and the size of one tile in texture coordinates:
+uniform sampler2D atlas : hint_albedo;
+uniform vec2 tile_size = vec2(1.0, 1.0);
+
+void fragment() {
+	// UV counts the voxels of the face, so the tile repeats on merged
+	// faces. UV2 is the upper left corner of the tile in the atlas.
+	// Staying half a texel inside of the tile avoids bleeding of the
+	// neighbouring tiles:
+	vec2 half_texel = 0.5 / vec2(textureSize(atlas, 0));
+	vec2 tile_uv = clamp(fract(UV) * tile_size, half_texel, tile_size - half_texel);
+
+	// The vertex color only carries the ambient occlusion:
+	ALBEDO = texture(atlas, UV2 + tile_uv).rgb * COLOR.rgb;
+}
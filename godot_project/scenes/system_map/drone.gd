@@ -22,6 +22,10 @@ var marker_vox = null
 var mining_time = 0.0 # in seconds
 var mining_time_dest = 1.0 # in seconds
 var prev_vox_pos = null
+# "single", "sphere", "drill" or "plane", see VoxStruct.mine_area_at_cursor
+var mining_tool = "single"
+var mining_tool_size = 1
+var mining_normal = Vector3(0, 1, 0)
 
 
 var old_on_floor = false
@@ -156,8 +160,10 @@ func process_mining_gun(delta):
 			if mining_vox != vox:
 				if vox.mine_status(true):
 					mining_vox = vox
+					mining_time_dest = vox.mine_info_at_cursor()["time"]
 #					var mining_info = mining_vox.mine_info_at_cursor()
 					mining_pos = vv
+					mining_normal = vox.global_transform.basis.xform_inv(cn)
 					raym.show()
 					mining_vox.set_marker_status(true, true)
 					mining_time = 0.0
@@ -173,7 +179,10 @@ func process_mining_gun(delta):
 				var done_value = (mining_time * 100.0) / mining_time_dest
 				self.get_parent().get_node("GUI/DroneHUDInfo/MiningProgress").value = done_value
 				if mining_vox and mining_time > mining_time_dest:
-					mining_vox.mine_at_cursor()
+					if mining_tool == "single":
+						mining_vox.mine_at_cursor()
+					else:
+						mining_vox.mine_area_at_cursor(mining_tool, mining_tool_size, mining_normal)
 					$MiningBeamSound.play_pop()
 					stop_mining()
 					#mining_vox.looking_at_nothing()